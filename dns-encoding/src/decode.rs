@@ -1,11 +1,15 @@
-use trust_dns_proto::rr::domain::Label;
-use trust_dns_proto::rr::Name;
+use std::convert::TryFrom;
+use std::fmt;
 
-use crate::message::{Id, Message};
-use base32::Alphabet;
+use trust_dns_proto::op::MessageType as DnsMessageType;
+use trust_dns_proto::rr::domain::Label;
+use trust_dns_proto::rr::{Name, RecordType};
 
-const ANNOUNCEMENT_ID: u16 = 0;
-const FINISH_ID: u16 = 1;
+use crate::capabilities::{Capabilities, PROTOCOL_VERSION};
+use crate::codec::{CodecError, CodecKind};
+use crate::encode::CHECKSUM_LEN;
+use crate::header::{FrameHeader, MessageType};
+use crate::message::{Digest, Message};
 
 pub struct MessageDecoder {
     magic_nr: Label,
@@ -15,6 +19,8 @@ pub struct MessageDecoder {
 
 #[derive(Debug)]
 pub enum MessageDecoderError {
+    /** The DNS message is a response, which is never answered */
+    NotAQuery,
     /** The DNS message contained no queries */
     NoQueries,
     /** The DNS messages contained to few labels */
@@ -24,9 +30,82 @@ pub enum MessageDecoderError {
     /** Wrong Subdomain */
     WrongSubdomain,
 
-    ExpectedNrLabel,
-
     InvalidBase32,
+
+    /** The data labels could not be decoded with the codec named in the header */
+    InvalidPayload(CodecError),
+
+    /** The label after the magic nr is no valid frame header */
+    InvalidHeader,
+
+    /** The first payload label of a data message is no CRC32 in hex */
+    InvalidChecksum,
+
+    /** The offset after the checksum of a data message is no number in hex */
+    InvalidOffset,
+
+    /** The file size of a finish message is no number in hex */
+    InvalidFileSize,
+
+    /** The digest of a finish message has the wrong length */
+    InvalidDigest,
+
+    /** The capabilities of an announcement are no number in hex */
+    InvalidCapabilities,
+
+    /** The file name of an announcement is not valid UTF-8 */
+    InvalidFileName,
+
+    /** The announcement is of a newer protocol version, whose format is unknown */
+    UnsupportedVersion { version: u8 },
+}
+
+impl fmt::Display for MessageDecoderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageDecoderError::NotAQuery => write!(f, "dns message is no query"),
+            MessageDecoderError::NoQueries => write!(f, "dns message contains no queries"),
+            MessageDecoderError::TooFewLabels => write!(f, "query name has too few labels"),
+            MessageDecoderError::NoMagicNr => write!(f, "query name does not start with the magic nr"),
+            MessageDecoderError::WrongSubdomain => write!(f, "query name is not below the sub domain"),
+            MessageDecoderError::InvalidBase32 => write!(f, "invalid base32"),
+            MessageDecoderError::InvalidPayload(_) => write!(f, "data labels could not be decoded"),
+            MessageDecoderError::InvalidHeader => write!(f, "invalid frame header"),
+            MessageDecoderError::InvalidChecksum => write!(f, "invalid checksum label"),
+            MessageDecoderError::InvalidOffset => write!(f, "invalid offset"),
+            MessageDecoderError::InvalidFileSize => write!(f, "invalid file size label"),
+            MessageDecoderError::InvalidDigest => write!(f, "invalid digest label"),
+            MessageDecoderError::InvalidCapabilities => write!(f, "invalid capabilities label"),
+            MessageDecoderError::InvalidFileName => write!(f, "file name is not valid UTF-8"),
+            MessageDecoderError::UnsupportedVersion { version } => write!(f, "unsupported protocol version {}", version),
+        }
+    }
+}
+
+impl MessageDecoderError {
+    /// The query name is no protocol message, e.g. a resolver asked for the zone itself
+    pub fn is_foreign_name(&self) -> bool {
+        self.is_intermediate_name() || matches!(self, MessageDecoderError::WrongSubdomain)
+    }
+
+    ///
+    /// The query name is below the sub domain, but no protocol message, so it may be an ancestor of one.
+    ///
+    /// Resolvers that minimise the query name (RFC 7816) ask for these names, e.g. `_.extract.de`
+    /// or the last payload labels of a message, before they send the full name.
+    ///
+    pub fn is_intermediate_name(&self) -> bool {
+        matches!(self, MessageDecoderError::TooFewLabels | MessageDecoderError::NoMagicNr)
+    }
+}
+
+impl std::error::Error for MessageDecoderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MessageDecoderError::InvalidPayload(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 pub type MessageResult = Result<Message, MessageDecoderError>;
@@ -36,99 +115,148 @@ impl MessageDecoder {
     pub fn new(magic_nr: Label, sub_domain: Name) -> MessageDecoder {
         let minimum_subdomains =
             1 + // one for magic nr
-                sub_domain.len() + // subdomains
-                1; // at least one to transmit payload
+                1 + // one for the frame header
+                sub_domain.num_labels() as usize; // subdomains
         MessageDecoder { magic_nr, sub_domain, minimum_subdomains }
     }
 
     pub fn decode(&self, dns_message: &trust_dns_proto::op::Message) -> Result<Message, MessageDecoderError> {
-        let payload = self.check_and_prepare_message(&dns_message)?;
-        match dns_message.id() {
-            ANNOUNCEMENT_ID => self.parse_announcement(payload),
-            FINISH_ID => self.parse_finish(payload),
-            _ => self.parse_data(payload, dns_message.id()),
-        }
+        self.decode_with_capabilities(dns_message).map(|(message, _)| message)
     }
 
-    pub fn decode_base32(data: &str) -> Result<Vec<u8>, MessageDecoderError> {
-        match base32::decode(Alphabet::Crockford, &data) {
-            None => { Err(MessageDecoderError::InvalidBase32,) },
-            Some(v) => Ok(v)
+    ///
+    /// Decode the message and tell which capabilities the query uses.
+    ///
+    /// These are the codec of data messages and TXT responses, the transmission must have negotiated them.
+    ///
+    pub fn decode_with_capabilities(&self, dns_message: &trust_dns_proto::op::Message) -> Result<(Message, Capabilities), MessageDecoderError> {
+        let (header, payload) = self.check_and_prepare_message(dns_message)?;
+        let mut capabilities = Capabilities::CODEC_BASE32;
+        if dns_message.queries()[0].query_type() == RecordType::TXT {
+            capabilities = capabilities.union(Capabilities::RESPONSE_TXT);
         }
+        let message = match header.message_type {
+            MessageType::Announcement => self.parse_announcement(header, payload)?,
+            MessageType::Finish => self.parse_finish(header, payload)?,
+            MessageType::Data(codec) => {
+                capabilities = capabilities.union(Capabilities::for_codec(codec));
+                self.parse_data(header, codec, payload)?
+            }
+        };
+        Ok((message, capabilities))
     }
 
     ///
-    /// Check if this is a valid message and return the payload
+    /// Check if this is a valid message and return the frame header and the payload
     ///
-    fn check_and_prepare_message(&self, dns_message: &trust_dns_proto::op::Message) -> Result<Vec<Label>, MessageDecoderError> {
+    fn check_and_prepare_message(&self, dns_message: &trust_dns_proto::op::Message) -> Result<(FrameHeader, Vec<Label>), MessageDecoderError> {
+        if dns_message.message_type() != DnsMessageType::Query {
+            return Err(MessageDecoderError::NotAQuery);
+        }
         if dns_message.query_count() < 1 {
             return Err(MessageDecoderError::NoQueries);
         }
 
         let query = &dns_message.queries()[0];
         let q_name = query.name();
-        if q_name.len() < self.minimum_subdomains {
+        // names outside the sub domain are checked first, the others are for this server
+        if !self.sub_domain.zone_of(q_name) {
+            return Err(MessageDecoderError::WrongSubdomain);
+        }
+
+        if (q_name.num_labels() as usize) < self.minimum_subdomains {
             return Err(MessageDecoderError::TooFewLabels);
         }
 
-        if q_name[0] != self.magic_nr {
+        // resolvers may randomize the case of the query name, all labels are compared case insensitive
+        if !q_name[0].eq_ignore_ascii_case(&self.magic_nr) {
             return Err(MessageDecoderError::NoMagicNr);
         }
 
-        let sub_domain_len = self.sub_domain.num_labels();
-
-        let dns_iter= q_name.iter()
-            .rev()
-            .take(sub_domain_len as usize);
-        let sub_domain_iter = self.sub_domain
-            .iter()
-            .rev();
-        if !dns_iter.eq(sub_domain_iter) {
-            return Err(MessageDecoderError::WrongSubdomain);
-        }
+        let header = FrameHeader::parse(&q_name[1])?;
 
         let end_index =  (q_name.num_labels() - self.sub_domain.num_labels()) as usize;
-        let mut result = Vec::with_capacity(end_index - 1);
-        for i in 1..end_index {
-            result.push(q_name[i].clone());
-        }
-        Ok(result)
+        let payload = (2..end_index)
+            .map(|i| q_name[i].clone())
+            .collect();
+        Ok((header, payload))
     }
 
-    fn parse_announcement(&self, payload: Vec<Label>) -> Result<Message, MessageDecoderError> {
-        if payload.len() < 3 {
+    ///
+    /// Parse an announcement, the sequence field of the header holds the protocol version.
+    ///
+    /// Version 0 announcements consist of host and file name only,
+    /// later versions add the capabilities of the client after the host.
+    ///
+    fn parse_announcement(&self, header: FrameHeader, payload: Vec<Label>) -> Result<Message, MessageDecoderError> {
+        let version = u8::try_from(header.sequence).map_err(|_| MessageDecoderError::InvalidHeader)?;
+        if version > PROTOCOL_VERSION {
+            return Err(MessageDecoderError::UnsupportedVersion { version });
+        }
+        let (capabilities, file_name_index) = if version == 0 {
+            (Capabilities::V0, 1)
+        } else {
+            let capabilities = payload.get(1)
+                .ok_or(MessageDecoderError::TooFewLabels)
+                .and_then(|label| u32::from_str_radix(&label.to_ascii(), 16)
+                    .map_err(|_| MessageDecoderError::InvalidCapabilities))?;
+            (Capabilities(capabilities), 2)
+        };
+        if payload.len() <= file_name_index {
             return Err(MessageDecoderError::TooFewLabels)
         }
-        let host = payload[0].to_ascii();
-        let encoded_str = payload[1].to_ascii();
-        let file_name_bytes  = MessageDecoder::decode_base32(encoded_str.as_str())?;
-        let file_name = String::from_utf8(file_name_bytes).unwrap();
-
-        let rnd_nr: u16 = payload[2].to_ascii()
-            .parse()
-            .map_err(|_| MessageDecoderError::ExpectedNrLabel)?;
-        Ok(Message::Announcement { host, file_name, rnd_nr })
+        let host = payload[0].to_ascii().to_ascii_lowercase();
+        let file_name_bytes = CodecKind::Base32.codec()
+            .decode(&Self::join_labels(&payload[file_name_index..]))
+            .map_err(|_| MessageDecoderError::InvalidBase32)?;
+        let file_name = String::from_utf8(file_name_bytes)
+            .map_err(|_| MessageDecoderError::InvalidFileName)?;
+
+        Ok(Message::Announcement { host, file_name, rnd_nr: header.session, version, capabilities })
     }
 
 
-    fn parse_data(&self, payload: Vec<Label>, id: Id) -> Result<Message, MessageDecoderError> {
+    fn parse_data(&self, header: FrameHeader, codec: CodecKind, payload: Vec<Label>) -> Result<Message, MessageDecoderError> {
         if payload.is_empty() {
             return Err(MessageDecoderError::TooFewLabels);
         }
-        let data = payload[0].as_bytes()
-            .iter()
-            .copied()
-            .collect();
-        Ok(Message::Data { id, data })
+        // `<checksum>-<offset>`, clients of version 1 send the checksum only
+        let chunk_label = payload[0].to_ascii();
+        let mut fields = chunk_label.splitn(2, '-');
+        let checksum = fields.next()
+            .filter(|checksum| checksum.len() <= CHECKSUM_LEN)
+            .and_then(|checksum| u32::from_str_radix(checksum, 16).ok())
+            .ok_or(MessageDecoderError::InvalidChecksum)?;
+        let offset = fields.next()
+            .map(|offset| u64::from_str_radix(offset, 16).map_err(|_| MessageDecoderError::InvalidOffset))
+            .transpose()?;
+        let data = codec.codec()
+            .decode(&Self::join_labels(&payload[1..]))
+            .map_err(MessageDecoderError::InvalidPayload)?;
+        Ok(Message::Data { rnd_nr: header.session, id: header.sequence, offset, data, checksum })
     }
 
-    fn parse_finish(&self, payload: Vec<Label>) -> Result<Message, MessageDecoderError> {
-        if payload.is_empty() {
+    fn parse_finish(&self, header: FrameHeader, payload: Vec<Label>) -> Result<Message, MessageDecoderError> {
+        if payload.len() < 2 {
             return Err(MessageDecoderError::TooFewLabels);
         }
-        let rnd_nr: u16 = payload[0].to_ascii()
-            .parse()
-            .map_err(|_| MessageDecoderError::ExpectedNrLabel)?;
-        Ok(Message::Finish { rnd_nr })
+        let file_size = u64::from_str_radix(&payload[0].to_ascii(), 16)
+            .map_err(|_| MessageDecoderError::InvalidFileSize)?;
+        let digest_bytes = CodecKind::Base32.codec()
+            .decode(payload[1].as_bytes())
+            .map_err(|_| MessageDecoderError::InvalidBase32)?;
+        if digest_bytes.len() != std::mem::size_of::<Digest>() {
+            return Err(MessageDecoderError::InvalidDigest);
+        }
+        let mut digest: Digest = Default::default();
+        digest.copy_from_slice(&digest_bytes);
+        Ok(Message::Finish { rnd_nr: header.session, file_size, digest })
+    }
+
+    /// Concatenate labels that were split by the encoder
+    fn join_labels(labels: &[Label]) -> Vec<u8> {
+        labels.iter()
+            .flat_map(|l| l.as_bytes().iter().copied())
+            .collect()
     }
 }
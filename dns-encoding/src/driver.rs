@@ -0,0 +1,1026 @@
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::op::{Header, Message as DnsMessage, MessageType, ResponseCode};
+use trust_dns_proto::rr::{Record, RecordType};
+use trust_dns_proto::serialize::binary::{BinDecodable, BinEncodable};
+
+use crate::capabilities::Capabilities;
+use crate::client;
+use crate::codec::CodecKind;
+use crate::decode::{MessageDecoder, MessageDecoderError};
+use crate::edns;
+use crate::encode::MessageEncoder;
+use crate::error::Error;
+use crate::message::{Message, MessageResponse, ResponseType};
+use crate::response::{error_code, response_code, ResponseBuilder};
+use crate::server::{Accepted, ServerState, TransmissionState};
+use crate::transport::{is_transient, Transport};
+use crate::zone::Zone;
+
+///
+/// Largest slice that fits into a query, or the requested size if it is smaller.
+///
+/// Returns 0 if magic nr and sub domain leave no room for data.
+///
+pub fn slice_size(message_encoder: &MessageEncoder, requested: Option<usize>) -> usize {
+    let max_slice_size = message_encoder.max_payload_per_query();
+    match requested {
+        Some(slice_size) if slice_size > max_slice_size => {
+            warn!("Slice size {} does not fit into a query, using {}", slice_size, max_slice_size);
+            max_slice_size
+        }
+        Some(slice_size) => slice_size,
+        None => max_slice_size,
+    }
+}
+
+///
+/// Runs a transmission of the client over a transport.
+///
+/// The driver encodes the messages of the transmission state, sends them and feeds the responses back,
+/// until the server acknowledged the file or the transmission failed.
+///
+pub struct ClientDriver {
+    message_encoder: MessageEncoder,
+    state: client::TransmissionState,
+    /** Codec the user asked for, the server may not support it */
+    codec: CodecKind,
+    /** Response type the user asked for, the server may not support it */
+    response_type: ResponseType,
+    /** Bytes per data message the user asked for, the maximum that fits if none */
+    slice_size: Option<usize>,
+    /** Transport that takes over once a response was truncated */
+    fallback: Option<Box<dyn Transport>>,
+    buffer: Vec<u8>,
+}
+
+impl ClientDriver {
+    ///
+    /// The message encoder starts with base32 and the requested response type,
+    /// it is switched to codec once the server agreed on it.
+    ///
+    pub fn new(message_encoder: MessageEncoder, state: client::TransmissionState, codec: CodecKind) -> ClientDriver {
+        let response_type = message_encoder.response_type();
+        ClientDriver { message_encoder, state, codec, response_type, slice_size: None, fallback: None, buffer: Vec::new() }
+    }
+
+    pub fn set_slice_size(&mut self, slice_size: usize) {
+        self.slice_size = Some(slice_size);
+    }
+
+    pub fn set_fallback(&mut self, fallback: Box<dyn Transport>) {
+        self.fallback = Some(fallback);
+    }
+
+    pub fn state(&self) -> &client::TransmissionState {
+        &self.state
+    }
+
+    /// Transmit the file, returns once the server acknowledged it
+    pub fn run(&mut self, mut transport: Box<dyn Transport>) -> Result<(), Error> {
+        let announcement = self.state.initial_message();
+        self.send(&mut transport, vec![announcement])?;
+
+        while !self.state.is_finished() {
+            debug!("Waiting for response");
+            let timeout = self.state.timeout();
+            match transport.receive(&mut self.buffer, timeout) {
+                Ok(()) => {}
+                // an unreachable resolver is treated like a lost packet, it may come back
+                Err(e) if is_transient(&e) => {
+                    if e.kind() == io::ErrorKind::ConnectionRefused {
+                        thread::sleep(timeout);
+                    }
+                    let messages = self.state.handle_timeout()?;
+                    warn!("No response within {:?}, sending {} queries again", timeout, messages.len());
+                    self.send(&mut transport, messages)?;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let dns_message = match DnsMessage::from_bytes(&self.buffer) {
+                Ok(dns_message) => dns_message,
+                Err(e) => {
+                    warn!("Ignoring malformed response: {}", e);
+                    continue;
+                }
+            };
+            debug!("response dns message = {:?}", dns_message);
+            if dns_message.truncated() {
+                match self.fallback.take() {
+                    Some(fallback) => {
+                        warn!("Response was truncated, switching to the fallback transport");
+                        transport = fallback;
+                        self.send_again(&mut transport, &dns_message)?;
+                    }
+                    None => warn!("Ignoring truncated response"),
+                }
+                continue;
+            }
+            if dns_message.response_code() == ResponseCode::FormErr && self.message_encoder.edns().is_some() {
+                // resolvers without EDNS may reject the OPT record, lost queries are sent again without it (RFC 6891)
+                warn!("Resolver answered with FORMERR, sending queries without EDNS");
+                self.message_encoder.set_edns_payload_size(None);
+                continue;
+            }
+            if dns_message.response_code() == ResponseCode::Refused {
+                // sending the message again would be refused as well
+                let code = match MessageResponse::decode(&dns_message) {
+                    Ok(MessageResponse::Error { code }) => Some(code),
+                    _ => None,
+                };
+                let messages = self.state.handle_refused(code)?;
+                warn!("Announcement was refused, announcing again with another token");
+                self.send(&mut transport, messages)?;
+                continue;
+            }
+            if dns_message.response_code() != ResponseCode::NoError {
+                // e.g. a lost response, whose retry the server did not recognize anymore
+                match MessageResponse::decode(&dns_message) {
+                    Ok(MessageResponse::Error { code }) => warn!("Ignoring response with {}: {}", dns_message.response_code(), code),
+                    _ => warn!("Ignoring response with {}", dns_message.response_code()),
+                }
+                continue;
+            }
+            let response = match MessageResponse::decode(&dns_message) {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("Ignoring malformed response: {}", e);
+                    continue;
+                }
+            };
+            debug!("received response {:?}", response);
+            if let MessageResponse::Announcement { capabilities, .. } = &response {
+                self.apply_capabilities(*capabilities);
+            }
+            let messages = self.state.handle_response(response)?;
+            self.send(&mut transport, messages)?;
+        }
+        Ok(())
+    }
+
+    /// Send the messages, a message that could not be sent is treated like a lost one
+    fn send(&mut self, transport: &mut Box<dyn Transport>, messages: Vec<Message>) -> Result<(), Error> {
+        for message in messages {
+            debug!("message = {:?}", message);
+            let dns_message = self.message_encoder.encode(message)?;
+            Self::send_query(transport, dns_message)?;
+        }
+        Ok(())
+    }
+
+    /// Ask the question of a truncated response again
+    fn send_again(&mut self, transport: &mut Box<dyn Transport>, truncated: &DnsMessage) -> Result<(), Error> {
+        let mut dns_message = DnsMessage::new();
+        dns_message.set_id(truncated.id());
+        dns_message.set_recursion_desired(true);
+        dns_message.add_queries(truncated.queries().to_vec());
+        if let Some(edns) = self.message_encoder.edns() {
+            dns_message.set_edns(edns);
+        }
+        Self::send_query(transport, dns_message)
+    }
+
+    fn send_query(transport: &mut Box<dyn Transport>, dns_message: DnsMessage) -> Result<(), Error> {
+        debug!("Sending dns message {:?}", dns_message);
+        match transport.send(&dns_message.to_bytes()?) {
+            Err(e) if is_transient(&e) => warn!("Failed to send query: {}", e),
+            result => result?,
+        }
+        Ok(())
+    }
+
+    /// Switch to the codec and response type the server agreed on
+    fn apply_capabilities(&mut self, capabilities: Capabilities) {
+        let codec = capabilities.select_codec(self.codec);
+        if codec != self.codec {
+            warn!("Server does not support codec {:?}, using {:?}", self.codec, codec);
+        }
+        self.message_encoder.set_codec(codec);
+        if self.response_type == ResponseType::Txt && !capabilities.contains(Capabilities::RESPONSE_TXT) {
+            warn!("Server does not support TXT responses, using A records");
+            self.message_encoder.set_response_type(ResponseType::Address);
+        }
+        let slice_size = slice_size(&self.message_encoder, self.slice_size);
+        if slice_size > 0 {
+            info!("Sending {} bytes per query", slice_size);
+            self.state.set_slice_size(slice_size);
+        }
+    }
+}
+
+///
+/// Receives the transmissions that left the server state.
+///
+pub trait Storage {
+    /// Size and digest of the file were verified
+    fn finished(&self, state: TransmissionState);
+
+    /// Size or digest of the file did not match
+    fn failed(&self, state: TransmissionState);
+
+    /// The client stopped sending before the file was finished
+    fn expired(&self, state: TransmissionState);
+}
+
+///
+/// Answers queries of clients over any number of transports.
+///
+/// The server state is shared, so the driver can serve several transports from different threads.
+///
+pub struct ServerDriver<S> {
+    message_decoder: MessageDecoder,
+    state: Mutex<ServerState>,
+    storage: S,
+    /** How often idle transmissions are evicted */
+    eviction_interval: Duration,
+    /** Answers queries for names that are no protocol messages */
+    zone: Option<Zone>,
+    /** Largest UDP response for queries with an OPT record, `None` ignores EDNS */
+    edns_payload_size: Option<u16>,
+}
+
+impl<S: Storage> ServerDriver<S> {
+    pub fn new(message_decoder: MessageDecoder, state: ServerState, storage: S) -> ServerDriver<S> {
+        let eviction_interval = (state.idle_timeout() / 4).clamp(Duration::from_secs(1), Duration::from_secs(10));
+        ServerDriver {
+            message_decoder,
+            state: Mutex::new(state),
+            storage,
+            eviction_interval,
+            zone: None,
+            edns_payload_size: Some(edns::DEFAULT_PAYLOAD_SIZE),
+        }
+    }
+
+    /// Answer SOA, NS and other queries of resolvers for the sub domain, they are ignored without a zone
+    pub fn set_zone(&mut self, zone: Zone) {
+        self.zone = Some(zone);
+    }
+
+    /// Send UDP responses up to payload_size bytes to resolvers that advertise as much, `None` disables EDNS
+    pub fn set_edns_payload_size(&mut self, payload_size: Option<u16>) {
+        self.edns_payload_size = payload_size;
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// How often `evict_idle` should be called
+    pub fn eviction_interval(&self) -> Duration {
+        self.eviction_interval
+    }
+
+    ///
+    /// Answer queries until the transport is closed.
+    ///
+    /// Idle transmissions are evicted regularly, even if no queries arrive.
+    ///
+    pub fn serve<T: Transport>(&self, transport: &mut T) {
+        let mut buffer = Vec::new();
+        let mut last_eviction = Instant::now();
+        loop {
+            if last_eviction.elapsed() >= self.eviction_interval {
+                last_eviction = Instant::now();
+                self.evict_idle();
+            }
+            match transport.receive(&mut buffer, self.eviction_interval) {
+                Ok(()) => self.reply(transport, &buffer),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return,
+                Err(e) => error!("Failed to receive message, error: {:?}", e),
+            }
+        }
+    }
+
+    ///
+    /// Answer the queries of a connection until the client closes it, or sends nothing for `idle_timeout`.
+    ///
+    pub fn serve_connection<T: Transport>(&self, transport: &mut T, idle_timeout: Duration) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        loop {
+            match transport.receive(&mut buffer, idle_timeout) {
+                Ok(()) => self.reply(transport, &buffer),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::UnexpectedEof) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn reply<T: Transport>(&self, transport: &mut T, query: &[u8]) {
+        let response = match self.answer(query, transport.max_message_len()) {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Failed to answer query: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = transport.send(&response) {
+            error!("Failed to send response, error: {}", e);
+        }
+    }
+
+    ///
+    /// Answer a query with the encoded response, truncated if it exceeds what the client can receive.
+    ///
+    /// `max_len` is the limit of the transport without EDNS, a query with an OPT record may raise it
+    /// up to the payload size of the server. Queries of an EDNS version other than 0 are answered with BADVERS.
+    ///
+    /// Queries that can not be handled are answered with an error response code, see `response::response_code`.
+    /// Returns an error only if there is nothing to answer, e.g. for a response or a message without header.
+    ///
+    pub fn answer(&self, query: &[u8], max_len: usize) -> Result<Vec<u8>, Error> {
+        let request = match DnsMessage::from_bytes(query) {
+            Ok(request) => request,
+            Err(e) => {
+                let header = Header::from_bytes(query).map_err(|_| e)?;
+                if header.message_type() != MessageType::Query {
+                    return Err(MessageDecoderError::NotAQuery.into());
+                }
+                warn!("Answering malformed query with FORMERR");
+                return Ok(ResponseBuilder::for_header(&header).set_response_code(ResponseCode::FormErr).build().to_bytes()?);
+            }
+        };
+        debug!("Received dns message = {:?}", request);
+        let response = match request.edns() {
+            Some(edns) if self.edns_payload_size.is_some() && edns.version() > edns::VERSION => {
+                warn!("Answering query of EDNS version {} with BADVERS", edns.version());
+                self.response_builder(&request).set_response_code(ResponseCode::BADVERS).build()
+            }
+            _ => match self.respond(&request) {
+                Ok(response) => response,
+                Err(e) => {
+                    let response_code = match response_code(&e) {
+                        Some(response_code) => response_code,
+                        None => return Err(e),
+                    };
+                    if response_code == ResponseCode::NoError {
+                        debug!("Answering query without records: {:?}", e);
+                    } else {
+                        warn!("Answering query with {}: {:?}", response_code, e);
+                    }
+                    self.error_response(&request, response_code, &e)
+                }
+            },
+        };
+        let max_len = edns::max_response_len(&request, max_len, self.edns_payload_size);
+        Ok(truncate(response.to_bytes()?, max_len)?)
+    }
+
+    /// Response to request, with an OPT record if the request carries one and the server supports EDNS
+    fn response_builder(&self, request: &DnsMessage) -> ResponseBuilder {
+        let mut builder = ResponseBuilder::new(request);
+        if let (Some(_), Some(payload_size)) = (request.edns(), self.edns_payload_size) {
+            builder.set_edns(edns::opt(payload_size));
+        }
+        builder
+    }
+
+    fn respond(&self, request: &DnsMessage) -> Result<DnsMessage, Error> {
+        let (message, capabilities) = match self.message_decoder.decode_with_capabilities(request) {
+            Ok(decoded) => decoded,
+            Err(e) if e.is_foreign_name() => return self.lookup(request).ok_or_else(|| e.into()),
+            Err(e) => return Err(e.into()),
+        };
+        debug!("Decoded message = {:?}", message);
+
+        let (accepted, finished, failed) = {
+            let mut state = self.state.lock().expect("Server state lock poisoned");
+            let accepted = state.accept_using(message, capabilities);
+            let finished: Vec<_> = state.finished_states.drain(..).collect();
+            let failed: Vec<_> = state.failed_states.drain(..).collect();
+            (accepted, finished, failed)
+        };
+        // the server state only keeps the books, finished files are stored without its lock,
+        // so other queries do not wait while a file of up to 1 GiB is synced to disk
+        for state in finished {
+            self.storage.finished(state);
+        }
+        for state in failed {
+            self.storage.failed(state);
+        }
+        let response = match accepted? {
+            Accepted::Response(response) => response,
+            Accepted::Chunk(chunk) => {
+                // written and hashed under the lock of its transmission, queries of other transmissions go on meanwhile
+                chunk.write()?;
+                self.state.lock().expect("Server state lock poisoned").complete(chunk)?
+            }
+        };
+        debug!("Responding with: {:?}", response);
+
+        let query = request.queries().first().ok_or(MessageDecoderError::NoQueries)?;
+        let r_data = response.encode_for(query.query_type())?;
+        let name = query.name().clone();
+        Ok(self.response_builder(request).add_answer(Record::from_rdata(name, RESPONSE_TTL, r_data)).build())
+    }
+
+    /// Authoritative answer of the zone, if it is responsible for the query
+    fn lookup(&self, request: &DnsMessage) -> Option<DnsMessage> {
+        let query = request.queries().first()?;
+        let lookup = self.zone.as_ref()?.lookup(query)?;
+        debug!("Answering from zone: {:?}", lookup);
+        Some(self.response_builder(request)
+            .add_answers(lookup.answers)
+            .add_authority(lookup.authority)
+            .add_additionals(lookup.additionals)
+            .build())
+    }
+
+    ///
+    /// Negative answers, NXDOMAIN and NODATA, carry the SOA record of the zone, so resolvers know how long to cache them (RFC 2308).
+    ///
+    /// NXDOMAIN is only sent for rejected messages, its SOA has a TTL of 0: a rejected message may be accepted later,
+    /// e.g. a chunk ahead of the window, and resolvers must not deny names below it either (RFC 8020).
+    ///
+    /// TXT queries of a message also get the error code, it tells the client why the message was rejected.
+    ///
+    fn error_response(&self, request: &DnsMessage, response_code: ResponseCode, error: &Error) -> DnsMessage {
+        let mut builder = self.response_builder(request);
+        builder.set_response_code(response_code);
+        if let (Some(query), Some(code)) = (request.queries().first(), error_code(error)) {
+            if query.query_type() == RecordType::TXT {
+                let r_data = MessageResponse::Error { code }.encode_txt();
+                builder.add_answer(Record::from_rdata(query.name().clone(), RESPONSE_TTL, r_data));
+            }
+        }
+        if let (ResponseCode::NXDomain | ResponseCode::NoError, Some(zone)) = (response_code, &self.zone) {
+            let mut soa = zone.negative_soa();
+            if response_code == ResponseCode::NXDomain {
+                soa.set_ttl(RESPONSE_TTL);
+            }
+            builder.add_authority(vec![soa]);
+        }
+        builder.build()
+    }
+
+    /// Hand transmissions without a message within the idle timeout to the storage
+    pub fn evict_idle(&self) {
+        let expired: Vec<_> = {
+            let mut state = self.state.lock().expect("Server state lock poisoned");
+            state.evict_idle(Instant::now());
+            state.expired_states.drain(..).collect()
+        };
+        for state in expired {
+            self.storage.expired(state);
+        }
+    }
+}
+
+///
+/// TTL of protocol responses, resolvers must not cache them.
+///
+/// Repeated queries like the finish message have the same name, but may get another answer, e.g. after a resend.
+///
+const RESPONSE_TTL: u32 = 0;
+
+/// TC bit in the third byte of the header
+const TC_BIT: u8 = 0b10;
+
+///
+/// Responses that do not fit into the transport are replaced by an empty one with the TC bit, the client asks again over TCP.
+///
+/// Only the question and the OPT record are kept, so the client still learns the payload size of the server.
+///
+pub fn truncate(response: Vec<u8>, max_len: usize) -> Result<Vec<u8>, ProtoError> {
+    if response.len() <= max_len {
+        return Ok(response);
+    }
+    let mut dns_message = DnsMessage::from_bytes(&response)?;
+    dns_message.take_answers();
+    dns_message.take_name_servers();
+    dns_message.take_additionals();
+    let mut truncated = dns_message.to_bytes()?;
+    // the encoder recalculates the TC bit from the records it left out, so it has to be set afterwards
+    truncated[2] |= TC_BIT;
+    Ok(truncated)
+}
+
+#[cfg(test)]
+mod driver_tests {
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use trust_dns_proto::op::{Query, ResponseCode};
+    use trust_dns_proto::rr::domain::Label;
+    use trust_dns_proto::rr::{Name, RecordType};
+
+    use super::*;
+    use crate::message::ErrorCode;
+    use crate::transport::{ChannelTransport, MAX_UDP_LEN};
+    use crate::zone::NEGATIVE_TTL;
+
+    #[test]
+    fn test_truncate() {
+        let mut dns_message = DnsMessage::new();
+        dns_message.add_query(trust_dns_proto::op::Query::query(sub_domain(), trust_dns_proto::rr::RecordType::A));
+        let response = MessageResponse::Data { response: crate::message::DataResponse::Acknowledge { next_id: 3 } };
+        dns_message.add_answer(Record::from_rdata(sub_domain(), 120, response.encode().unwrap()));
+        let bytes = dns_message.to_bytes().unwrap();
+
+        assert_eq!(bytes, truncate(bytes.clone(), bytes.len()).unwrap());
+        let truncated = DnsMessage::from_bytes(&truncate(bytes.clone(), bytes.len() - 1).unwrap()).unwrap();
+        assert!(truncated.truncated());
+        assert!(truncated.answers().is_empty());
+        assert_eq!(dns_message.queries(), truncated.queries());
+    }
+
+    /// Keeps the files in memory
+    #[derive(Default)]
+    struct MemoryStorage {
+        finished: Mutex<Vec<(String, Vec<u8>)>>,
+        failed: Mutex<Vec<String>>,
+    }
+
+    impl Storage for MemoryStorage {
+        fn finished(&self, state: TransmissionState) {
+            let data = state.data().sink.read_all().unwrap();
+            self.finished.lock().unwrap().push((state.name, data));
+        }
+
+        fn failed(&self, state: TransmissionState) {
+            self.failed.lock().unwrap().push(state.name);
+        }
+
+        fn expired(&self, _state: TransmissionState) {}
+    }
+
+    /// A channel that carries at most max_len bytes per message, like a datagram without EDNS
+    struct SmallTransport {
+        channel: ChannelTransport,
+        max_len: usize,
+    }
+
+    impl Transport for SmallTransport {
+        fn send(&mut self, message: &[u8]) -> io::Result<()> {
+            self.channel.send(message)
+        }
+
+        fn receive(&mut self, buffer: &mut Vec<u8>, timeout: Duration) -> io::Result<()> {
+            self.channel.receive(buffer, timeout)
+        }
+
+        fn max_message_len(&self) -> usize {
+            self.max_len
+        }
+    }
+
+    fn magic_nr() -> Label {
+        Label::from_ascii("8k1").unwrap()
+    }
+
+    fn sub_domain() -> Name {
+        Name::from_ascii("extract.de.").unwrap()
+    }
+
+    fn server() -> Arc<ServerDriver<MemoryStorage>> {
+        let decoder = MessageDecoder::new(magic_nr(), sub_domain());
+        Arc::new(ServerDriver::new(decoder, ServerState::new(), MemoryStorage::default()))
+    }
+
+    fn serve<T: Transport + Send + 'static>(server: &Arc<ServerDriver<MemoryStorage>>, mut transport: T) -> thread::JoinHandle<()> {
+        let server = Arc::clone(server);
+        thread::spawn(move || server.serve(&mut transport))
+    }
+
+    fn client(data: &[u8], codec: CodecKind, response_type: ResponseType, capabilities: Capabilities) -> ClientDriver {
+        let encoder = MessageEncoder::new(magic_nr(), sub_domain(), CodecKind::Base32, response_type);
+        let slice_size = slice_size(&encoder, None);
+        let state = client::TransmissionState::new("db".to_string(), "passwords.txt".to_string(), data.to_vec(), slice_size, capabilities);
+        ClientDriver::new(encoder, state, codec)
+    }
+
+    #[test]
+    fn test_transfer() {
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let transfers = vec![
+            (CodecKind::Base32, ResponseType::Address, Capabilities::CODEC_BASE32),
+            (CodecKind::Hex, ResponseType::Txt, Capabilities::CODEC_HEX.union(Capabilities::RESPONSE_TXT)),
+            (CodecKind::Base36, ResponseType::Address, Capabilities::CODEC_BASE36.union(Capabilities::WINDOW)),
+        ];
+        let server = server();
+        for (codec, response_type, capabilities) in transfers {
+            let (client_transport, server_transport) = ChannelTransport::pair();
+            let serving = serve(&server, server_transport);
+            client(&data, codec, response_type, capabilities).run(Box::new(client_transport)).unwrap();
+            serving.join().unwrap();
+        }
+        let finished = server.storage().finished.lock().unwrap();
+        assert_eq!(3, finished.len());
+        assert!(finished.iter().all(|(name, file)| name == "passwords.txt" && file == &data));
+        assert!(server.storage().failed.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fallback_on_truncation() {
+        let server = server();
+        let (client_transport, server_transport) = ChannelTransport::pair();
+        let serving = serve(&server, SmallTransport { channel: server_transport, max_len: 100 });
+        let (fallback_transport, server_transport) = ChannelTransport::pair();
+        let serving_fallback = serve(&server, server_transport);
+
+        // without EDNS the client can not receive more than the transport carries
+        let mut encoder = MessageEncoder::new(magic_nr(), sub_domain(), CodecKind::Base32, ResponseType::Address);
+        encoder.set_edns_payload_size(None);
+        let state = client::TransmissionState::new("db".to_string(), "passwords.txt".to_string(), b"password=password123".to_vec(), 10, Capabilities::CODEC_BASE32);
+        let mut client = ClientDriver::new(encoder, state, CodecKind::Base32);
+        client.set_fallback(Box::new(fallback_transport));
+        client.run(Box::new(client_transport)).unwrap();
+        drop(client);
+        serving.join().unwrap();
+        serving_fallback.join().unwrap();
+        assert_eq!(b"password=password123".to_vec(), server.storage().finished.lock().unwrap()[0].1);
+    }
+
+    #[test]
+    fn test_truncation_over_udp() {
+        use crate::transport::{TcpTransport, UdpTransport};
+        use std::net::TcpListener;
+
+        let name_servers: Vec<Name> = (0..12)
+            .map(|i| Name::from_ascii(format!("ns.{}-a-rather-long-name-of-a-name-server-{}.example.", i, i)).unwrap())
+            .collect();
+        let decoder = MessageDecoder::new(magic_nr(), sub_domain());
+        let mut server = ServerDriver::new(decoder, ServerState::new(), MemoryStorage::default());
+        server.set_zone(Zone::new(sub_domain(), name_servers, Name::from_ascii("hostmaster.extract.de.").unwrap()));
+        let server = Arc::new(server);
+
+        // the NS records exceed a datagram, the client receives the TC bit
+        let mut server_transport = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let mut client = UdpTransport::connect("127.0.0.1:0", server_transport.local_addr().unwrap()).unwrap();
+        let mut buffer = Vec::new();
+        client.send(&query("extract.de.", RecordType::NS)).unwrap();
+        server_transport.receive(&mut buffer, Duration::from_secs(1)).unwrap();
+        server.reply(&mut server_transport, &buffer);
+        client.receive(&mut buffer, Duration::from_secs(1)).unwrap();
+        assert!(buffer.len() <= MAX_UDP_LEN);
+        let response = DnsMessage::from_bytes(&buffer).unwrap();
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+        assert_eq!(4711, response.id());
+
+        // over TCP the same query is answered in full
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpTransport::connect(listener.local_addr().unwrap());
+        client.send(&query("extract.de.", RecordType::NS)).unwrap();
+        let mut connection = TcpTransport::from_stream(listener.accept().unwrap().0).unwrap();
+        let serving = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve_connection(&mut connection, Duration::from_secs(1)).unwrap())
+        };
+        client.receive(&mut buffer, Duration::from_secs(1)).unwrap();
+        let response = DnsMessage::from_bytes(&buffer).unwrap();
+        assert!(!response.truncated());
+        assert_eq!(12, response.answers().len());
+        drop(client);
+        serving.join().unwrap();
+    }
+
+    fn query(name: &str, record_type: trust_dns_proto::rr::RecordType) -> Vec<u8> {
+        let mut dns_message = DnsMessage::new();
+        dns_message.set_id(4711)
+            .set_recursion_desired(true)
+            .add_query(trust_dns_proto::op::Query::query(Name::from_ascii(name).unwrap(), record_type));
+        dns_message.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn test_zone() {
+        use trust_dns_proto::op::ResponseCode;
+        use trust_dns_proto::rr::RecordType;
+
+        let decoder = MessageDecoder::new(magic_nr(), sub_domain());
+        let mut server = ServerDriver::new(decoder, ServerState::new(), MemoryStorage::default());
+        let response = DnsMessage::from_bytes(&server.answer(&query("extract.de.", RecordType::SOA), MAX_UDP_LEN).unwrap()).unwrap();
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert!(response.answers().is_empty());
+        assert!(response.name_servers().is_empty());
+
+        server.set_zone(Zone::new(sub_domain(), vec![Name::from_ascii("ns1.extract.de.").unwrap()], Name::from_ascii("hostmaster.extract.de.").unwrap()));
+        let response = DnsMessage::from_bytes(&server.answer(&query("extract.de.", RecordType::SOA), MAX_UDP_LEN).unwrap()).unwrap();
+        assert_eq!(4711, response.id());
+        assert_eq!(MessageType::Response, response.message_type());
+        assert!(response.authoritative());
+        assert!(response.recursion_desired());
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert_eq!(RecordType::SOA, response.answers()[0].record_type());
+
+        let response = DnsMessage::from_bytes(&server.answer(&query("www.extract.de.", RecordType::A), MAX_UDP_LEN).unwrap()).unwrap();
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert!(response.answers().is_empty());
+        assert_eq!(RecordType::SOA, response.name_servers()[0].record_type());
+        assert_eq!(NEGATIVE_TTL, response.name_servers()[0].ttl());
+
+        // rejected messages are denied, but not cached
+        let response = DnsMessage::from_bytes(&server.answer(&query("8k1.db-0000002a-2.00000000-0.aa.extract.de.", RecordType::A), MAX_UDP_LEN).unwrap()).unwrap();
+        assert_eq!(ResponseCode::NXDomain, response.response_code());
+        assert_eq!(RecordType::SOA, response.name_servers()[0].record_type());
+        assert_eq!(0, response.name_servers()[0].ttl());
+
+        // names of other zones are refused
+        let response = DnsMessage::from_bytes(&server.answer(&query("example.com.", RecordType::A), MAX_UDP_LEN).unwrap()).unwrap();
+        assert_eq!(ResponseCode::Refused, response.response_code());
+        assert!(response.authoritative());
+    }
+
+    #[test]
+    fn test_error_responses() {
+        use trust_dns_proto::op::ResponseCode;
+        use trust_dns_proto::rr::RecordType;
+
+        let server = server();
+        // a data message of an unknown transmission
+        let response = DnsMessage::from_bytes(&server.answer(&query("8k1.db-0000002a-2.00000000-0.aa.extract.de.", RecordType::A), MAX_UDP_LEN).unwrap()).unwrap();
+        assert_eq!(MessageType::Response, response.message_type());
+        assert_eq!(4711, response.id());
+        assert_eq!(ResponseCode::NXDomain, response.response_code());
+        assert!(response.answers().is_empty());
+
+        // TXT queries learn why
+        let response = DnsMessage::from_bytes(&server.answer(&query("8k1.db-0000002a-2.00000000-0.aa.extract.de.", RecordType::TXT), MAX_UDP_LEN).unwrap()).unwrap();
+        assert_eq!(ResponseCode::NXDomain, response.response_code());
+        assert_eq!(MessageResponse::Error { code: ErrorCode::UnknownSession }, MessageResponse::decode(&response).unwrap());
+
+        // a broken query gets FORMERR, as long as the header can be read
+        let mut broken = query("extract.de.", RecordType::A);
+        broken.truncate(broken.len() - 2);
+        let response = DnsMessage::from_bytes(&server.answer(&broken, MAX_UDP_LEN).unwrap()).unwrap();
+        assert_eq!(ResponseCode::FormErr, response.response_code());
+        assert_eq!(4711, response.id());
+        assert!(server.answer(&broken[..4], MAX_UDP_LEN).is_err());
+
+        // responses are never answered, that could start a loop
+        let mut response = DnsMessage::from_bytes(&query("extract.de.", RecordType::A)).unwrap();
+        response.set_message_type(MessageType::Response);
+        assert!(server.answer(&response.to_bytes().unwrap(), MAX_UDP_LEN).is_err());
+    }
+
+    #[test]
+    fn test_case_randomization() {
+        // resolvers may change the case of every letter (0x20 encoding), also when they retry a query
+        let server = server();
+        let encoder = MessageEncoder::new(magic_nr(), sub_domain(), CodecKind::Base32, ResponseType::Address);
+        let announcement = Message::initial("db".to_string(), "passwords.txt".to_string(), 0x2a, Capabilities::supported());
+        let name = encoder.encode(announcement).unwrap().queries()[0].name().to_ascii();
+        let mixed_case: String = name.chars().enumerate()
+            .map(|(i, c)| if i % 2 == 0 { c.to_ascii_uppercase() } else { c })
+            .collect();
+        let upper_case = name.to_ascii_uppercase();
+
+        let mut responses = Vec::new();
+        for name in [&name, &mixed_case, &upper_case] {
+            let response = DnsMessage::from_bytes(&server.answer(&query(name, RecordType::A), MAX_UDP_LEN).unwrap()).unwrap();
+            assert_eq!(ResponseCode::NoError, response.response_code(), "name {}", name);
+            responses.push(MessageResponse::decode(&response).unwrap());
+        }
+        assert_eq!(responses[0], responses[1]);
+        assert_eq!(responses[0], responses[2]);
+    }
+
+    #[test]
+    fn test_refused() {
+        let server = server();
+        let (client_transport, server_transport) = ChannelTransport::pair();
+        let serving = serve(&server, server_transport);
+
+        // the server is not responsible for the sub domain, announcing with other tokens does not help
+        let encoder = MessageEncoder::new(magic_nr(), Name::from_ascii("other.de.").unwrap(), CodecKind::Base32, ResponseType::Address);
+        let state = client::TransmissionState::new("db".to_string(), "passwords.txt".to_string(), vec![1, 2, 3], 10, Capabilities::CODEC_BASE32);
+        let mut client = ClientDriver::new(encoder, state, CodecKind::Base32);
+        match client.run(Box::new(client_transport)) {
+            Err(Error::Client(client::ClientError::Refused { code: None })) => {}
+            other => panic!("Expected a refused transmission, got {:?}", other),
+        }
+        drop(client);
+        serving.join().unwrap();
+        assert!(server.state.lock().unwrap().finished_states.is_empty());
+    }
+
+    #[test]
+    fn test_edns() {
+        use trust_dns_proto::op::ResponseCode;
+        use trust_dns_proto::rr::RecordType;
+
+        let name_servers: Vec<Name> = (0..12)
+            .map(|i| Name::from_ascii(format!("ns.{}-a-rather-long-name-of-a-name-server-{}.example.", i, i)).unwrap())
+            .collect();
+        let decoder = MessageDecoder::new(magic_nr(), sub_domain());
+        let mut server = ServerDriver::new(decoder, ServerState::new(), MemoryStorage::default());
+        server.set_zone(Zone::new(sub_domain(), name_servers, Name::from_ascii("hostmaster.extract.de.").unwrap()));
+        let query_with_edns = |payload_size: u16, version: u8| {
+            let mut request = DnsMessage::from_bytes(&query("extract.de.", RecordType::NS)).unwrap();
+            let mut edns = edns::opt(payload_size);
+            edns.set_version(version);
+            request.set_edns(edns);
+            request.to_bytes().unwrap()
+        };
+
+        // the NS records exceed 512 bytes, so they need EDNS
+        let response = DnsMessage::from_bytes(&server.answer(&query("extract.de.", RecordType::NS), MAX_UDP_LEN).unwrap()).unwrap();
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+        assert!(response.edns().is_none());
+
+        let bytes = server.answer(&query_with_edns(4096, 0), MAX_UDP_LEN).unwrap();
+        assert!(bytes.len() > MAX_UDP_LEN);
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        assert!(!response.truncated());
+        assert_eq!(12, response.answers().len());
+        assert_eq!(edns::DEFAULT_PAYLOAD_SIZE, response.edns().unwrap().max_payload());
+
+        // the advertised size is respected, the truncated response keeps the OPT record
+        let response = DnsMessage::from_bytes(&server.answer(&query_with_edns(600, 0), MAX_UDP_LEN).unwrap()).unwrap();
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+        assert_eq!(edns::DEFAULT_PAYLOAD_SIZE, response.edns().unwrap().max_payload());
+
+        let response = DnsMessage::from_bytes(&server.answer(&query_with_edns(4096, 1), MAX_UDP_LEN).unwrap()).unwrap();
+        assert_eq!(u16::from(ResponseCode::BADVERS), u16::from(response.response_code()));
+        assert_eq!(edns::VERSION, response.edns().unwrap().version());
+
+        // a server without EDNS ignores the OPT record
+        server.set_edns_payload_size(None);
+        let response = DnsMessage::from_bytes(&server.answer(&query_with_edns(4096, 1), MAX_UDP_LEN).unwrap()).unwrap();
+        assert!(response.truncated());
+        assert!(response.edns().is_none());
+    }
+
+    ///
+    /// A resolver that minimises the query name (RFC 7816), it asks for every ancestor below the sub domain
+    /// before it sends the full name, and gives up if an ancestor is answered with NXDOMAIN.
+    ///
+    struct MinimisingResolver {
+        server: ChannelTransport,
+        /** Ask for `_.<ancestor>` instead of `<ancestor>`, like some resolvers do to hide the query type */
+        underscore: bool,
+        /** Responses to full queries that arrived while an ancestor was asked for */
+        pending: VecDeque<Vec<u8>>,
+        minimised_queries: Arc<AtomicUsize>,
+    }
+
+    impl MinimisingResolver {
+        fn ask_for_ancestor(&mut self, id: u16, name: Name) -> io::Result<()> {
+            let mut query = DnsMessage::new();
+            query.set_id(id).add_query(Query::query(name.clone(), RecordType::A));
+            self.server.send(&query.to_bytes().unwrap())?;
+            let mut buffer = Vec::new();
+            loop {
+                self.server.receive(&mut buffer, Duration::from_secs(1))?;
+                let response = DnsMessage::from_bytes(&buffer).unwrap();
+                if response.id() == id && response.queries()[0].name() == &name {
+                    assert_eq!(ResponseCode::NoError, response.response_code(), "resolver gives up at {}", name);
+                    assert!(response.answers().is_empty());
+                    self.minimised_queries.fetch_add(1, Ordering::SeqCst);
+                    return Ok(());
+                }
+                self.pending.push_back(buffer.clone());
+            }
+        }
+    }
+
+    impl Transport for MinimisingResolver {
+        fn send(&mut self, message: &[u8]) -> io::Result<()> {
+            let query = DnsMessage::from_bytes(message).unwrap();
+            let name = query.queries()[0].name().clone();
+            for num_labels in sub_domain().num_labels() + 1..name.num_labels() {
+                let mut ancestor = name.trim_to(num_labels as usize);
+                if self.underscore {
+                    ancestor = Name::from_ascii("_").unwrap().append_domain(&ancestor);
+                }
+                self.ask_for_ancestor(query.id().wrapping_add(1), ancestor)?;
+            }
+            self.server.send(message)
+        }
+
+        fn receive(&mut self, buffer: &mut Vec<u8>, timeout: Duration) -> io::Result<()> {
+            match self.pending.pop_front() {
+                Some(response) => {
+                    *buffer = response;
+                    Ok(())
+                }
+                None => self.server.receive(buffer, timeout),
+            }
+        }
+    }
+
+    #[test]
+    fn test_minimising_resolver() {
+        let mut with_zone = ServerDriver::new(MessageDecoder::new(magic_nr(), sub_domain()), ServerState::new(), MemoryStorage::default());
+        with_zone.set_zone(Zone::new(sub_domain(), vec![Name::from_ascii("ns1.extract.de.").unwrap()], Name::from_ascii("hostmaster.extract.de.").unwrap()));
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+
+        for (server, underscore) in [(server(), false), (Arc::new(with_zone), true)] {
+            let (client_transport, server_transport) = ChannelTransport::pair();
+            let serving = serve(&server, server_transport);
+            let minimised_queries = Arc::new(AtomicUsize::new(0));
+            let resolver = MinimisingResolver {
+                server: client_transport,
+                underscore,
+                pending: VecDeque::new(),
+                minimised_queries: Arc::clone(&minimised_queries),
+            };
+            let capabilities = Capabilities::CODEC_BASE32.union(Capabilities::WINDOW);
+            client(&data, CodecKind::Base32, ResponseType::Txt, capabilities).run(Box::new(resolver)).unwrap();
+            serving.join().unwrap();
+
+            assert!(minimised_queries.load(Ordering::SeqCst) > 10);
+            assert_eq!(data, server.storage().finished.lock().unwrap()[0].1);
+        }
+    }
+
+    ///
+    /// A recursive resolver that caches answers for their TTL, like any resolver between client and server.
+    ///
+    /// It also moves the first chunk to offset 10 once, so the server misses the start of the file at the finish message.
+    ///
+    struct CachingResolver {
+        server: ChannelTransport,
+        cache: HashMap<(Name, RecordType), (Instant, Vec<u8>)>,
+        /** Answers from the cache, waiting to be received */
+        pending: VecDeque<Vec<u8>>,
+        misplaced: bool,
+    }
+
+    impl Transport for CachingResolver {
+        fn send(&mut self, message: &[u8]) -> io::Result<()> {
+            let query = DnsMessage::from_bytes(message).unwrap();
+            let name = query.queries()[0].name().to_lowercase();
+            if let Some((expires, response)) = self.cache.get(&(name.clone(), query.queries()[0].query_type())) {
+                if *expires > Instant::now() {
+                    let mut response = DnsMessage::from_bytes(response).unwrap();
+                    response.set_id(query.id());
+                    self.pending.push_back(response.to_bytes().unwrap());
+                    return Ok(());
+                }
+            }
+            let chunk_label = name[2].to_ascii();
+            if !self.misplaced && name[1].to_ascii().starts_with('d') && chunk_label.ends_with("-0") {
+                // same length, so the label can be replaced in place
+                self.misplaced = true;
+                let position = message.windows(chunk_label.len()).position(|window| window == chunk_label.as_bytes()).unwrap();
+                let mut message = message.to_vec();
+                message[position + chunk_label.len() - 1] = b'a';
+                return self.server.send(&message);
+            }
+            self.server.send(message)
+        }
+
+        fn receive(&mut self, buffer: &mut Vec<u8>, timeout: Duration) -> io::Result<()> {
+            if let Some(response) = self.pending.pop_front() {
+                *buffer = response;
+                return Ok(());
+            }
+            self.server.receive(buffer, timeout)?;
+            let response = DnsMessage::from_bytes(buffer).unwrap();
+            if let Some(ttl) = response.answers().iter().map(|answer| answer.ttl()).min() {
+                let key = (response.queries()[0].name().to_lowercase(), response.queries()[0].query_type());
+                self.cache.insert(key, (Instant::now() + Duration::from_secs(ttl as u64), buffer.clone()));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_caching_resolver() {
+        let data: Vec<u8> = (0..30).collect();
+        for response_type in [ResponseType::Address, ResponseType::Txt] {
+            let server = server();
+            let (client_transport, server_transport) = ChannelTransport::pair();
+            let serving = serve(&server, server_transport);
+            let resolver = CachingResolver { server: client_transport, cache: HashMap::new(), pending: VecDeque::new(), misplaced: false };
+
+            // the finish message is answered with a resend first, the repeated one must reach the server
+            let encoder = MessageEncoder::new(magic_nr(), sub_domain(), CodecKind::Base32, response_type);
+            let mut state = client::TransmissionState::new("db".to_string(), "passwords.txt".to_string(), data.clone(), 10, Capabilities::supported());
+            state.set_initial_timeout(Duration::from_millis(10));
+            let mut client = ClientDriver::new(encoder, state, CodecKind::Base32);
+            client.set_slice_size(10);
+            client.run(Box::new(resolver)).unwrap();
+            drop(client);
+            serving.join().unwrap();
+            assert_eq!(data, server.storage().finished.lock().unwrap()[0].1);
+        }
+    }
+
+    #[test]
+    fn test_server_gone() {
+        let (client_transport, server_transport) = ChannelTransport::pair();
+        drop(server_transport);
+        let mut state = client::TransmissionState::new("db".to_string(), "passwords.txt".to_string(), b"password".to_vec(), 10, Capabilities::CODEC_BASE32);
+        state.set_max_retries(1);
+        state.set_initial_timeout(Duration::from_millis(1));
+        let encoder = MessageEncoder::new(magic_nr(), sub_domain(), CodecKind::Base32, ResponseType::Address);
+        let mut client = ClientDriver::new(encoder, state, CodecKind::Base32);
+        match client.run(Box::new(client_transport)) {
+            Err(Error::Client(client::ClientError::TooManyRetries { retries: 1 })) => {}
+            other => panic!("Expected TooManyRetries, got {:?}", other),
+        }
+    }
+}
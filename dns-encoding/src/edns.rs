@@ -0,0 +1,52 @@
+use trust_dns_proto::op::{Edns, Message as DnsMessage};
+
+/// Advertised UDP payload size, the largest one that avoids IP fragmentation on common paths (DNS flag day 2020)
+pub const DEFAULT_PAYLOAD_SIZE: u16 = 1232;
+
+/// The only version of EDNS, queries with a higher one are answered with BADVERS (RFC 6891)
+pub const VERSION: u8 = 0;
+
+///
+/// OPT pseudo record that advertises how large a UDP message the sender can receive.
+///
+/// Without it, UDP messages are limited to 512 bytes (RFC 1035).
+///
+pub fn opt(payload_size: u16) -> Edns {
+    let mut edns = Edns::new();
+    edns.set_version(VERSION);
+    edns.set_max_payload(payload_size);
+    edns
+}
+
+///
+/// Largest response to request over a transport that carries `transport_len` bytes without EDNS.
+///
+/// A request with an OPT record may receive up to its advertised payload size, but not more than
+/// the server is willing to send, `payload_size`. `None` means the server does not support EDNS.
+///
+pub fn max_response_len(request: &DnsMessage, transport_len: usize, payload_size: Option<u16>) -> usize {
+    match (request.edns(), payload_size) {
+        (Some(edns), Some(payload_size)) => transport_len.max(edns.max_payload().min(payload_size) as usize),
+        _ => transport_len,
+    }
+}
+
+#[cfg(test)]
+mod edns_tests {
+    use super::*;
+
+    #[test]
+    fn test_max_response_len() {
+        let mut request = DnsMessage::new();
+        assert_eq!(512, max_response_len(&request, 512, Some(DEFAULT_PAYLOAD_SIZE)));
+
+        request.set_edns(opt(4096));
+        assert_eq!(1232, max_response_len(&request, 512, Some(DEFAULT_PAYLOAD_SIZE)));
+        assert_eq!(512, max_response_len(&request, 512, None));
+        // TCP carries more than any payload size
+        assert_eq!(65535, max_response_len(&request, 65535, Some(DEFAULT_PAYLOAD_SIZE)));
+
+        request.set_edns(opt(1000));
+        assert_eq!(1000, max_response_len(&request, 512, Some(DEFAULT_PAYLOAD_SIZE)));
+    }
+}
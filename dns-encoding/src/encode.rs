@@ -1,51 +1,176 @@
+use std::fmt;
+
+use trust_dns_proto::error::ProtoError;
 use trust_dns_proto::rr::domain::Label;
 use trust_dns_proto::rr::Name;
-use crate::message::{Message, ANNOUNCEMENT_ID, FINISH_ID};
-use trust_dns_proto::op::Query;
-use base32::Alphabet;
+use crate::codec::CodecKind;
+use crate::header::{FrameHeader, MessageType};
+use crate::message::{Id, Message, ResponseType};
+use trust_dns_proto::op::{Edns, Query};
+use crate::edns;
+
+/// Maximum length of a single label
+pub const MAX_LABEL_LEN: usize = 63;
+/// Maximum length of a name in wire format, every label is prefixed by its length, plus the root
+pub const MAX_NAME_LEN: usize = 255;
+/// Length of the checksum label of data messages, a CRC32 in hex
+pub const CHECKSUM_LEN: usize = 8;
+/// Length of the longest offset in hex, it follows the checksum separated by a hyphen
+pub const OFFSET_LEN: usize = 16;
+
+#[derive(Debug)]
+pub enum MessageEncoderError {
+    /** A label is longer than 63 bytes or contains invalid characters, e.g. the host */
+    InvalidLabel(ProtoError),
+    /** The query name is longer than 255 bytes */
+    NameTooLong { len: usize },
+}
+
+impl fmt::Display for MessageEncoderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageEncoderError::InvalidLabel(_) => write!(f, "invalid label"),
+            MessageEncoderError::NameTooLong { len } => write!(f, "query name of {} bytes exceeds {} bytes", len, MAX_NAME_LEN),
+        }
+    }
+}
+
+impl std::error::Error for MessageEncoderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MessageEncoderError::InvalidLabel(e) => Some(e),
+            MessageEncoderError::NameTooLong { .. } => None,
+        }
+    }
+}
+
+impl From<ProtoError> for MessageEncoderError {
+    fn from(e: ProtoError) -> Self {
+        MessageEncoderError::InvalidLabel(e)
+    }
+}
 
 pub struct MessageEncoder {
     magic_nr: Label,
     sub_domain: Name,
+    codec: CodecKind,
+    response_type: ResponseType,
+    /** UDP payload size advertised in an OPT record, `None` sends queries without EDNS */
+    edns_payload_size: Option<u16>,
 }
 
 impl MessageEncoder {
 
-    pub fn new(magic_nr: Label, sub_domain: Name) -> MessageEncoder {
-        MessageEncoder { magic_nr, sub_domain }
+    pub fn new(magic_nr: Label, sub_domain: Name, codec: CodecKind, response_type: ResponseType) -> MessageEncoder {
+        MessageEncoder { magic_nr, sub_domain, codec, response_type, edns_payload_size: Some(edns::DEFAULT_PAYLOAD_SIZE) }
     }
 
-    pub fn encode(&self, message: Message) -> trust_dns_proto::op::Message {
-        let mut dns_message = trust_dns_proto::op::Message::new();
+    pub fn set_codec(&mut self, codec: CodecKind) {
+        self.codec = codec;
+    }
+
+    pub fn set_response_type(&mut self, response_type: ResponseType) {
+        self.response_type = response_type;
+    }
+
+    pub fn response_type(&self) -> ResponseType {
+        self.response_type
+    }
+
+    /// Advertise that responses up to payload_size bytes can be received over UDP, `None` disables EDNS
+    pub fn set_edns_payload_size(&mut self, payload_size: Option<u16>) {
+        self.edns_payload_size = payload_size;
+    }
 
-        let mut name = Name::new().append_label(&self.magic_nr).unwrap();
+    /// The OPT record sent with every query, if EDNS is enabled
+    pub fn edns(&self) -> Option<Edns> {
+        self.edns_payload_size.map(edns::opt)
+    }
+
+    ///
+    /// Maximum number of data bytes that fit into a single data message.
+    ///
+    /// The payload is spread over as many labels as fit between the header label
+    /// and the sub domain.
+    ///
+    pub fn max_payload_per_query(&self) -> usize {
+        let fixed_len = 1 + self.magic_nr.len() +
+            1 + FrameHeader::MAX_LEN +
+            1 + CHECKSUM_LEN + 1 + OFFSET_LEN +
+            self.sub_domain.iter().map(|l| 1 + l.len()).sum::<usize>() +
+            1; // root label
+        let available = MAX_NAME_LEN.saturating_sub(fixed_len);
+        let full_labels = available / (1 + MAX_LABEL_LEN);
+        let last_label_len = (available % (1 + MAX_LABEL_LEN)).saturating_sub(1);
+        let encoded_len = full_labels * MAX_LABEL_LEN + last_label_len;
+        self.codec.codec().max_decoded_len(encoded_len)
+    }
 
-        let (id, payload_name) = match message {
-            Message::Announcement { host, file_name, rnd_nr } => {
-                let encoded_filename = base32::encode(Alphabet::Crockford, file_name.as_bytes());
-                let labels = vec![host, encoded_filename, rnd_nr.to_string()];
-                (ANNOUNCEMENT_ID, Name::from_labels(labels).unwrap())
+    pub fn encode(&self, message: Message) -> Result<trust_dns_proto::op::Message, MessageEncoderError> {
+        let mut dns_message = trust_dns_proto::op::Message::new();
+
+        let (header, payload_name) = match message {
+            Message::Announcement { host, file_name, rnd_nr, version, capabilities } => {
+                let encoded_filename = CodecKind::Base32.codec().encode(file_name.as_bytes());
+                // announcements carry the protocol version in the sequence field
+                let header = FrameHeader::new(MessageType::Announcement, rnd_nr, version as Id);
+                let mut payload_name = Name::new()
+                    .append_label(host.as_str())?;
+                if version > 0 {
+                    payload_name = payload_name.append_label(format!("{:x}", capabilities.0))?;
+                }
+                let payload_name = payload_name.append_name(&Self::split_into_labels(&encoded_filename)?);
+                (header, payload_name)
             },
-            Message::Data { id, data } => {
-                (id, Name::from_labels(vec![data]).unwrap())
+            Message::Data { rnd_nr, id, offset, data, checksum } => {
+                let header = FrameHeader::new(MessageType::Data(self.codec), rnd_nr, id);
+                let encoded_data = self.codec.codec().encode(&data);
+                let chunk_label = match offset {
+                    Some(offset) => format!("{:08x}-{:x}", checksum, offset),
+                    None => format!("{:08x}", checksum),
+                };
+                let payload_name = Name::new()
+                    .append_label(chunk_label)?
+                    .append_name(&Self::split_into_labels(&encoded_data)?);
+                (header, payload_name)
             },
-            Message::Finish { rnd_nr } => {
-                let labels = vec![rnd_nr.to_string()];
-                (FINISH_ID, Name::from_labels(labels).unwrap())
+            Message::Finish { rnd_nr, file_size, digest } => {
+                let header = FrameHeader::new(MessageType::Finish, rnd_nr, 0);
+                let encoded_digest = CodecKind::Base32.codec().encode(&digest);
+                let payload_name = Name::new()
+                    .append_label(format!("{:x}", file_size))?
+                    .append_label(encoded_digest)?;
+                (header, payload_name)
             },
         };
 
+        let mut name = Name::new()
+            .append_label(&self.magic_nr)?
+            .append_label(header.to_label())?;
         name = name.append_name(&payload_name);
         name = name.append_name(&self.sub_domain);
 
+        let len = name.iter().map(|l| 1 + l.len()).sum::<usize>() + 1;
+        if len > MAX_NAME_LEN {
+            return Err(MessageEncoderError::NameTooLong { len });
+        }
 
         let mut query = Query::new();
         query.set_name(name);
+        query.set_query_type(self.response_type.record_type());
 
-        dns_message.set_id(id);
+        // the transaction id carries no information, resolvers are free to rewrite it
+        dns_message.set_id(rand::random());
         dns_message.set_recursion_desired(true);
         dns_message.add_query(query);
-        dns_message
+        if let Some(edns) = self.edns() {
+            dns_message.set_edns(edns);
+        }
+        Ok(dns_message)
+    }
+
+    fn split_into_labels(encoded: &[u8]) -> Result<Name, ProtoError> {
+        Name::from_labels(encoded.chunks(MAX_LABEL_LEN))
     }
 
 }
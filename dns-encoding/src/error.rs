@@ -0,0 +1,127 @@
+use std::fmt;
+use std::io;
+
+use trust_dns_proto::error::ProtoError;
+
+use crate::client::ClientError;
+use crate::decode::MessageDecoderError;
+use crate::encode::MessageEncoderError;
+use crate::message::MessageResponseDecoderError;
+use crate::server::ServerError;
+
+///
+/// Any error of this crate, the error of the failing step is available as `source`.
+///
+#[derive(Debug)]
+pub enum Error {
+    /** A message or response could not be encoded into a dns message */
+    Encode(MessageEncoderError),
+    /** A query did not contain a valid message */
+    Decode(MessageDecoderError),
+    /** An answer did not contain a valid response */
+    ResponseDecode(MessageResponseDecoderError),
+    /** The server could not assign a message to a transmission */
+    Server(ServerError),
+    /** The client could not continue the transmission */
+    Client(ClientError),
+    /** A dns message could not be read or written */
+    Proto(ProtoError),
+    /** A dns message could not be sent or received */
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Encode(_) => write!(f, "failed to encode message"),
+            Error::Decode(_) => write!(f, "failed to decode message"),
+            Error::ResponseDecode(_) => write!(f, "failed to decode response"),
+            Error::Server(_) => write!(f, "failed to handle message"),
+            Error::Client(_) => write!(f, "failed to handle response"),
+            Error::Proto(_) => write!(f, "invalid dns message"),
+            Error::Io(_) => write!(f, "transport failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Encode(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            Error::ResponseDecode(e) => Some(e),
+            Error::Server(e) => Some(e),
+            Error::Client(e) => Some(e),
+            Error::Proto(e) => Some(e),
+            Error::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<MessageEncoderError> for Error {
+    fn from(e: MessageEncoderError) -> Self {
+        Error::Encode(e)
+    }
+}
+
+impl From<MessageDecoderError> for Error {
+    fn from(e: MessageDecoderError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<MessageResponseDecoderError> for Error {
+    fn from(e: MessageResponseDecoderError) -> Self {
+        Error::ResponseDecode(e)
+    }
+}
+
+impl From<ServerError> for Error {
+    fn from(e: ServerError) -> Self {
+        Error::Server(e)
+    }
+}
+
+impl From<ClientError> for Error {
+    fn from(e: ClientError) -> Self {
+        Error::Client(e)
+    }
+}
+
+impl From<ProtoError> for Error {
+    fn from(e: ProtoError) -> Self {
+        Error::Proto(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use super::*;
+    use crate::codec::CodecError;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_source_chain() {
+        let error: Error = MessageDecoderError::InvalidPayload(CodecError::InvalidCharacter).into();
+        let decoder_error = error.source().expect("Expected the decoder error");
+        assert_eq!("data labels could not be decoded", decoder_error.to_string());
+        let codec_error = decoder_error.source().expect("Expected the codec error");
+        assert_eq!("invalid character", codec_error.to_string());
+        assert!(codec_error.source().is_none());
+    }
+
+    #[test]
+    fn test_storage_source() {
+        let error: Error = ServerError::Storage(io::Error::other("disk full")).into();
+        let server_error = error.source().expect("Expected the server error");
+        let io_error = server_error.source().expect("Expected the io error");
+        assert_eq!("disk full", io_error.to_string());
+        assert!(ServerError::UnknownId { id: 2 }.source().is_none());
+    }
+}
@@ -0,0 +1,126 @@
+use trust_dns_proto::rr::domain::Label;
+
+use crate::codec::CodecKind;
+use crate::decode::MessageDecoderError;
+use crate::message::{Id, SessionId};
+
+///
+/// Type of a protocol message, transmitted as first field of the header label.
+///
+/// Data messages also name the codec of their payload (e.g. `dz` for base36),
+/// so the decoder does not need to know which codec a client picked.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum MessageType {
+    Announcement,
+    Data(CodecKind),
+    Finish,
+}
+
+impl MessageType {
+    fn tag(self) -> String {
+        match self {
+            MessageType::Announcement => "a".to_string(),
+            MessageType::Data(codec) => format!("d{}", codec.tag()),
+            MessageType::Finish => "f".to_string(),
+        }
+    }
+
+    fn from_tag(tag: &str) -> Option<MessageType> {
+        let mut chars = tag.chars();
+        let message_type = match (chars.next()?, chars.next()) {
+            ('a', None) => MessageType::Announcement,
+            ('d', Some(codec)) => MessageType::Data(CodecKind::from_tag(codec)?),
+            ('f', None) => MessageType::Finish,
+            _ => return None,
+        };
+        if chars.next().is_some() {
+            return None;
+        }
+        Some(message_type)
+    }
+}
+
+///
+/// Framing header that is transmitted as the label directly after the magic nr.
+///
+/// The DNS transaction id is rewritten by recursive resolvers, so everything the server needs
+/// to assign a query to a transmission has to be part of the query name.
+/// The label has the form `<type>-<session>-<sequence>` with session and sequence in hex.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct FrameHeader {
+    pub message_type: MessageType,
+    pub session: SessionId,
+    pub sequence: Id,
+}
+
+impl FrameHeader {
+    /// Length of the longest possible header label, e.g. `db-ffffffffffffffff-ffffffff`
+    pub const MAX_LEN: usize = 28;
+
+    pub fn new(message_type: MessageType, session: SessionId, sequence: Id) -> FrameHeader {
+        FrameHeader { message_type, session, sequence }
+    }
+
+    pub fn to_label(&self) -> Label {
+        let label = format!("{}-{:x}-{:x}", self.message_type.tag(), self.session, self.sequence);
+        Label::from_ascii(label.as_str()).expect("header label is always valid ascii")
+    }
+
+    pub fn parse(label: &Label) -> Result<FrameHeader, MessageDecoderError> {
+        let ascii = label.to_ascii().to_ascii_lowercase();
+        let mut fields = ascii.split('-');
+        let message_type = fields.next()
+            .and_then(MessageType::from_tag)
+            .ok_or(MessageDecoderError::InvalidHeader)?;
+        let session = Self::parse_field(fields.next(), SessionId::from_str_radix)?;
+        let sequence = Self::parse_field(fields.next(), Id::from_str_radix)?;
+        if fields.next().is_some() {
+            return Err(MessageDecoderError::InvalidHeader);
+        }
+        Ok(FrameHeader { message_type, session, sequence })
+    }
+
+    fn parse_field<T, E>(field: Option<&str>, from_str_radix: fn(&str, u32) -> Result<T, E>) -> Result<T, MessageDecoderError> {
+        let field = field.ok_or(MessageDecoderError::InvalidHeader)?;
+        from_str_radix(field, 16).map_err(|_| MessageDecoderError::InvalidHeader)
+    }
+}
+
+#[cfg(test)]
+mod frame_header_tests {
+    use super::*;
+
+    #[test]
+    fn test_symmetric() {
+        let headers = vec![
+            FrameHeader::new(MessageType::Announcement, 48309, 0),
+            FrameHeader::new(MessageType::Data(CodecKind::Base32), 48309, 2),
+            FrameHeader::new(MessageType::Data(CodecKind::Raw), 48309, 3),
+            FrameHeader::new(MessageType::Data(CodecKind::Hex), SessionId::MAX, Id::MAX),
+            FrameHeader::new(MessageType::Finish, 0, Id::MAX),
+        ];
+        for header in headers {
+            let label = header.to_label();
+            assert!(label.len() <= FrameHeader::MAX_LEN);
+            assert_eq!(header, FrameHeader::parse(&label).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_mixed_case() {
+        // resolvers may randomize the case of the query name (0x20 encoding)
+        let label = Label::from_ascii("DZ-BCB5-2A").unwrap();
+        let header = FrameHeader::parse(&label).unwrap();
+        assert_eq!(FrameHeader::new(MessageType::Data(CodecKind::Base36), 0xbcb5, 0x2a), header);
+    }
+
+    #[test]
+    fn test_invalid() {
+        for invalid in &["x-1-2", "d-1-2", "dx-1-2", "db-1", "db-1-2-3", "db-zz-2", "db-1-100000000", "db-10000000000000000-2", "ab-1-2"] {
+            let label = Label::from_ascii(invalid).unwrap();
+            assert!(FrameHeader::parse(&label).is_err(), "{} should be rejected", invalid);
+        }
+    }
+}
@@ -1,8 +1,20 @@
 
+pub mod capabilities;
+pub mod codec;
 pub mod encode;
+pub mod error;
 pub mod decode;
+pub mod header;
 pub mod server;
 pub mod client;
 pub mod message;
+pub mod ranges;
+pub mod tcp;
+pub mod transport;
+pub mod driver;
+pub mod edns;
+pub mod response;
+pub mod sink;
+pub mod zone;
 
 mod translation_tests;
@@ -1,34 +1,116 @@
-use trust_dns_proto::rr::{RData, Name};
+use crate::capabilities::{Capabilities, PROTOCOL_VERSION};
+use crate::encode::{MessageEncoderError, MAX_NAME_LEN};
+use trust_dns_proto::rr::{RData, Name, RecordType};
+use trust_dns_proto::rr::rdata::TXT;
+use sha2::{Digest as _, Sha256};
+use std::convert::TryFrom;
+use std::fmt;
 use std::net::Ipv4Addr;
+use std::ops::Range;
 use std::str::FromStr;
 
-pub type Id = u16;
+/// Sequence number of a data message, counted per transmission
+pub type Id = u32;
 
-pub const ANNOUNCEMENT_ID: Id = 0;
-pub const FINISH_ID: Id = 1;
+/// Id of the first data message of every transmission, each one counts its own sequence from here
+pub const FIRST_ID: Id = 2;
+
+/// Ids of a sequence, the ones below `FIRST_ID` are reserved
+const ID_COUNT: u64 = Id::MAX as u64 - FIRST_ID as u64 + 1;
+
+/// The id after this one, after `Id::MAX` the sequence starts again at `FIRST_ID`
+pub fn next_id(id: Id) -> Id {
+    advance_id(id, 1)
+}
+
+/// The id `count` places after this one in the sequence
+pub fn advance_id(id: Id, count: u64) -> Id {
+    ((id.saturating_sub(FIRST_ID) as u64 + count) % ID_COUNT) as Id + FIRST_ID
+}
+
+/// Places from one id to a later one in the sequence
+pub fn id_distance(from: Id, to: Id) -> u64 {
+    (to.saturating_sub(FIRST_ID) as u64 + ID_COUNT - from.saturating_sub(FIRST_ID) as u64) % ID_COUNT
+}
+
+/// Token of a transmission, chosen at random by the client and carried in every message
+pub type SessionId = u64;
+
+/// Data messages in flight at once, limited by the bitmap of a selective acknowledgement
+pub const MAX_WINDOW_SIZE: usize = 32;
+
+/// SHA-256 of a file
+pub type Digest = [u8; 32];
+
+pub fn file_digest(data: &[u8]) -> Digest {
+    Sha256::digest(data).into()
+}
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Message {
     Announcement {
         host: String,
         file_name: String,
-        rnd_nr: u16,
+        rnd_nr: SessionId,
+        /** Protocol version of the client */
+        version: u8,
+        /** Capabilities offered by the client */
+        capabilities: Capabilities,
     },
     Data {
+        rnd_nr: SessionId,
         id: Id,
+        /** Position of data in the file, clients of version 1 send none and their chunks are appended */
+        offset: Option<u64>,
         data: Vec<u8>,
+        /** CRC32 of data, calculated by the client */
+        checksum: u32,
     },
     Finish {
-        rnd_nr: u16,
+        rnd_nr: SessionId,
+        /** Size of the whole file in bytes */
+        file_size: u64,
+        /** SHA-256 of the whole file */
+        digest: Digest,
     },
 }
 
 impl Message {
-    pub fn initial(host: String, file_name: String, rnd_nr: u16) -> Message {
+    pub fn initial(host: String, file_name: String, rnd_nr: SessionId, capabilities: Capabilities) -> Message {
         Message::Announcement {
             host,
             file_name,
             rnd_nr,
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    pub fn data(rnd_nr: SessionId, id: Id, offset: u64, data: Vec<u8>) -> Message {
+        let checksum = crc32fast::hash(&data);
+        Message::Data {
+            rnd_nr,
+            id,
+            offset: Some(offset),
+            data,
+            checksum,
+        }
+    }
+
+    pub fn finish(rnd_nr: SessionId, file: &[u8]) -> Message {
+        Message::Finish {
+            rnd_nr,
+            file_size: file.len() as u64,
+            digest: file_digest(file),
+        }
+    }
+
+    /// The random nr of the transmission the message belongs to
+    pub fn rnd_nr(&self) -> SessionId {
+        match self {
+            Message::Announcement { rnd_nr, .. } => *rnd_nr,
+            Message::Data { rnd_nr, .. } => *rnd_nr,
+            Message::Finish { rnd_nr, .. } => *rnd_nr,
         }
     }
 }
@@ -37,19 +119,133 @@ impl Message {
 pub enum DataResponse {
     Resend,
     Acknowledge { next_id: Id },
+    /** Windowed transmissions: all chunks before `next_id` arrived, bit k of `received` stands for `next_id + 1 + k` */
+    SelectiveAcknowledge { next_id: Id, received: u32 },
 }
 
+/// Maximum length of a string in a TXT record
+const MAX_TXT_STRING_LEN: usize = 255;
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum FinishResponse {
-    Resend,
-    Acknowledge { rnd_nr: u16 },
+    /** Parts of the file did not arrive, the client sends them with ids from `next_id` on and finishes again */
+    Resend { next_id: Id, missing: Vec<Range<u64>> },
+    Acknowledge { rnd_nr: SessionId },
+    /** Size or digest of the received file did not match, the transmission was discarded */
+    Failed { rnd_nr: SessionId },
+}
+
+impl FinishResponse {
+    ///
+    /// Resend response with the first gaps, as many as fit into the CNAME and into the TXT string.
+    ///
+    /// The others are listed once these were filled.
+    ///
+    pub fn resend(next_id: Id, mut missing: Vec<Range<u64>>) -> FinishResponse {
+        // labels f, r and the next id in wire format, plus the root
+        let mut name_len = 2 + 2 + 1 + next_id.to_string().len() + 1;
+        // "m=" and a separator after every range, one more than needed
+        let mut txt_len = 2;
+        let fitting = missing.iter().take_while(|range| {
+            let range_len = range.start.to_string().len() + range.end.to_string().len();
+            name_len += 1 + range_len + 1;
+            txt_len += range_len + 3;
+            name_len <= MAX_NAME_LEN && txt_len <= MAX_TXT_STRING_LEN
+        }).count();
+        missing.truncate(fitting);
+        FinishResponse::Resend { next_id, missing }
+    }
+}
+
+/// Limits of the server, sent with the announcement response
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct ServerParameters {
+    /** Protocol version of the server */
+    pub version: u8,
+    /** Data messages the server accepts in flight */
+    pub max_window_size: u32,
+    /** Largest file the server accepts */
+    pub max_file_size: u64,
+}
+
+/// Why the server rejected a message, the response code of the reply tells only roughly
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ErrorCode {
+    /** No transmission with the random nr of the message */
+    UnknownSession,
+    /** The transmission does not expect the id of the message */
+    UnknownId,
+    /** The random nr of an announcement belongs to another transmission */
+    SessionInUse,
+    /** The data would end beyond the largest file the server accepts */
+    InvalidOffset,
+    /** The server failed to store the data, a retry may succeed */
+    ServerFailure,
+    /** The query is no valid message */
+    InvalidMessage,
+    /** The server does not know the protocol version of the announcement */
+    UnsupportedVersion,
+    /** The message uses a codec or response type that was not negotiated */
+    NotNegotiated,
+    /** A code of a newer server */
+    Unknown(u8),
+}
+
+impl ErrorCode {
+    pub fn code(self) -> u8 {
+        match self {
+            ErrorCode::UnknownSession => 1,
+            ErrorCode::UnknownId => 2,
+            ErrorCode::SessionInUse => 3,
+            ErrorCode::InvalidOffset => 4,
+            ErrorCode::ServerFailure => 5,
+            ErrorCode::InvalidMessage => 6,
+            ErrorCode::UnsupportedVersion => 7,
+            ErrorCode::NotNegotiated => 8,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+
+    pub fn from_code(code: u8) -> ErrorCode {
+        match code {
+            1 => ErrorCode::UnknownSession,
+            2 => ErrorCode::UnknownId,
+            3 => ErrorCode::SessionInUse,
+            4 => ErrorCode::InvalidOffset,
+            5 => ErrorCode::ServerFailure,
+            6 => ErrorCode::InvalidMessage,
+            7 => ErrorCode::UnsupportedVersion,
+            8 => ErrorCode::NotNegotiated,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::UnknownSession => write!(f, "unknown session"),
+            ErrorCode::UnknownId => write!(f, "unexpected id"),
+            ErrorCode::SessionInUse => write!(f, "session belongs to another transmission"),
+            ErrorCode::InvalidOffset => write!(f, "invalid offset, the chunk would end beyond the maximum file size"),
+            ErrorCode::ServerFailure => write!(f, "server failure"),
+            ErrorCode::InvalidMessage => write!(f, "invalid message"),
+            ErrorCode::UnsupportedVersion => write!(f, "unsupported protocol version"),
+            ErrorCode::NotNegotiated => write!(f, "capability was not negotiated"),
+            ErrorCode::Unknown(code) => write!(f, "error {}", code),
+        }
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum MessageResponse {
     Announcement {
-        rnd_nr: u16,
+        rnd_nr: SessionId,
         next_id: Id,
+        /** Negotiated capabilities, servers of version 0 do not send them */
+        capabilities: Capabilities,
+        /** Limits of the server, servers of version 1 and older do not send them */
+        parameters: Option<ServerParameters>,
     },
     Data {
         response: DataResponse
@@ -57,8 +253,45 @@ pub enum MessageResponse {
     Finish {
         response: FinishResponse
     },
+    /** The message was rejected, sent along with an error response code */
+    Error {
+        code: ErrorCode
+    },
+}
+
+
+///
+/// Record type the client asks for, the server encodes its response accordingly.
+///
+/// `Address` responses are A records or CNAMEs and pass every resolver,
+/// `Txt` responses consist of `key=value` fields and can carry more information.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ResponseType {
+    Address,
+    Txt,
 }
 
+impl ResponseType {
+    pub fn record_type(self) -> RecordType {
+        match self {
+            ResponseType::Address => RecordType::A,
+            ResponseType::Txt => RecordType::TXT,
+        }
+    }
+}
+
+impl FromStr for ResponseType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "a" | "address" => Ok(ResponseType::Address),
+            "txt" => Ok(ResponseType::Txt),
+            _ => Err(format!("Unknown response type '{}', expected a or txt", s)),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum MessageResponseDecoderError {
@@ -68,42 +301,237 @@ pub enum MessageResponseDecoderError {
     InvalidName,
     TooFewLabels,
     InvalidNumber,
+    /** A TXT string was not of the form key=value */
+    InvalidTxtField,
+    /** A TXT response lacked a required field */
+    MissingTxtField { key: &'static str },
 }
 
+impl fmt::Display for MessageResponseDecoderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageResponseDecoderError::NoAnswers => write!(f, "dns message contains no answer with a response"),
+            MessageResponseDecoderError::UnsupportedDnsType => write!(f, "unsupported record type"),
+            MessageResponseDecoderError::InvalidIpv4 => write!(f, "invalid ipv4 address"),
+            MessageResponseDecoderError::InvalidName => write!(f, "invalid cname"),
+            MessageResponseDecoderError::TooFewLabels => write!(f, "cname has too few labels"),
+            MessageResponseDecoderError::InvalidNumber => write!(f, "invalid number"),
+            MessageResponseDecoderError::InvalidTxtField => write!(f, "txt string is no key=value field"),
+            MessageResponseDecoderError::MissingTxtField { key } => write!(f, "txt field '{}' is missing", key),
+        }
+    }
+}
 
+impl std::error::Error for MessageResponseDecoderError {}
+
+/// The `key=value` strings of a TXT response
+struct TxtFields {
+    fields: Vec<(String, String)>,
+}
+
+impl TxtFields {
+    fn new() -> TxtFields {
+        TxtFields { fields: Vec::new() }
+    }
+
+    fn with<V: ToString>(mut self, key: &str, value: V) -> TxtFields {
+        self.fields.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    fn encode(self) -> RData {
+        let strings = self.fields.into_iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        RData::TXT(TXT::new(strings))
+    }
+
+    fn parse(txt: &TXT) -> Result<TxtFields, MessageResponseDecoderError> {
+        let mut fields = Vec::new();
+        for string in txt.iter() {
+            let string = std::str::from_utf8(string).map_err(|_| MessageResponseDecoderError::InvalidTxtField)?;
+            let mut split = string.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(key), Some(value)) => fields.push((key.to_string(), value.to_string())),
+                _ => return Err(MessageResponseDecoderError::InvalidTxtField),
+            }
+        }
+        Ok(TxtFields { fields })
+    }
+
+    /// Value of the first field with this key, unknown keys are ignored for forward compatibility
+    fn get(&self, key: &'static str) -> Result<&str, MessageResponseDecoderError> {
+        self.fields.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .ok_or(MessageResponseDecoderError::MissingTxtField { key })
+    }
+
+    fn number<T: FromStr>(&self, key: &'static str) -> Result<T, MessageResponseDecoderError> {
+        self.get(key)?
+            .parse()
+            .map_err(|_| MessageResponseDecoderError::InvalidNumber)
+    }
+
+    /// Ranges as comma separated `start..end`, the end is exclusive
+    fn with_ranges(self, key: &str, ranges: &[Range<u64>]) -> TxtFields {
+        let value = ranges.iter()
+            .map(|range| format!("{}..{}", range.start, range.end))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.with(key, value)
+    }
+
+    fn ranges(&self, key: &'static str) -> Result<Vec<Range<u64>>, MessageResponseDecoderError> {
+        let value = self.get(key)?;
+        if value.is_empty() {
+            return Ok(Vec::new());
+        }
+        value.split(',')
+            .map(|range| parse_range(range, ".."))
+            .collect()
+    }
+}
+
+/// A non-empty range `<start><separator><end>`, the end is exclusive
+fn parse_range(range: &str, separator: &str) -> Result<Range<u64>, MessageResponseDecoderError> {
+    let mut split = range.splitn(2, separator);
+    let mut bound = || split.next()
+        .and_then(|bound| bound.parse().ok())
+        .ok_or(MessageResponseDecoderError::InvalidNumber);
+    let (start, end) = (bound()?, bound()?);
+    if start >= end {
+        return Err(MessageResponseDecoderError::InvalidNumber);
+    }
+    Ok(start..end)
+}
+
+/// Chunks after `next_id` that arrived, as ranges of bit positions in the bitmap of a selective acknowledgement
+fn bitmap_ranges(received: u32) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for k in (0..MAX_WINDOW_SIZE as u64).filter(|k| received & 1 << k != 0) {
+        match ranges.last_mut() {
+            Some(range) if range.end == k => range.end = k + 1,
+            _ => ranges.push(k..k + 1),
+        }
+    }
+    ranges
+}
+
+fn ranges_bitmap(ranges: &[Range<u64>]) -> Result<u32, MessageResponseDecoderError> {
+    ranges.iter().try_fold(0_u32, |received, range| {
+        if range.end > MAX_WINDOW_SIZE as u64 {
+            return Err(MessageResponseDecoderError::InvalidNumber);
+        }
+        Ok(range.clone().fold(received, |received, k| received | 1 << k))
+    })
+}
 
 impl MessageResponse {
 
-    pub fn encode(self) -> RData {
-        match self {
-            MessageResponse::Announcement { rnd_nr, next_id } => {
-                let name = Name::from_str(format!("a.{}.{}", rnd_nr, next_id).as_str()).unwrap();
-                RData::CNAME(name)
+    /// Encode as record of the type that was queried, TXT queries get a TXT record
+    pub fn encode_for(self, query_type: RecordType) -> Result<RData, MessageEncoderError> {
+        match query_type {
+            RecordType::TXT => Ok(self.encode_txt()),
+            _ => self.encode(),
+        }
+    }
+
+    ///
+    /// Encode as TXT record of `key=value` fields, `t` tells the type of the response.
+    ///
+    /// Selective acknowledgements list the chunks that arrived after `n` as ranges `k`, counted from `n + 1`.
+    /// Announcement responses carry the limits of the server, version `v`, window size `w` and file size `m`.
+    ///
+    pub fn encode_txt(self) -> RData {
+        let fields = match self {
+            MessageResponse::Announcement { rnd_nr, next_id, capabilities, parameters } => {
+                let fields = TxtFields::new().with("t", "a").with("r", rnd_nr).with("n", next_id).with("c", capabilities.0);
+                match parameters {
+                    Some(parameters) => fields
+                        .with("v", parameters.version)
+                        .with("w", parameters.max_window_size)
+                        .with("m", parameters.max_file_size),
+                    None => fields,
+                }
+            },
+            MessageResponse::Data { response } => {
+                let fields = TxtFields::new().with("t", "d");
+                match response {
+                    DataResponse::Resend => fields.with("s", "r"),
+                    DataResponse::Acknowledge { next_id } => fields.with("s", "a").with("n", next_id),
+                    DataResponse::SelectiveAcknowledge { next_id, received } => {
+                        fields.with("s", "w").with("n", next_id).with_ranges("k", &bitmap_ranges(received))
+                    },
+                }
+            },
+            MessageResponse::Finish { response } => {
+                let fields = TxtFields::new().with("t", "f");
+                match response {
+                    FinishResponse::Resend { next_id, missing } => fields.with("s", "r").with("n", next_id).with_ranges("m", &missing),
+                    FinishResponse::Acknowledge { rnd_nr } => fields.with("s", "a").with("r", rnd_nr),
+                    FinishResponse::Failed { rnd_nr } => fields.with("s", "x").with("r", rnd_nr),
+                }
+            },
+            MessageResponse::Error { code } => TxtFields::new().with("t", "e").with("e", code.code()),
+        };
+        fields.encode()
+    }
+
+    pub fn encode(self) -> Result<RData, MessageEncoderError> {
+        let r_data = match self {
+            MessageResponse::Announcement { rnd_nr, next_id, capabilities, parameters } => {
+                let name = match parameters {
+                    // older clients ignore the labels after the capabilities
+                    Some(p) => format!("a.{}.{}.{}.{}.{}.{}", rnd_nr, next_id, capabilities.0, p.version, p.max_window_size, p.max_file_size),
+                    None => format!("a.{}.{}.{}", rnd_nr, next_id, capabilities.0),
+                };
+                RData::CNAME(Name::from_str(name.as_str())?)
             },
             MessageResponse::Data { response } => {
-                RData::A(match response {
+                match response {
                     DataResponse::Resend => {
-                        Ipv4Addr::new(1, 1, 1, 1)
+                        RData::A(Ipv4Addr::new(1, 1, 1, 1))
                     },
                     DataResponse::Acknowledge { next_id } => {
-                        let next_id_bytes = next_id.to_le_bytes();
-                        Ipv4Addr::new(2, 2, next_id_bytes[0], next_id_bytes[1])
+                        match u16::try_from(next_id) {
+                            Ok(next_id) => {
+                                let next_id_bytes = next_id.to_le_bytes();
+                                RData::A(Ipv4Addr::new(2, 2, next_id_bytes[0], next_id_bytes[1]))
+                            },
+                            // only transmissions of more than 65533 chunks get here
+                            Err(_) => RData::CNAME(Name::from_str(format!("d.{}", next_id).as_str())?),
+                        }
+                    },
+                    DataResponse::SelectiveAcknowledge { next_id, received } => {
+                        // the bitmap does not fit into an address
+                        let name = Name::from_str(format!("d.{}.{}", next_id, received).as_str())?;
+                        RData::CNAME(name)
                     },
-                })
+                }
             },
             MessageResponse::Finish { response } => {
                 match response {
-                    FinishResponse::Resend => {
-                        let name = Name::from_str("f.r").unwrap();
-                        RData::CNAME(name)
+                    FinishResponse::Resend { next_id, missing } => {
+                        let mut name = format!("f.r.{}", next_id);
+                        for range in missing {
+                            name.push_str(&format!(".{}-{}", range.start, range.end));
+                        }
+                        RData::CNAME(Name::from_str(name.as_str())?)
                     },
                     FinishResponse::Acknowledge { rnd_nr } => {
-                        let name = Name::from_str(format!("f.a.{}", rnd_nr).as_str()).unwrap();
+                        let name = Name::from_str(format!("f.a.{}", rnd_nr).as_str())?;
+                        RData::CNAME(name)
+                    },
+                    FinishResponse::Failed { rnd_nr } => {
+                        let name = Name::from_str(format!("f.x.{}", rnd_nr).as_str())?;
                         RData::CNAME(name)
                     },
                 }
             },
-        }
+            MessageResponse::Error { code } => RData::CNAME(Name::from_str(format!("e.{}", code.code()).as_str())?),
+        };
+        Ok(r_data)
     }
 
     fn parse_ip(ip: &Ipv4Addr) -> Result<MessageResponse, MessageResponseDecoderError> {
@@ -111,7 +539,7 @@ impl MessageResponse {
         if bytes[0] == 1 && bytes[1] == 1 {
             Ok(MessageResponse::Data { response: DataResponse::Resend })
         } else if bytes[0] == 2 && bytes[1] == 2 {
-            let next_id = u16::from_le_bytes([bytes[2], bytes[3]]);
+            let next_id = u16::from_le_bytes([bytes[2], bytes[3]]) as Id;
             Ok(MessageResponse::Data { response: DataResponse::Acknowledge { next_id } })
         } else {
             Err(MessageResponseDecoderError::InvalidIpv4)
@@ -119,47 +547,146 @@ impl MessageResponse {
     }
 
     fn parse_cname(cname: &Name) -> Result<MessageResponse, MessageResponseDecoderError> {
-        if cname.len() < 2 {
+        if cname.num_labels() < 2 {
             return Err(MessageResponseDecoderError::TooFewLabels)
         }
         let message_type = &cname[0].to_ascii();
         match message_type.as_str() {
             "a" => {
-                if cname.len() < 3 {
+                if cname.num_labels() < 3 {
                     return Err(MessageResponseDecoderError::TooFewLabels);
                 }
                 let rnd_nr = cname[1].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?;
                 let next_id = cname[2].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?;
+                let capabilities = if cname.num_labels() > 3 {
+                    Capabilities(cname[3].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?)
+                } else {
+                    Capabilities::V0
+                };
+                let parameters = if cname.num_labels() > 6 {
+                    Some(ServerParameters {
+                        version: cname[4].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?,
+                        max_window_size: cname[5].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?,
+                        max_file_size: cname[6].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?,
+                    })
+                } else {
+                    None
+                };
 
-                Ok(MessageResponse::Announcement { rnd_nr, next_id })
+                Ok(MessageResponse::Announcement { rnd_nr, next_id, capabilities, parameters })
+            },
+            "d" => {
+                let next_id = cname[1].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?;
+                if cname.num_labels() < 3 {
+                    // acknowledgements with ids that do not fit into an address
+                    return Ok(MessageResponse::Data { response: DataResponse::Acknowledge { next_id } });
+                }
+                let received = cname[2].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?;
+                Ok(MessageResponse::Data { response: DataResponse::SelectiveAcknowledge { next_id, received } })
             },
             "f" => {
                 let finish_type = cname[1].to_ascii();
                 match finish_type.as_str() {
-                    "r" => Ok(MessageResponse::Finish { response: FinishResponse::Resend }),
+                    "r" => {
+                        if cname.num_labels() < 3 {
+                            return Err(MessageResponseDecoderError::TooFewLabels)
+                        }
+                        let next_id = cname[2].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?;
+                        let missing = (3..cname.num_labels() as usize)
+                            .map(|i| parse_range(&cname[i].to_ascii(), "-"))
+                            .collect::<Result<_, _>>()?;
+                        Ok(MessageResponse::Finish { response: FinishResponse::Resend { next_id, missing } })
+                    },
                     "a" => {
-                        if cname.len() < 3 {
+                        if cname.num_labels() < 3 {
                             return Err(MessageResponseDecoderError::TooFewLabels)
                         }
                         let rnd_nr = cname[2].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?;
                         Ok(MessageResponse::Finish { response: FinishResponse::Acknowledge { rnd_nr } })
                     },
+                    "x" => {
+                        if cname.num_labels() < 3 {
+                            return Err(MessageResponseDecoderError::TooFewLabels)
+                        }
+                        let rnd_nr = cname[2].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?;
+                        Ok(MessageResponse::Finish { response: FinishResponse::Failed { rnd_nr } })
+                    },
                     _ => Err(MessageResponseDecoderError::InvalidName)
                 }
             },
+            "e" => {
+                let code = cname[1].to_ascii().parse().map_err(|_| MessageResponseDecoderError::InvalidNumber)?;
+                Ok(MessageResponse::Error { code: ErrorCode::from_code(code) })
+            },
             _ => Err(MessageResponseDecoderError::InvalidName)
         }
     }
 
-    pub fn decode(message: &trust_dns_proto::op::Message) -> Result<MessageResponse, MessageResponseDecoderError> {
-        if message.answers().is_empty() {
-            return Err(MessageResponseDecoderError::NoAnswers)
+    fn parse_txt(txt: &TXT) -> Result<MessageResponse, MessageResponseDecoderError> {
+        let fields = TxtFields::parse(txt)?;
+        match fields.get("t")? {
+            "a" => Ok(MessageResponse::Announcement {
+                rnd_nr: fields.number("r")?,
+                next_id: fields.number("n")?,
+                capabilities: match fields.get("c") {
+                    Ok(_) => Capabilities(fields.number("c")?),
+                    Err(_) => Capabilities::V0,
+                },
+                parameters: match fields.get("v") {
+                    Ok(_) => Some(ServerParameters {
+                        version: fields.number("v")?,
+                        max_window_size: fields.number("w")?,
+                        max_file_size: fields.number("m")?,
+                    }),
+                    Err(_) => None,
+                },
+            }),
+            "d" => {
+                let response = match fields.get("s")? {
+                    "r" => DataResponse::Resend,
+                    "a" => DataResponse::Acknowledge { next_id: fields.number("n")? },
+                    "w" => DataResponse::SelectiveAcknowledge { next_id: fields.number("n")?, received: ranges_bitmap(&fields.ranges("k")?)? },
+                    _ => return Err(MessageResponseDecoderError::InvalidTxtField),
+                };
+                Ok(MessageResponse::Data { response })
+            },
+            "f" => {
+                let response = match fields.get("s")? {
+                    "r" => FinishResponse::Resend { next_id: fields.number("n")?, missing: fields.ranges("m")? },
+                    "a" => FinishResponse::Acknowledge { rnd_nr: fields.number("r")? },
+                    "x" => FinishResponse::Failed { rnd_nr: fields.number("r")? },
+                    _ => return Err(MessageResponseDecoderError::InvalidTxtField),
+                };
+                Ok(MessageResponse::Finish { response })
+            },
+            "e" => Ok(MessageResponse::Error { code: ErrorCode::from_code(fields.number("e")?) }),
+            _ => Err(MessageResponseDecoderError::InvalidTxtField)
         }
-        let record = &message.answers()[0];
-        match record.rdata() {
+    }
+
+    fn parse_record(r_data: &RData) -> Result<MessageResponse, MessageResponseDecoderError> {
+        match r_data {
             RData::A(ip) => Self::parse_ip(ip),
             RData::CNAME(cname) => Self::parse_cname(cname),
+            RData::TXT(txt) => Self::parse_txt(txt),
             _ => Err(MessageResponseDecoderError::UnsupportedDnsType)
         }
     }
+
+    ///
+    /// Decode the first answer that contains a response.
+    ///
+    /// Resolvers may add records of their own (e.g. while following a CNAME),
+    /// so answers that can not be decoded are skipped.
+    ///
+    pub fn decode(message: &trust_dns_proto::op::Message) -> Result<MessageResponse, MessageResponseDecoderError> {
+        let mut result = Err(MessageResponseDecoderError::NoAnswers);
+        for record in message.answers() {
+            result = Self::parse_record(record.rdata());
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
 }
\ No newline at end of file
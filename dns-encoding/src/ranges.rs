@@ -0,0 +1,121 @@
+use std::ops::Range;
+
+///
+/// Byte ranges of a file that were received, kept sorted and merged.
+///
+/// Chunks may arrive in any order, the gaps tell which parts of the file are still missing.
+///
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct RangeSet {
+    ranges: Vec<Range<u64>>,
+}
+
+impl RangeSet {
+    pub fn new() -> RangeSet {
+        RangeSet { ranges: Vec::new() }
+    }
+
+    pub fn insert(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        // first range that ends at or after the start of the new one, it may be merged
+        let first = self.ranges.partition_point(|r| r.end < range.start);
+        // first range that starts after the end of the new one, it stays separate
+        let last = self.ranges.partition_point(|r| r.start <= range.end);
+        let merged = if first < last {
+            self.ranges[first].start.min(range.start)..self.ranges[last - 1].end.max(range.end)
+        } else {
+            range
+        };
+        self.ranges.splice(first..last, std::iter::once(merged));
+    }
+
+    pub fn contains(&self, range: &Range<u64>) -> bool {
+        range.is_empty() || self.ranges.iter().any(|r| r.start <= range.start && range.end <= r.end)
+    }
+
+    /// Bytes from the start of the file that were received without a gap
+    pub fn contiguous_len(&self) -> u64 {
+        match self.ranges.first() {
+            Some(r) if r.start == 0 => r.end,
+            _ => 0,
+        }
+    }
+
+    /// Parts of a file of `len` bytes that were not received
+    pub fn gaps(&self, len: u64) -> Vec<Range<u64>> {
+        let mut gaps = Vec::new();
+        let mut position = 0;
+        for r in &self.ranges {
+            if r.start >= len {
+                break;
+            }
+            if r.start > position {
+                gaps.push(position..r.start);
+            }
+            position = position.max(r.end);
+        }
+        if position < len {
+            gaps.push(position..len);
+        }
+        gaps
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Range<u64>> {
+        self.ranges.iter()
+    }
+}
+
+#[cfg(test)]
+mod range_set_tests {
+    use super::*;
+
+    #[test]
+    fn test_insert() {
+        let mut ranges = RangeSet::new();
+        ranges.insert(10..20);
+        ranges.insert(30..40);
+        ranges.insert(0..0);
+        assert_eq!(vec![10..20, 30..40], ranges.iter().cloned().collect::<Vec<_>>());
+
+        // adjacent ranges are merged
+        ranges.insert(20..25);
+        assert_eq!(vec![10..25, 30..40], ranges.iter().cloned().collect::<Vec<_>>());
+
+        // overlapping several ranges
+        ranges.insert(5..35);
+        assert_eq!(vec![5..40], ranges.iter().cloned().collect::<Vec<_>>());
+
+        // duplicates change nothing
+        ranges.insert(10..20);
+        assert_eq!(vec![5..40], ranges.iter().cloned().collect::<Vec<_>>());
+
+        ranges.insert(0..3);
+        assert_eq!(vec![0..3, 5..40], ranges.iter().cloned().collect::<Vec<_>>());
+        assert!(ranges.contains(&(6..40)));
+        assert!(!ranges.contains(&(2..6)));
+    }
+
+    #[test]
+    fn test_gaps() {
+        let mut ranges = RangeSet::new();
+        assert_eq!(vec![0..10], ranges.gaps(10));
+        assert!(ranges.gaps(0).is_empty());
+
+        ranges.insert(2..4);
+        ranges.insert(6..8);
+        assert_eq!(0, ranges.contiguous_len());
+        assert_eq!(vec![0..2, 4..6, 8..10], ranges.gaps(10));
+        assert_eq!(vec![0..2, 4..5], ranges.gaps(5));
+
+        ranges.insert(0..2);
+        ranges.insert(8..10);
+        assert_eq!(4, ranges.contiguous_len());
+        assert_eq!(vec![4..6], ranges.gaps(10));
+
+        ranges.insert(4..6);
+        assert_eq!(10, ranges.contiguous_len());
+        assert!(ranges.gaps(10).is_empty());
+    }
+}
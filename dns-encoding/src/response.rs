@@ -0,0 +1,229 @@
+use std::mem;
+
+use trust_dns_proto::op::{Edns, Header, Message as DnsMessage, MessageType, ResponseCode};
+use trust_dns_proto::rr::Record;
+
+use crate::decode::MessageDecoderError;
+use crate::error::Error;
+use crate::message::ErrorCode;
+use crate::server::ServerError;
+
+///
+/// Builds the reply to a query.
+///
+/// The reply takes over id, opcode, RD bit and question of the query, and is marked as authoritative,
+/// since the server is the name server of the sub domain. Recursion is never available.
+///
+pub struct ResponseBuilder {
+    response: DnsMessage,
+    /** Extended response codes, e.g. BADVERS, are split between header and OPT record */
+    response_code: ResponseCode,
+}
+
+impl ResponseBuilder {
+    pub fn new(request: &DnsMessage) -> ResponseBuilder {
+        let mut builder = ResponseBuilder::for_header(request.header());
+        builder.response.add_queries(request.queries().to_vec());
+        builder
+    }
+
+    /// Reply to a query of which only the header could be read, e.g. to answer it with FORMERR
+    pub fn for_header(header: &Header) -> ResponseBuilder {
+        let mut response = DnsMessage::new();
+        response.set_id(header.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(header.op_code())
+            .set_authoritative(true)
+            .set_recursion_desired(header.recursion_desired())
+            .set_recursion_available(false)
+            .set_checking_disabled(header.checking_disabled())
+            .set_response_code(ResponseCode::NoError);
+        ResponseBuilder { response, response_code: ResponseCode::NoError }
+    }
+
+    pub fn set_response_code(&mut self, response_code: ResponseCode) -> &mut Self {
+        self.response.set_response_code(response_code);
+        self.response_code = response_code;
+        self
+    }
+
+    /// OPT record of the response, it tells the resolver how large a response the server sends over UDP
+    pub fn set_edns(&mut self, edns: Edns) -> &mut Self {
+        self.response.set_edns(edns);
+        self
+    }
+
+    pub fn add_answer(&mut self, record: Record) -> &mut Self {
+        self.response.add_answer(record);
+        self
+    }
+
+    pub fn add_answers(&mut self, records: Vec<Record>) -> &mut Self {
+        self.response.add_answers(records);
+        self
+    }
+
+    /// Records of the authority section, e.g. the SOA record of a negative answer
+    pub fn add_authority(&mut self, records: Vec<Record>) -> &mut Self {
+        self.response.add_name_servers(records);
+        self
+    }
+
+    pub fn add_additionals(&mut self, records: Vec<Record>) -> &mut Self {
+        for record in records {
+            self.response.add_additional(record);
+        }
+        self
+    }
+
+    pub fn build(&mut self) -> DnsMessage {
+        let mut response = mem::replace(&mut self.response, DnsMessage::new());
+        if response.edns().is_some() {
+            response.edns_mut().set_rcode_high(self.response_code.high() as u8);
+        }
+        response
+    }
+}
+
+///
+/// The response code that tells the resolver why a query could not be answered.
+///
+/// * NOERROR: the name is no message, but names of messages may be below it, see `MessageDecoderError::is_intermediate_name`.
+///   The response has no answers (NODATA), NXDOMAIN would tell the resolver that nothing exists below (RFC 8020)
+/// * REFUSED: the query is not for the sub domain, or the server does not accept it, e.g. a newer protocol version
+/// * NXDOMAIN: the name is no valid message, or belongs to no transmission.
+///   Only names that start with the magic number get here, the SOA of the answer has a TTL of 0,
+///   so resolvers do not cache it and do not deny the names below (RFC 8020)
+/// * SERVFAIL: the server failed, e.g. to store the data, a retry may succeed
+/// * FORMERR: the query contains no question
+///
+/// Returns `None` for messages that must not be answered at all, i.e. responses.
+///
+pub fn response_code(error: &Error) -> Option<ResponseCode> {
+    match error {
+        Error::Decode(e) => match e {
+            MessageDecoderError::NotAQuery => None,
+            e if e.is_intermediate_name() => Some(ResponseCode::NoError),
+            MessageDecoderError::NoQueries => Some(ResponseCode::FormErr),
+            MessageDecoderError::WrongSubdomain | MessageDecoderError::UnsupportedVersion { .. } => Some(ResponseCode::Refused),
+            _ => Some(ResponseCode::NXDomain),
+        },
+        Error::Server(e) => match e {
+            ServerError::UnknownId { .. } | ServerError::UnknownRndNr { .. } => Some(ResponseCode::NXDomain),
+            ServerError::SessionInUse { .. } | ServerError::InvalidOffset { .. } | ServerError::NotNegotiated { .. } => {
+                Some(ResponseCode::Refused)
+            }
+            ServerError::Storage(_) => Some(ResponseCode::ServFail),
+        },
+        _ => Some(ResponseCode::ServFail),
+    }
+}
+
+///
+/// The error code that tells the client exactly why its message was rejected.
+///
+/// Only errors about messages have one, names that are no message are none of the client's business.
+///
+pub fn error_code(error: &Error) -> Option<ErrorCode> {
+    match error {
+        Error::Decode(e) => match e {
+            MessageDecoderError::NotAQuery | MessageDecoderError::NoQueries => None,
+            e if e.is_foreign_name() => None,
+            MessageDecoderError::UnsupportedVersion { .. } => Some(ErrorCode::UnsupportedVersion),
+            _ => Some(ErrorCode::InvalidMessage),
+        },
+        Error::Server(e) => Some(match e {
+            ServerError::UnknownRndNr { .. } => ErrorCode::UnknownSession,
+            ServerError::UnknownId { .. } => ErrorCode::UnknownId,
+            ServerError::SessionInUse { .. } => ErrorCode::SessionInUse,
+            ServerError::InvalidOffset { .. } => ErrorCode::InvalidOffset,
+            ServerError::NotNegotiated { .. } => ErrorCode::NotNegotiated,
+            ServerError::Storage(_) => ErrorCode::ServerFailure,
+        }),
+        _ => Some(ErrorCode::ServerFailure),
+    }
+}
+
+#[cfg(test)]
+mod response_tests {
+    use trust_dns_proto::op::{OpCode, Query};
+    use trust_dns_proto::rr::{Name, RData, RecordType};
+    use trust_dns_proto::serialize::binary::{BinDecodable, BinEncodable};
+
+    use super::*;
+
+    fn request() -> DnsMessage {
+        let mut request = DnsMessage::new();
+        request.set_id(4711)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_ascii("8k1.a-1-2.extract.de.").unwrap(), RecordType::A));
+        request
+    }
+
+    #[test]
+    fn test_response() {
+        let request = request();
+        let record = Record::from_rdata(request.queries()[0].name().clone(), 120, RData::A([2, 2, 0, 3].into()));
+        let response = ResponseBuilder::new(&request).add_answer(record.clone()).build();
+        assert_eq!(4711, response.id());
+        assert_eq!(MessageType::Response, response.message_type());
+        assert_eq!(OpCode::Query, response.op_code());
+        assert!(response.authoritative());
+        assert!(response.recursion_desired());
+        assert!(!response.recursion_available());
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert_eq!(request.queries(), response.queries());
+        assert_eq!(&[record], response.answers());
+    }
+
+    #[test]
+    fn test_error_response() {
+        let response = ResponseBuilder::new(&request()).set_response_code(ResponseCode::Refused).build();
+        assert_eq!(ResponseCode::Refused, response.response_code());
+        assert!(response.answers().is_empty());
+
+        let response = ResponseBuilder::for_header(request().header()).set_response_code(ResponseCode::FormErr).build();
+        assert_eq!(4711, response.id());
+        assert!(response.queries().is_empty());
+    }
+
+    #[test]
+    fn test_extended_response_code() {
+        let response = ResponseBuilder::new(&request())
+            .set_edns(crate::edns::opt(1232))
+            .set_response_code(ResponseCode::BADVERS)
+            .build();
+        let response = DnsMessage::from_bytes(&response.to_bytes().unwrap()).unwrap();
+        // trust-dns reads code 16 as BADSIG, which shares it with BADVERS
+        assert_eq!(u16::from(ResponseCode::BADVERS), u16::from(response.response_code()));
+        assert_eq!(1232, response.edns().unwrap().max_payload());
+    }
+
+    #[test]
+    fn test_response_codes() {
+        assert_eq!(None, response_code(&MessageDecoderError::NotAQuery.into()));
+        assert_eq!(Some(ResponseCode::FormErr), response_code(&MessageDecoderError::NoQueries.into()));
+        assert_eq!(Some(ResponseCode::Refused), response_code(&MessageDecoderError::WrongSubdomain.into()));
+        assert_eq!(Some(ResponseCode::NXDomain), response_code(&MessageDecoderError::InvalidHeader.into()));
+        assert_eq!(Some(ResponseCode::NoError), response_code(&MessageDecoderError::TooFewLabels.into()));
+        assert_eq!(Some(ResponseCode::NoError), response_code(&MessageDecoderError::NoMagicNr.into()));
+        assert_eq!(Some(ResponseCode::NXDomain), response_code(&ServerError::UnknownRndNr { rnd_nr: 1 }.into()));
+        assert_eq!(Some(ResponseCode::Refused), response_code(&ServerError::SessionInUse { rnd_nr: 1 }.into()));
+        let storage_error = ServerError::Storage(std::io::Error::from(std::io::ErrorKind::Other));
+        assert_eq!(Some(ResponseCode::ServFail), response_code(&storage_error.into()));
+    }
+
+    #[test]
+    fn test_error_codes() {
+        assert_eq!(None, error_code(&MessageDecoderError::WrongSubdomain.into()));
+        assert_eq!(None, error_code(&MessageDecoderError::NoMagicNr.into()));
+        assert_eq!(Some(ErrorCode::InvalidMessage), error_code(&MessageDecoderError::InvalidHeader.into()));
+        assert_eq!(Some(ErrorCode::UnknownSession), error_code(&ServerError::UnknownRndNr { rnd_nr: 1 }.into()));
+        assert_eq!(Some(ErrorCode::UnknownId), error_code(&ServerError::UnknownId { id: 2 }.into()));
+        assert_eq!(Some(ErrorCode::SessionInUse), error_code(&ServerError::SessionInUse { rnd_nr: 1 }.into()));
+        assert_eq!(Some(ErrorCode::UnsupportedVersion), error_code(&MessageDecoderError::UnsupportedVersion { version: 3 }.into()));
+        let invalid_offset = error_code(&ServerError::InvalidOffset { offset: 1 << 30 }.into()).unwrap();
+        assert_eq!(4, invalid_offset.code());
+        assert!(invalid_offset.to_string().starts_with("invalid offset"));
+    }
+}
@@ -1,69 +1,437 @@
-use crate::message::{DataResponse, FinishResponse, Id, Message, MessageResponse};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::capabilities::{Capabilities, PROTOCOL_VERSION};
+use crate::message::{advance_id, id_distance, next_id, DataResponse, Digest, FinishResponse, Id, Message, MessageResponse, ServerParameters, SessionId, MAX_WINDOW_SIZE};
+use crate::ranges::RangeSet;
+use crate::sink::{DataSink, PrefixDigest, SpoolFile};
+
+/// Largest file a client may send, larger offsets are rejected
+pub const MAX_FILE_SIZE: u64 = 1 << 30;
+
+/// Largest file a client may send by default if the data is kept in memory, a chunk far into the file allocates all before it
+pub const DEFAULT_MAX_MEMORY_FILE_SIZE: u64 = 16 << 20;
+
+pub use crate::message::FIRST_ID;
+
+/// Transmissions without a message for this long are evicted
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
 
 #[derive(Debug)]
 pub struct ServerState {
     states: Vec<TransmissionState>,
+    /** Transmissions whose size and digest were verified */
     pub finished_states: Vec<TransmissionState>,
-    id_generator: IdGenerator,
+    /** Transmissions that were finished, but did not match size or digest */
+    pub failed_states: Vec<TransmissionState>,
+    /** Transmissions that were evicted because the client stopped sending */
+    pub expired_states: Vec<TransmissionState>,
+    idle_timeout: Duration,
+    /** Capabilities the server offers to clients */
+    capabilities: Capabilities,
+    /** Responses to the latest finish messages, replayed if a client retries its finish message */
+    completed: VecDeque<(SessionId, FinishResponse)>,
+    /** Directory for the data of active transmissions, they are kept in memory if there is none */
+    spool_directory: Option<PathBuf>,
+    /** Largest file a client may send, depends on the spool directory if not set */
+    max_file_size: Option<u64>,
+}
+
+/// Finished transmissions whose response is kept for retried finish messages
+const COMPLETED_CACHE_SIZE: usize = 256;
+
+impl Default for ServerState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Debug)]
 pub enum ServerError {
     UnknownId { id: Id },
-    UnknownRndNr { rnd_nr: u16 },
+    UnknownRndNr { rnd_nr: SessionId },
+    /** An announcement used the token of another transmission */
+    SessionInUse { rnd_nr: SessionId },
+    /** A chunk would end beyond the maximum file size */
+    InvalidOffset { offset: u64 },
+    /** The data of a transmission could not be written or read */
+    Storage(io::Error),
+    /** The message uses capabilities its transmission did not negotiate, e.g. another codec */
+    NotNegotiated { capabilities: Capabilities },
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::UnknownId { id } => write!(f, "no transmission expects id {}", id),
+            ServerError::UnknownRndNr { rnd_nr } => write!(f, "no transmission with random nr {}", rnd_nr),
+            ServerError::SessionInUse { rnd_nr } => write!(f, "random nr {} belongs to another transmission", rnd_nr),
+            ServerError::InvalidOffset { offset } => write!(f, "chunk at offset {} exceeds the maximum file size", offset),
+            ServerError::Storage(e) => write!(f, "failed to store data: {}", e),
+            ServerError::NotNegotiated { capabilities } => write!(f, "capabilities {:#x} were not negotiated", capabilities.0),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+///
+/// A chunk that passed the checks of its transmission, see `ServerState::accept`.
+///
+/// It holds the data of its transmission, so it can be written after the server state was released.
+///
+#[derive(Debug)]
+pub struct PendingChunk {
+    rnd_nr: SessionId,
+    id: Id,
+    /** Clients of version 1 send no offset, their chunks are appended */
+    offset: Option<u64>,
+    data: Vec<u8>,
+    /** Chunks must end before this offset */
+    max_file_size: u64,
+    transmission: Arc<Mutex<TransmissionData>>,
+}
+
+impl PendingChunk {
+    /// Write the chunk and hash the data it completes, only the lock of its transmission is held meanwhile
+    pub fn write(&self) -> Result<(), ServerError> {
+        self.transmission.lock()
+            .expect("Transmission data lock poisoned")
+            .write(self.offset, &self.data, self.max_file_size)
+    }
+}
+
+/// What `ServerState::accept` made of a message
+#[derive(Debug)]
+pub enum Accepted {
+    /** The message was handled, e.g. an announcement or a retried chunk */
+    Response(MessageResponse),
+    /** A chunk to write, `ServerState::complete` acknowledges it afterwards */
+    Chunk(PendingChunk),
 }
 
 impl ServerState {
     pub fn new() -> ServerState {
+        ServerState::with_capabilities(Capabilities::supported())
+    }
+
+    /// Capabilities this implementation does not support, e.g. compression, are never offered
+    pub fn with_capabilities(capabilities: Capabilities) -> ServerState {
+        let capabilities = capabilities.negotiate(Capabilities::supported());
         ServerState {
             states: Vec::new(),
             finished_states: Vec::new(),
-            id_generator: IdGenerator::new(),
+            failed_states: Vec::new(),
+            expired_states: Vec::new(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            capabilities,
+            completed: VecDeque::new(),
+            spool_directory: None,
+            max_file_size: None,
         }
     }
 
+    ///
+    /// Handle a message of a client and return the response.
+    ///
+    /// Resolvers retry queries and clients resend messages whose response got lost,
+    /// so repeated announcements, data and finish messages are answered with the response
+    /// they got the first time, without changing the transmission again.
+    ///
+    /// Chunks are written right away, `accept` leaves that to the caller.
+    ///
     pub fn handle_message(&mut self, message: Message) -> Result<MessageResponse, ServerError> {
+        let accepted = self.accept(message)?;
+        self.handle_accepted(accepted)
+    }
+
+    ///
+    /// Handle a message whose query uses these capabilities, see `MessageDecoder::decode_with_capabilities`.
+    ///
+    /// Data and finish messages are rejected, unless their transmission negotiated all of them.
+    ///
+    pub fn handle_message_using(&mut self, message: Message, capabilities: Capabilities) -> Result<MessageResponse, ServerError> {
+        let accepted = self.accept_using(message, capabilities)?;
+        self.handle_accepted(accepted)
+    }
+
+    ///
+    /// Handle a message, except for writing its chunk.
+    ///
+    /// A chunk that passed the checks of its transmission is returned instead of the response,
+    /// the caller writes it with `PendingChunk::write` and gets the response from `complete`.
+    /// Meanwhile the server state is free for other queries, writing and hashing only holds the lock of the transmission.
+    ///
+    pub fn accept(&mut self, message: Message) -> Result<Accepted, ServerError> {
+        self.touch(message.rnd_nr());
+        if let Some(response) = self.replay(&message) {
+            return Ok(Accepted::Response(response));
+        }
         match message {
-            Message::Announcement { host, file_name, rnd_nr } => {
-                let next_id = self.id_generator.next_id();
-                let state = TransmissionState::new(
-                    rnd_nr, host, file_name, next_id);
-                self.states.push(state);
-                Ok(MessageResponse::Announcement { rnd_nr, next_id })
-            }
-            Message::Data { id, mut data } => {
-                let mut state = ServerState::find_state(&mut self.states, id)?;
-                state.data.append(&mut data);
-
-                let next_id = self.id_generator.next_id();
-                state.expected_id = next_id;
-                Ok(MessageResponse::Data {
-                    response: DataResponse::Acknowledge {
-                        next_id
-                    }
-                })
+            Message::Announcement { host, file_name, rnd_nr, version, capabilities } => {
+                self.announce(host, file_name, rnd_nr, version, capabilities).map(Accepted::Response)
+            }
+            Message::Data { rnd_nr, id, offset, data, checksum } if self.is_windowed(rnd_nr) => {
+                let state = ServerState::find_windowed_state(&mut self.states, rnd_nr)?;
+                // a corrupted chunk shows up as a gap in the acknowledgement, duplicates and chunks beyond the window are dropped
+                if crc32fast::hash(&data) != checksum || !state.expects_chunk(id) {
+                    return Ok(Accepted::Response(MessageResponse::Data { response: state.selective_acknowledge() }));
+                }
+                Ok(Accepted::Chunk(state.pending_chunk(id, offset, data)))
+            }
+            Message::Data { rnd_nr, id, offset, data, checksum } => {
+                let state = ServerState::find_state(&mut self.states, rnd_nr, id)?;
+                if crc32fast::hash(&data) != checksum {
+                    // keep the expected id, the client has to send this chunk again
+                    return Ok(Accepted::Response(MessageResponse::Data { response: DataResponse::Resend }));
+                }
+                Ok(Accepted::Chunk(state.pending_chunk(id, offset, data)))
+            }
+            Message::Finish { rnd_nr, file_size, digest } => self.finish(rnd_nr, file_size, &digest).map(Accepted::Response),
+        }
+    }
+
+    /// `accept` for a message whose query uses these capabilities, see `handle_message_using`
+    pub fn accept_using(&mut self, message: Message, capabilities: Capabilities) -> Result<Accepted, ServerError> {
+        if !matches!(message, Message::Announcement { .. }) {
+            let negotiated = self.states.iter()
+                .find(|s| s.rdm_nr == message.rnd_nr())
+                .map(|s| s.capabilities);
+            if let Some(negotiated) = negotiated.filter(|negotiated| !negotiated.contains(capabilities)) {
+                return Err(ServerError::NotNegotiated { capabilities: capabilities.without(negotiated) });
+            }
+        }
+        self.accept(message)
+    }
+
+    ///
+    /// Acknowledge a chunk of `accept` that was written.
+    ///
+    /// Other queries were handled in between, e.g. a retry of the same chunk was acknowledged already,
+    /// or the transmission was evicted.
+    ///
+    pub fn complete(&mut self, chunk: PendingChunk) -> Result<MessageResponse, ServerError> {
+        let PendingChunk { rnd_nr, id, .. } = chunk;
+        if self.is_windowed(rnd_nr) {
+            let state = ServerState::find_windowed_state(&mut self.states, rnd_nr)?;
+            state.acknowledge_chunk(id);
+            return Ok(MessageResponse::Data { response: state.selective_acknowledge() });
+        }
+        let state = self.states.iter_mut()
+            .find(|s| s.window.is_none() && s.rdm_nr == rnd_nr)
+            .ok_or(ServerError::UnknownRndNr { rnd_nr })?;
+        if state.acknowledged_id != Some(id) {
+            if state.expected_id != id {
+                return Err(ServerError::UnknownId { id });
+            }
+            state.acknowledged_id = Some(id);
+            state.expected_id = next_id(id);
+        }
+        Ok(MessageResponse::Data { response: DataResponse::Acknowledge { next_id: state.expected_id } })
+    }
+
+    fn handle_accepted(&mut self, accepted: Accepted) -> Result<MessageResponse, ServerError> {
+        match accepted {
+            Accepted::Response(response) => Ok(response),
+            Accepted::Chunk(chunk) => {
+                chunk.write()?;
+                self.complete(chunk)
+            }
+        }
+    }
+
+    fn announce(&mut self, host: String, file_name: String, rnd_nr: SessionId, version: u8, capabilities: Capabilities) -> Result<MessageResponse, ServerError> {
+        if self.is_known(rnd_nr) {
+            // a guessed or colliding token must not take over another transmission
+            return Err(ServerError::SessionInUse { rnd_nr });
+        }
+        let next_id = FIRST_ID;
+        let mut capabilities = capabilities.negotiate(self.capabilities);
+        if version < 2 {
+            // chunks of a window may arrive in any order, so they need an offset
+            capabilities = capabilities.without(Capabilities::WINDOW);
+        }
+        let data = self.create_sink(rnd_nr).map_err(ServerError::Storage)?;
+        let mut state = TransmissionState::new(
+            rnd_nr, host, file_name, next_id, capabilities, data);
+        state.max_file_size = self.max_file_size();
+        if capabilities.contains(Capabilities::WINDOW) {
+            state.window = Some(BTreeSet::new());
+        }
+        self.states.push(state);
+        Ok(MessageResponse::Announcement { rnd_nr, next_id, capabilities, parameters: Some(self.parameters()) })
+    }
+
+    fn finish(&mut self, rnd_nr: SessionId, file_size: u64, digest: &Digest) -> Result<MessageResponse, ServerError> {
+        let mut state = self.pop_state(rnd_nr)?;
+        let missing = state.missing(file_size);
+        if !missing.is_empty() {
+            // the transmission goes on until the client filled the gaps
+            let next_id = state.resend_id();
+            self.states.push(state);
+            return Ok(MessageResponse::Finish { response: FinishResponse::resend(next_id, missing) });
+        }
+        let response = if state.verify(file_size, digest) {
+            self.finished_states.push(state);
+            FinishResponse::Acknowledge { rnd_nr }
+        } else {
+            self.failed_states.push(state);
+            FinishResponse::Failed { rnd_nr }
+        };
+        if self.completed.len() == COMPLETED_CACHE_SIZE {
+            self.completed.pop_front();
+        }
+        self.completed.push_back((rnd_nr, response.clone()));
+        Ok(MessageResponse::Finish { response })
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Limits of the server, clients learn them from the announcement response
+    pub fn parameters(&self) -> ServerParameters {
+        ServerParameters {
+            version: PROTOCOL_VERSION,
+            max_window_size: MAX_WINDOW_SIZE as u32,
+            max_file_size: self.max_file_size(),
+        }
+    }
+
+    ///
+    /// Largest file a client may send, at most `MAX_FILE_SIZE`.
+    ///
+    /// Without spool directory it is `DEFAULT_MAX_MEMORY_FILE_SIZE` by default, since all data is kept in memory.
+    /// Applies to transmissions that are announced later.
+    ///
+    pub fn set_max_file_size(&mut self, max_file_size: u64) {
+        self.max_file_size = Some(max_file_size.min(MAX_FILE_SIZE));
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        match (self.max_file_size, &self.spool_directory) {
+            (Some(max_file_size), _) => max_file_size,
+            (None, Some(_)) => MAX_FILE_SIZE,
+            (None, None) => DEFAULT_MAX_MEMORY_FILE_SIZE,
+        }
+    }
+
+    ///
+    /// Write the data of new transmissions to `<session>.part` files in this directory, instead of keeping it in memory.
+    ///
+    /// The directory must exist. To move finished files without copying, it should be on the same file system as their destination.
+    ///
+    pub fn set_spool_directory<P: AsRef<Path>>(&mut self, spool_directory: P) {
+        self.spool_directory = Some(spool_directory.as_ref().to_path_buf());
+    }
+
+    fn create_sink(&self, rnd_nr: SessionId) -> io::Result<DataSink> {
+        match &self.spool_directory {
+            None => Ok(DataSink::Memory(Vec::new())),
+            Some(directory) => {
+                let path = directory.join(format!("{:016x}.part", rnd_nr));
+                Ok(DataSink::File(SpoolFile::create(path)?))
+            }
+        }
+    }
+
+    ///
+    /// Move transmissions without a message within the idle timeout to `expired_states`.
+    ///
+    /// Returns the number of evicted transmissions.
+    ///
+    pub fn evict_idle(&mut self, now: Instant) -> usize {
+        let idle_timeout = self.idle_timeout;
+        let (expired, active): (Vec<_>, Vec<_>) = self.states
+            .drain(..)
+            .partition(|s| now.saturating_duration_since(s.last_activity) >= idle_timeout);
+        self.states = active;
+        let count = expired.len();
+        self.expired_states.extend(expired);
+        count
+    }
+
+    fn touch(&mut self, rnd_nr: SessionId) {
+        let now = Instant::now();
+        for state in self.states.iter_mut().filter(|s| s.rdm_nr == rnd_nr) {
+            state.last_activity = now;
+        }
+    }
+
+    ///
+    /// The response a message got before, if it was already handled.
+    ///
+    /// Windowed transmissions need no replay, their acknowledgements do not change with duplicates.
+    ///
+    fn replay(&self, message: &Message) -> Option<MessageResponse> {
+        match message {
+            Message::Announcement { host, file_name, rnd_nr, .. } => {
+                self.states.iter()
+                    .find(|s| s.rdm_nr == *rnd_nr && s.host.eq_ignore_ascii_case(host) && &s.name == file_name)
+                    .map(|s| MessageResponse::Announcement {
+                        rnd_nr: *rnd_nr, next_id: s.first_id, capabilities: s.capabilities, parameters: Some(self.parameters()),
+                    })
             }
-            Message::Finish { rnd_nr } => {
-                let state = self.pop_state(rnd_nr)?;
-                self.finished_states.push(state);
-                Ok(MessageResponse::Finish {
-                    response: FinishResponse::Acknowledge { rnd_nr }
-                })
+            Message::Data { rnd_nr, id, .. } => {
+                self.states.iter()
+                    .find(|s| s.window.is_none() && s.rdm_nr == *rnd_nr && s.acknowledged_id == Some(*id))
+                    .map(|s| MessageResponse::Data { response: DataResponse::Acknowledge { next_id: s.expected_id } })
+            }
+            Message::Finish { rnd_nr, .. } => {
+                self.completed.iter()
+                    .rev()
+                    .find(|(r, _)| r == rnd_nr)
+                    .map(|(_, response)| MessageResponse::Finish { response: response.clone() })
             }
         }
     }
 
-    fn find_state(states: &mut Vec<TransmissionState>, id: Id) -> Result<&mut TransmissionState, ServerError> {
+    /// Whether a transmission with this token is active or was finished recently
+    fn is_known(&self, rnd_nr: SessionId) -> bool {
+        self.states.iter().any(|s| s.rdm_nr == rnd_nr) || self.completed.iter().any(|(r, _)| *r == rnd_nr)
+    }
+
+    fn is_windowed(&self, rnd_nr: SessionId) -> bool {
+        self.states.iter().any(|s| s.rdm_nr == rnd_nr && s.window.is_some())
+    }
+
+    fn find_windowed_state(states: &mut [TransmissionState], rnd_nr: SessionId) -> Result<&mut TransmissionState, ServerError> {
+        states
+            .iter_mut()
+            .find(|s| s.rdm_nr == rnd_nr && s.window.is_some())
+            .ok_or(ServerError::UnknownRndNr { rnd_nr })
+    }
+
+    /// The session of the header selects the transmission, the id must be the one it expects next
+    fn find_state(states: &mut [TransmissionState], rnd_nr: SessionId, id: Id) -> Result<&mut TransmissionState, ServerError> {
         let state = states
             .iter_mut()
-            .find(|s| s.expected_id == id);
-        match state {
-            None => Err(ServerError::UnknownId { id }),
-            Some(state) => Ok(state),
+            .find(|s| s.window.is_none() && s.rdm_nr == rnd_nr)
+            .ok_or(ServerError::UnknownRndNr { rnd_nr })?;
+        if state.expected_id != id {
+            return Err(ServerError::UnknownId { id });
         }
+        Ok(state)
     }
 
-    fn pop_state(&mut self, rnd_nr: u16) -> Result<TransmissionState, ServerError> {
+    fn pop_state(&mut self, rnd_nr: SessionId) -> Result<TransmissionState, ServerError> {
         let x = self.states
             .iter()
             .enumerate()
@@ -80,6 +448,10 @@ impl ServerState {
 
 #[cfg(test)]
 mod server_state_tests {
+    use trust_dns_proto::rr::RecordType;
+
+    use crate::message::file_digest;
+
     use super::*;
 
     #[test]
@@ -90,19 +462,21 @@ mod server_state_tests {
             host: "db-server".to_string(),
             file_name: "passwords.txt".to_string(),
             rnd_nr: 23523,
+            version: 0,
+            capabilities: Capabilities::V0,
         };
 
         let response0 = server_state.handle_message(message0)
             .expect("expected a response");
         let next_id = match response0 {
-            MessageResponse::Announcement { rnd_nr, next_id } => {
+            MessageResponse::Announcement { rnd_nr, next_id, .. } => {
                 assert_eq!(23523, rnd_nr);
                 next_id
             }
             _ => panic!("Expected an announcement response")
         };
 
-        let message1 = Message::Data { id: next_id, data: vec![1, 2, 3] };
+        let message1 = Message::data(23523, next_id, 0, vec![1, 2, 3]);
         let response1 = server_state.handle_message(message1)
             .expect("expected an response");
 
@@ -110,13 +484,13 @@ mod server_state_tests {
             MessageResponse::Data { response } => {
                 match response {
                     DataResponse::Acknowledge { next_id: _ } => { }
-                    DataResponse::Resend => { panic!("Expected an acknowledge") }
+                    _ => { panic!("Expected an acknowledge") }
                 }
             }
             _ => panic!("Expected an data response")
         };
 
-        let message2 = Message::Finish { rnd_nr: 23523 };
+        let message2 = Message::finish(23523, &[1, 2, 3]);
         let response2 = server_state.handle_message(message2)
             .expect("expected an response");
         match response2 {
@@ -125,7 +499,7 @@ mod server_state_tests {
                     FinishResponse::Acknowledge { rnd_nr } => {
                         assert_eq!(23523, rnd_nr);
                     }
-                    FinishResponse::Resend => { panic!("Expected an acknowledge") }
+                    _ => { panic!("Expected an acknowledge") }
                 }
             }
             _ => panic!("Expected Finish response")
@@ -133,69 +507,654 @@ mod server_state_tests {
 
         assert_eq!(1, server_state.finished_states.len());
     }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut server_state = ServerState::new();
+        let message0 = Message::initial("db-server".to_string(), "passwords.txt".to_string(), 23523, Capabilities::V0);
+        let next_id = match server_state.handle_message(message0).unwrap() {
+            MessageResponse::Announcement { rnd_nr: _, next_id, .. } => next_id,
+            _ => panic!("Expected an announcement response")
+        };
+
+        let corrupted = Message::Data { rnd_nr: 23523, id: next_id, offset: Some(0), data: vec![1, 2, 4], checksum: crc32fast::hash(&[1, 2, 3]) };
+        let response = server_state.handle_message(corrupted).unwrap();
+        assert_eq!(MessageResponse::Data { response: DataResponse::Resend }, response);
+
+        // the chunk was discarded and the id is still valid for the resend
+        let response = server_state.handle_message(Message::data(23523, next_id, 0, vec![1, 2, 3])).unwrap();
+        match response {
+            MessageResponse::Data { response: DataResponse::Acknowledge { next_id: _ } } => {}
+            _ => panic!("Expected an acknowledge")
+        }
+        server_state.handle_message(Message::finish(23523, &[1, 2, 3])).unwrap();
+        assert_eq!(vec![1, 2, 3], server_state.finished_states[0].data().sink.read_all().unwrap());
+    }
+
+    #[test]
+    fn test_verify_finish() {
+        let files: Vec<(&[u8], Message)> = vec![
+            (b"abc", Message::finish(1, b"abc")),
+            // missing data, the client has to send it
+            (b"ab", Message::finish(2, b"abc")),
+            // same size, different content
+            (b"abd", Message::finish(3, b"abc")),
+        ];
+        let mut server_state = ServerState::new();
+        let mut responses = Vec::new();
+        for (data, finish) in files {
+            let rnd_nr = match finish { Message::Finish { rnd_nr, .. } => rnd_nr, _ => unreachable!() };
+            let announcement = Message::initial("db-server".to_string(), "passwords.txt".to_string(), rnd_nr, Capabilities::supported());
+            let next_id = match server_state.handle_message(announcement).unwrap() {
+                MessageResponse::Announcement { rnd_nr: _, next_id, .. } => next_id,
+                _ => panic!("Expected an announcement response")
+            };
+            server_state.handle_message(Message::data(rnd_nr, next_id, 0, data.to_vec())).unwrap();
+            responses.push(server_state.handle_message(finish).unwrap());
+        }
+
+        assert_eq!(MessageResponse::Finish { response: FinishResponse::Acknowledge { rnd_nr: 1 } }, responses[0]);
+        match &responses[1] {
+            MessageResponse::Finish { response: FinishResponse::Resend { next_id, missing } } => {
+                assert_eq!(FIRST_ID + 1, *next_id);
+                assert_eq!(vec![2..3], *missing);
+            }
+            other => panic!("Expected a resend, got {:?}", other),
+        }
+        assert_eq!(MessageResponse::Finish { response: FinishResponse::Failed { rnd_nr: 3 } }, responses[2]);
+        assert_eq!(1, server_state.finished_states.len());
+        assert_eq!(1, server_state.failed_states.len());
+        assert_eq!(1, server_state.states.len());
+    }
+
+    #[test]
+    fn test_selective_acknowledge() {
+        let mut server_state = ServerState::new();
+        let announcement = Message::initial("db-server".to_string(), "passwords.txt".to_string(), 1, Capabilities::WINDOW);
+        let first_id = match server_state.handle_message(announcement).unwrap() {
+            MessageResponse::Announcement { rnd_nr: _, next_id, capabilities, .. } => {
+                assert!(capabilities.contains(Capabilities::WINDOW));
+                next_id
+            }
+            _ => panic!("Expected an announcement response")
+        };
+        let mut send = |index: Id, data: Vec<u8>, checksum: u32| {
+            let message = Message::Data { rnd_nr: 1, id: first_id.wrapping_add(index), offset: Some(index as u64), data, checksum };
+            match server_state.handle_message(message).unwrap() {
+                MessageResponse::Data { response: DataResponse::SelectiveAcknowledge { next_id, received } } => {
+                    (next_id.wrapping_sub(first_id), received)
+                }
+                _ => panic!("Expected a selective acknowledge")
+            }
+        };
+        let mut send_chunk = |index: Id| send(index, vec![index as u8 + 1], crc32fast::hash(&[index as u8 + 1]));
+        assert_eq!((0, 0b10), send_chunk(2));
+        assert_eq!((0, 0b110), send_chunk(3));
+        // duplicates change nothing
+        assert_eq!((0, 0b110), send_chunk(3));
+        assert_eq!((1, 0b11), send_chunk(0));
+        assert_eq!((1, 0b11), send_chunk(0));
+        assert_eq!((4, 0), send_chunk(1));
+        // corrupted chunks are not acknowledged
+        assert_eq!((4, 0), send(4, vec![5], 0));
+
+        server_state.handle_message(Message::finish(1, &[1, 2, 3, 4])).unwrap();
+        assert_eq!(vec![1, 2, 3, 4], server_state.finished_states[0].data().sink.read_all().unwrap());
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut server_state = ServerState::new();
+        let announcement = Message::initial("db-server".to_string(), "passwords.txt".to_string(), 1, Capabilities::V0);
+        let response0 = server_state.handle_message(announcement.clone()).unwrap();
+        assert_eq!(response0, server_state.handle_message(announcement).unwrap());
+        assert_eq!(1, server_state.states.len());
+        let next_id = match response0 {
+            MessageResponse::Announcement { rnd_nr: _, next_id, .. } => next_id,
+            _ => panic!("Expected an announcement response")
+        };
+
+        let data = Message::data(1, next_id, 0, vec![1, 2, 3]);
+        let response1 = server_state.handle_message(data.clone()).unwrap();
+        assert_eq!(response1, server_state.handle_message(data.clone()).unwrap());
+        let next_id = match response1 {
+            MessageResponse::Data { response: DataResponse::Acknowledge { next_id } } => next_id,
+            _ => panic!("Expected an acknowledge")
+        };
+        server_state.handle_message(Message::data(1, next_id, 3, vec![4])).unwrap();
+        // an older chunk is not acknowledged anymore
+        assert!(server_state.handle_message(data).is_err());
+
+        let finish = Message::finish(1, &[1, 2, 3, 4]);
+        let response2 = server_state.handle_message(finish.clone()).unwrap();
+        server_state.finished_states.clear();
+        assert_eq!(response2, server_state.handle_message(finish).unwrap());
+        assert!(server_state.finished_states.is_empty());
+        assert!(server_state.failed_states.is_empty());
+    }
+
+    fn announce(server_state: &mut ServerState, rnd_nr: SessionId, version: u8) -> (Id, Capabilities) {
+        let announcement = Message::Announcement {
+            host: "db-server".to_string(),
+            file_name: "passwords.txt".to_string(),
+            rnd_nr,
+            version,
+            capabilities: Capabilities::supported(),
+        };
+        match server_state.handle_message(announcement).unwrap() {
+            MessageResponse::Announcement { rnd_nr: _, next_id, capabilities, .. } => (next_id, capabilities),
+            _ => panic!("Expected an announcement response")
+        }
+    }
+
+    #[test]
+    fn test_out_of_order_offsets() {
+        let mut server_state = ServerState::new();
+        let (first_id, capabilities) = announce(&mut server_state, 1, 2);
+        assert!(capabilities.contains(Capabilities::WINDOW));
+
+        // chunks of different sizes, the offsets place them
+        let chunks: Vec<(u64, &[u8])> = vec![(0, b"pass"), (4, b"wo"), (6, b"rd12"), (10, b"3")];
+        for i in &[3, 1, 2] {
+            let (offset, data) = chunks[*i];
+            server_state.handle_message(Message::data(1, first_id.wrapping_add(*i as Id), offset, data.to_vec())).unwrap();
+        }
+        let state = &mut server_state.states[0];
+        assert_eq!(vec![0..4], state.data().received.gaps(11));
+        assert!(!state.verify(11, &file_digest(b"password123")));
+
+        server_state.handle_message(Message::data(1, first_id, 0, b"pass".to_vec())).unwrap();
+        server_state.handle_message(Message::finish(1, b"password123")).unwrap();
+        assert_eq!(b"password123".to_vec(), server_state.finished_states[0].data().sink.read_all().unwrap());
+    }
+
+    #[test]
+    fn test_rewritten_chunk() {
+        let mut server_state = ServerState::new();
+        let (first_id, _) = announce(&mut server_state, 1, 1);
+        server_state.handle_message(Message::data(1, first_id, 0, vec![1, 2])).unwrap();
+        server_state.handle_message(Message::data(1, first_id + 1, 2, vec![3])).unwrap();
+        // the hashed data changes, so the digest has to start over
+        server_state.handle_message(Message::data(1, first_id + 2, 0, vec![9])).unwrap();
+        let response = server_state.handle_message(Message::finish(1, &[9, 2, 3])).unwrap();
+        assert_eq!(MessageResponse::Finish { response: FinishResponse::Acknowledge { rnd_nr: 1 } }, response);
+    }
+
+    #[test]
+    fn test_gap_resends() {
+        let mut server_state = ServerState::new();
+        let (first_id, _) = announce(&mut server_state, 1, 2);
+        // the first chunk is missing, the zeros of the sink must not pass for data
+        server_state.handle_message(Message::data(1, first_id.wrapping_add(1), 2, vec![0, 0])).unwrap();
+        server_state.handle_message(Message::data(1, first_id.wrapping_add(2), 4, vec![5])).unwrap();
+        let finish = Message::finish(1, &[0, 0, 0, 0, 5, 6]);
+        let response = server_state.handle_message(finish.clone()).unwrap();
+        let next_id = first_id.wrapping_add(3);
+        assert_eq!(MessageResponse::Finish { response: FinishResponse::Resend { next_id, missing: vec![0..2, 5..6] } }, response);
+
+        server_state.handle_message(Message::data(1, next_id, 0, vec![0, 0])).unwrap();
+        server_state.handle_message(Message::data(1, next_id.wrapping_add(1), 5, vec![6])).unwrap();
+        let response = server_state.handle_message(finish).unwrap();
+        assert_eq!(MessageResponse::Finish { response: FinishResponse::Acknowledge { rnd_nr: 1 } }, response);
+        assert_eq!(vec![0, 0, 0, 0, 5, 6], server_state.finished_states[0].data().sink.read_all().unwrap());
+    }
+
+    #[test]
+    fn test_many_wide_gaps() {
+        let mut server_state = ServerState::new();
+        let (first_id, _) = announce(&mut server_state, 1, 2);
+        let file_size = DEFAULT_MAX_MEMORY_FILE_SIZE;
+        let offsets: Vec<u64> = (1..=20).map(|k| file_size - 2 * k).rev().collect();
+        for (id, offset) in (first_id..).zip(&offsets) {
+            server_state.handle_message(Message::data(1, id, *offset, vec![1])).unwrap();
+        }
+        let finish = Message::Finish { rnd_nr: 1, file_size, digest: file_digest(&[]) };
+        let response = server_state.handle_message(finish).unwrap();
+        match &response {
+            MessageResponse::Finish { response: FinishResponse::Resend { missing, .. } } => {
+                // more gaps than fit into a name, the first ones are listed
+                assert!(missing.len() > 8 && missing.len() < offsets.len());
+                assert_eq!(0..offsets[0], missing[0]);
+                assert_eq!(offsets[0] + 1..offsets[1], missing[1]);
+            },
+            other => panic!("Expected a resend, got {:?}", other),
+        }
+        response.clone().encode().unwrap();
+        response.encode_for(RecordType::TXT).unwrap();
+    }
+
+    #[test]
+    fn test_write_without_state() {
+        let mut server_state = ServerState::new();
+        let (first_id, _) = announce(&mut server_state, 1, 1);
+        let (window_id, _) = announce(&mut server_state, 2, 2);
+        let pending = |accepted| match accepted {
+            Accepted::Chunk(chunk) => chunk,
+            other => panic!("Expected a chunk to write, got {:?}", other),
+        };
+
+        // a retried chunk is accepted again while the first one is written, both get the acknowledgement
+        let chunk = pending(server_state.accept(Message::data(1, first_id, 0, vec![1, 2])).unwrap());
+        let retry = pending(server_state.accept(Message::data(1, first_id, 0, vec![1, 2])).unwrap());
+        let windowed = pending(server_state.accept(Message::data(2, window_id, 0, vec![3])).unwrap());
+        chunk.write().unwrap();
+        retry.write().unwrap();
+        windowed.write().unwrap();
+        let acknowledge = MessageResponse::Data { response: DataResponse::Acknowledge { next_id: first_id + 1 } };
+        assert_eq!(acknowledge, server_state.complete(chunk).unwrap());
+        assert_eq!(acknowledge, server_state.complete(retry).unwrap());
+        let acknowledge = MessageResponse::Data { response: DataResponse::SelectiveAcknowledge { next_id: window_id + 1, received: 0 } };
+        assert_eq!(acknowledge, server_state.complete(windowed).unwrap());
+
+        server_state.handle_message(Message::finish(1, &[1, 2])).unwrap();
+        assert_eq!(vec![1, 2], server_state.finished_states[0].data().sink.read_all().unwrap());
+
+        // the transmission may be gone once the chunk is written
+        let chunk = pending(server_state.accept(Message::data(2, window_id + 1, 1, vec![4])).unwrap());
+        server_state.evict_idle(Instant::now() + DEFAULT_IDLE_TIMEOUT);
+        chunk.write().unwrap();
+        assert!(matches!(server_state.complete(chunk), Err(ServerError::UnknownRndNr { rnd_nr: 2 })));
+    }
+
+    #[test]
+    fn test_version_1_appends() {
+        let mut server_state = ServerState::new();
+        let (next_id, capabilities) = announce(&mut server_state, 1, 1);
+        assert!(!capabilities.contains(Capabilities::WINDOW));
+        let chunk = |id, data: Vec<u8>| Message::Data { rnd_nr: 1, id, offset: None, checksum: crc32fast::hash(&data), data };
+        let next_id = match server_state.handle_message(chunk(next_id, vec![1, 2])).unwrap() {
+            MessageResponse::Data { response: DataResponse::Acknowledge { next_id } } => next_id,
+            _ => panic!("Expected an acknowledge")
+        };
+        server_state.handle_message(chunk(next_id, vec![3])).unwrap();
+        server_state.handle_message(Message::finish(1, &[1, 2, 3])).unwrap();
+        assert_eq!(vec![1, 2, 3], server_state.finished_states[0].data().sink.read_all().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_offset() {
+        let mut server_state = ServerState::new();
+        let (first_id, _) = announce(&mut server_state, 1, 2);
+        let result = server_state.handle_message(Message::data(1, first_id, MAX_FILE_SIZE, vec![1]));
+        match result {
+            Err(ServerError::InvalidOffset { offset }) => assert_eq!(MAX_FILE_SIZE, offset),
+            other => panic!("Expected InvalidOffset, got {:?}", other),
+        }
+        assert!(server_state.handle_message(Message::data(1, first_id, u64::MAX, vec![1])).is_err());
+    }
+
+    #[test]
+    fn test_max_file_size() {
+        // data in memory is limited further, a single chunk must not allocate a gigabyte
+        let mut server_state = ServerState::new();
+        assert_eq!(DEFAULT_MAX_MEMORY_FILE_SIZE, server_state.parameters().max_file_size);
+        let (first_id, _) = announce(&mut server_state, 1, 2);
+        match server_state.handle_message(Message::data(1, first_id, DEFAULT_MAX_MEMORY_FILE_SIZE, vec![1])) {
+            Err(ServerError::InvalidOffset { offset }) => assert_eq!(DEFAULT_MAX_MEMORY_FILE_SIZE, offset),
+            other => panic!("Expected InvalidOffset, got {:?}", other),
+        }
+        assert_eq!(0, server_state.states[0].data().sink.len());
+
+        server_state.set_max_file_size(10);
+        assert_eq!(10, server_state.parameters().max_file_size);
+        let (first_id, _) = announce(&mut server_state, 2, 2);
+        server_state.handle_message(Message::data(2, first_id, 8, vec![9, 10])).unwrap();
+        assert!(server_state.handle_message(Message::data(2, first_id.wrapping_add(1), 10, vec![11])).is_err());
+        // a finish message beyond the limit has nothing to resend
+        let response = server_state.handle_message(Message::finish(2, &[0; 11])).unwrap();
+        assert_eq!(MessageResponse::Finish { response: FinishResponse::Failed { rnd_nr: 2 } }, response);
+
+        server_state.set_max_file_size(u64::MAX);
+        assert_eq!(MAX_FILE_SIZE, server_state.max_file_size());
+    }
+
+    #[test]
+    fn test_evict_idle() {
+        let mut server_state = ServerState::new();
+        server_state.set_idle_timeout(Duration::from_secs(60));
+        let (first_id, _) = announce(&mut server_state, 1, 2);
+        announce(&mut server_state, 2, 2);
+        server_state.handle_message(Message::data(1, first_id, 0, vec![1, 2, 3])).unwrap();
+
+        let now = Instant::now();
+        assert_eq!(0, server_state.evict_idle(now));
+        server_state.states[0].last_activity = now - Duration::from_secs(61);
+        assert_eq!(1, server_state.evict_idle(now));
+        assert_eq!(1, server_state.states.len());
+        assert_eq!(vec![1, 2, 3], server_state.expired_states[0].data().sink.read_all().unwrap());
+
+        // a message keeps the transmission alive
+        server_state.states[0].last_activity = now - Duration::from_secs(61);
+        announce(&mut server_state, 2, 2);
+        assert_eq!(0, server_state.evict_idle(now));
+        assert_eq!(1, server_state.states.len());
+    }
+
+    #[test]
+    fn test_session_collision() {
+        let mut server_state = ServerState::new();
+        let (first_id, _) = announce(&mut server_state, 1, 2);
+
+        // another client with the same token is rejected, a retried announcement is not
+        let collision = Message::initial("web-server".to_string(), "passwords.txt".to_string(), 1, Capabilities::supported());
+        match server_state.handle_message(collision) {
+            Err(ServerError::SessionInUse { rnd_nr: 1 }) => {}
+            other => panic!("Expected SessionInUse, got {:?}", other),
+        }
+        assert_eq!((first_id, Capabilities::supported()), announce(&mut server_state, 1, 2));
+
+        // tokens of finished transmissions are not reused while their response may be replayed
+        server_state.handle_message(Message::data(1, first_id, 0, vec![1])).unwrap();
+        server_state.handle_message(Message::finish(1, &[1])).unwrap();
+        let reused = Message::initial("db-server".to_string(), "other.txt".to_string(), 1, Capabilities::supported());
+        assert!(server_state.handle_message(reused).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_sessions() {
+        // both transmissions count from the same first id, the token tells them apart
+        let mut server_state = ServerState::new();
+        let (first_id_1, _) = announce(&mut server_state, 1, 1);
+        let (first_id_2, _) = announce(&mut server_state, 2, 1);
+        assert_eq!(first_id_1, first_id_2);
+
+        let mut next_ids = [first_id_1, first_id_2];
+        for i in 0..10_u8 {
+            for (rnd_nr, next_id) in next_ids.iter_mut().enumerate() {
+                let rnd_nr = rnd_nr as SessionId + 1;
+                let chunk = Message::data(rnd_nr, *next_id, i as u64, vec![rnd_nr as u8 * 10 + i]);
+                *next_id = match server_state.handle_message(chunk).unwrap() {
+                    MessageResponse::Data { response: DataResponse::Acknowledge { next_id } } => next_id,
+                    other => panic!("Expected an acknowledge, got {:?}", other),
+                };
+            }
+        }
+        assert_eq!([first_id_1 + 10, first_id_2 + 10], next_ids);
+
+        let file_1: Vec<u8> = (10..20).collect();
+        let file_2: Vec<u8> = (20..30).collect();
+        server_state.handle_message(Message::finish(2, &file_2)).unwrap();
+        server_state.handle_message(Message::finish(1, &file_1)).unwrap();
+        assert_eq!(file_2, server_state.finished_states[0].data().sink.read_all().unwrap());
+        assert_eq!(file_1, server_state.finished_states[1].data().sink.read_all().unwrap());
+    }
+
+    #[test]
+    fn test_unknown_session() {
+        let mut server_state = ServerState::new();
+        let (first_id, _) = announce(&mut server_state, 1, 1);
+
+        // the id alone does not select a transmission
+        match server_state.handle_message(Message::data(2, first_id, 0, vec![1])) {
+            Err(ServerError::UnknownRndNr { rnd_nr: 2 }) => {}
+            other => panic!("Expected UnknownRndNr, got {:?}", other),
+        }
+        match server_state.handle_message(Message::data(1, first_id + 1, 0, vec![1])) {
+            Err(ServerError::UnknownId { id }) => assert_eq!(first_id + 1, id),
+            other => panic!("Expected UnknownId, got {:?}", other),
+        }
+        assert_eq!(vec![0..1], server_state.states[0].data().received.gaps(1));
+
+        // data with the expected id of another transmission is rejected as well
+        server_state.handle_message(Message::data(1, first_id, 0, vec![1])).unwrap();
+        announce(&mut server_state, 2, 1);
+        match server_state.handle_message(Message::data(2, first_id + 1, 0, vec![2])) {
+            Err(ServerError::UnknownId { id }) => assert_eq!(first_id + 1, id),
+            other => panic!("Expected UnknownId, got {:?}", other),
+        }
+        assert_eq!(vec![0..1], server_state.states[1].data().received.gaps(1));
+        server_state.handle_message(Message::data(1, first_id + 1, 1, vec![2])).unwrap();
+    }
+
+    #[test]
+    fn test_sequence_wrap_around() {
+        let mut server_state = ServerState::new();
+        announce(&mut server_state, 1, 1);
+        announce(&mut server_state, 2, 2);
+        for state in server_state.states.iter_mut() {
+            state.expected_id = Id::MAX - 1;
+        }
+
+        // stop-and-wait, the reserved ids 0 and 1 are skipped
+        let mut next_ids = vec![Id::MAX - 1];
+        for i in 0..3_u8 {
+            let id = *next_ids.last().unwrap();
+            next_ids.push(match server_state.handle_message(Message::data(1, id, i as u64, vec![i])).unwrap() {
+                MessageResponse::Data { response: DataResponse::Acknowledge { next_id } } => next_id,
+                other => panic!("Expected an acknowledge, got {:?}", other),
+            });
+        }
+        assert_eq!(vec![Id::MAX - 1, Id::MAX, FIRST_ID, FIRST_ID + 1], next_ids);
+        server_state.handle_message(Message::data(1, FIRST_ID + 1, 3, vec![3])).unwrap();
+
+        // windowed, the chunk after the wrap arrives first
+        let chunk = |id: Id, i: u8| Message::data(2, id, i as u64, vec![i]);
+        match server_state.handle_message(chunk(FIRST_ID, 2)).unwrap() {
+            MessageResponse::Data { response } => {
+                assert_eq!(DataResponse::SelectiveAcknowledge { next_id: Id::MAX - 1, received: 0b10 }, response)
+            }
+            other => panic!("Expected a data response, got {:?}", other),
+        }
+        // reserved ids are never part of the window
+        match server_state.handle_message(chunk(0, 2)).unwrap() {
+            MessageResponse::Data { response } => {
+                assert_eq!(DataResponse::SelectiveAcknowledge { next_id: Id::MAX - 1, received: 0b10 }, response)
+            }
+            other => panic!("Expected a data response, got {:?}", other),
+        }
+        server_state.handle_message(chunk(Id::MAX - 1, 0)).unwrap();
+        match server_state.handle_message(chunk(Id::MAX, 1)).unwrap() {
+            MessageResponse::Data { response } => {
+                assert_eq!(DataResponse::SelectiveAcknowledge { next_id: FIRST_ID + 1, received: 0 }, response)
+            }
+            other => panic!("Expected a data response, got {:?}", other),
+        }
+
+        server_state.handle_message(Message::finish(1, &[0, 1, 2, 3])).unwrap();
+        server_state.handle_message(Message::finish(2, &[0, 1, 2])).unwrap();
+        assert_eq!(2, server_state.finished_states.len());
+    }
+
+    #[test]
+    fn test_enforce_capabilities() {
+        let mut server_state = ServerState::with_capabilities(Capabilities::supported().union(Capabilities::COMPRESSION));
+        let client_capabilities = Capabilities::CODEC_HEX.union(Capabilities::COMPRESSION).union(Capabilities::ENCRYPTION);
+        let announcement = Message::initial("db-server".to_string(), "passwords.txt".to_string(), 1, client_capabilities);
+        let next_id = match server_state.handle_message(announcement).unwrap() {
+            MessageResponse::Announcement { next_id, capabilities, .. } => {
+                // not implemented, so never offered
+                assert_eq!(Capabilities::CODEC_BASE32.union(Capabilities::CODEC_HEX), capabilities);
+                next_id
+            }
+            _ => panic!("Expected an announcement response")
+        };
+
+        let chunk = Message::data(1, next_id, 0, vec![1]);
+        match server_state.handle_message_using(chunk.clone(), Capabilities::CODEC_BASE36.union(Capabilities::RESPONSE_TXT)) {
+            Err(ServerError::NotNegotiated { capabilities }) => {
+                assert_eq!(Capabilities::CODEC_BASE36.union(Capabilities::RESPONSE_TXT), capabilities)
+            }
+            other => panic!("Expected NotNegotiated, got {:?}", other),
+        }
+        server_state.handle_message_using(chunk, Capabilities::CODEC_HEX).unwrap();
+
+        // without window, chunks must arrive in order
+        assert!(server_state.handle_message_using(Message::data(1, next_id + 2, 2, vec![3]), Capabilities::CODEC_HEX).is_err());
+    }
+
+    #[test]
+    fn test_negotiate_capabilities() {
+        let server_capabilities = Capabilities::CODEC_HEX.union(Capabilities::RESPONSE_TXT);
+        let mut server_state = ServerState::with_capabilities(server_capabilities);
+        let client_capabilities = Capabilities::CODEC_RAW.union(Capabilities::CODEC_HEX);
+        let announcement = Message::initial("db-server".to_string(), "passwords.txt".to_string(), 1, client_capabilities);
+        match server_state.handle_message(announcement).unwrap() {
+            MessageResponse::Announcement { rnd_nr: _, next_id: _, capabilities, .. } => {
+                assert_eq!(Capabilities::CODEC_BASE32.union(Capabilities::CODEC_HEX), capabilities);
+            }
+            _ => panic!("Expected an announcement response")
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct TransmissionState {
-    rdm_nr: u16,
+    rdm_nr: SessionId,
+    /** The id of the announcement response */
+    first_id: Id,
     expected_id: Id,
+    /** Stop-and-wait transmissions only: id of the last chunk that was acknowledged */
+    acknowledged_id: Option<Id>,
     pub host: String,
     pub name: String,
-    pub data: Vec<u8>,
+    data: Arc<Mutex<TransmissionData>>,
+    /** Capabilities negotiated with the client */
+    pub capabilities: Capabilities,
+    /** Windowed transmissions only: ids of the chunks that arrived after `expected_id` */
+    window: Option<BTreeSet<Id>>,
+    /** Chunks must end before this offset */
+    max_file_size: u64,
+    /** When the last message of the client arrived */
+    pub last_activity: Instant,
 }
 
 impl TransmissionState {
-    fn new(rdm_nr: u16, host: String, name: String, expected_id: Id) -> TransmissionState {
+    fn new(rdm_nr: SessionId, host: String, name: String, expected_id: Id, capabilities: Capabilities, data: DataSink) -> TransmissionState {
         TransmissionState {
             rdm_nr,
+            first_id: expected_id,
             expected_id,
+            acknowledged_id: None,
             host,
             name,
-            data: Vec::new(),
+            data: Arc::new(Mutex::new(TransmissionData { sink: data, received: RangeSet::new(), digest: PrefixDigest::default() })),
+            capabilities,
+            window: None,
+            max_file_size: MAX_FILE_SIZE,
+            last_activity: Instant::now(),
         }
     }
-}
 
-const ID_RANGE_START: u16 = 2;
+    /// The random token the client announced the transmission with
+    pub fn session(&self) -> SessionId {
+        self.rdm_nr
+    }
+
+    /// The received data, chunks that are written meanwhile wait for the guard
+    pub fn data(&self) -> MutexGuard<'_, TransmissionData> {
+        self.data.lock().expect("Transmission data lock poisoned")
+    }
 
-#[derive(Debug)]
-pub struct IdGenerator {
-    next_id: Id
-}
+    /// Take the data of a transmission that left the server state, e.g. to discard it
+    pub fn into_sink(self) -> DataSink {
+        mem::replace(&mut self.data().sink, DataSink::Memory(Vec::new()))
+    }
 
-impl IdGenerator {
-    pub fn new() -> IdGenerator {
-        IdGenerator {
-            next_id: ID_RANGE_START
+    fn pending_chunk(&self, id: Id, offset: Option<u64>, data: Vec<u8>) -> PendingChunk {
+        PendingChunk { rnd_nr: self.rdm_nr, id, offset, data, max_file_size: self.max_file_size, transmission: Arc::clone(&self.data) }
+    }
+
+    ///
+    /// The gaps in a file of this size.
+    ///
+    /// Empty if the file can not have this size, then the client has nothing to resend and the transmission fails.
+    ///
+    fn missing(&self, file_size: u64) -> Vec<Range<u64>> {
+        let data = self.data();
+        if file_size > self.max_file_size || data.sink.len() > file_size {
+            return Vec::new();
         }
+        data.received.gaps(file_size)
     }
 
-    pub fn next_id(&mut self) -> Id {
-        let result = self.next_id;
-        if self.next_id == std::u16::MAX {
-            self.next_id = ID_RANGE_START
-        } else {
-            self.next_id += 1;
+    ///
+    /// The id of the first chunk the client resends.
+    ///
+    /// Ids of a window that arrived are skipped, so they are not taken for duplicates.
+    ///
+    fn resend_id(&mut self) -> Id {
+        let expected_id = self.expected_id;
+        if let Some(window) = &mut self.window {
+            if let Some(last) = window.iter().max_by_key(|id| id_distance(expected_id, **id)) {
+                self.expected_id = next_id(*last);
+            }
+            window.clear();
         }
-        result
+        self.expected_id
     }
-}
 
+    /// True if the file has this size and digest, and all of it was received
+    fn verify(&self, file_size: u64, digest: &Digest) -> bool {
+        let data = self.data();
+        // the digest covers the data up to the first gap, so all of it if there is none
+        data.sink.len() == file_size && data.digest.hashed_len() == file_size && data.digest.digest() == *digest
+    }
 
-#[cfg(test)]
-mod id_generator_tests {
-    use super::*;
+    ///
+    /// Windowed transmissions: whether a chunk is in the window and has not arrived yet.
+    ///
+    /// The client numbers the chunks consecutively, starting with the id of the announcement response.
+    ///
+    fn expects_chunk(&self, id: Id) -> bool {
+        let distance = id_distance(self.expected_id, id);
+        let duplicate = self.window.as_ref().is_none_or(|window| window.contains(&id));
+        id >= FIRST_ID && distance <= MAX_WINDOW_SIZE as u64 && !duplicate
+    }
 
-    #[test]
-    fn test_id_generator() {
-        let mut generator = IdGenerator::new();
-        assert_eq!(2, generator.next_id());
-        assert_eq!(3, generator.next_id());
-        assert_eq!(4, generator.next_id());
+    ///
+    /// Record a written chunk of a windowed transmission.
+    ///
+    /// The data is written already, the window only tracks which ids after `expected_id` arrived.
+    /// A chunk whose retry was recorded meanwhile changes nothing.
+    ///
+    fn acknowledge_chunk(&mut self, id: Id) {
+        if !self.expects_chunk(id) {
+            return;
+        }
+        if let Some(window) = &mut self.window {
+            window.insert(id);
+            while window.remove(&self.expected_id) {
+                self.expected_id = next_id(self.expected_id);
+            }
+        }
+    }
 
-        let mut generator = IdGenerator { next_id: std::u16::MAX };
-        assert_eq!(std::u16::MAX, generator.next_id());
-        assert_eq!(2, generator.next_id());
-        assert_eq!(3, generator.next_id());
+    fn selective_acknowledge(&self) -> DataResponse {
+        let received = (0..MAX_WINDOW_SIZE as Id)
+            .filter(|k| self.window.as_ref()
+                .is_some_and(|window| window.contains(&advance_id(self.expected_id, 1 + *k as u64))))
+            .fold(0_u32, |received, k| received | 1 << k);
+        DataResponse::SelectiveAcknowledge { next_id: self.expected_id, received }
     }
-}
\ No newline at end of file
+}
+
+///
+/// The data of a transmission, with the parts that were received and the digest of its start.
+///
+/// It has a lock of its own, so chunks are written and hashed without holding the server state.
+///
+#[derive(Debug)]
+pub struct TransmissionData {
+    pub sink: DataSink,
+    /** The parts of the data that were received */
+    pub received: RangeSet,
+    /** Digest of the data up to the first gap in `received` */
+    digest: PrefixDigest,
+}
+
+impl TransmissionData {
+    /// Write a chunk at its offset, chunks without offset are appended to the data received so far
+    fn write(&mut self, offset: Option<u64>, chunk: &[u8], max_file_size: u64) -> Result<(), ServerError> {
+        let offset = offset.unwrap_or_else(|| self.received.contiguous_len());
+        let end = offset.checked_add(chunk.len() as u64)
+            .filter(|end| *end <= max_file_size)
+            .ok_or(ServerError::InvalidOffset { offset })?;
+        if offset < self.digest.hashed_len() {
+            // data that was hashed already changes, which a client only does to repair it
+            self.digest = PrefixDigest::default();
+        }
+        self.sink.write_at(offset, chunk).map_err(ServerError::Storage)?;
+        self.received.insert(offset..end);
+        self.digest.extend(&mut self.sink, self.received.contiguous_len()).map_err(ServerError::Storage)
+    }
+}
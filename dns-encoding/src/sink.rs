@@ -0,0 +1,262 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest as _, Sha256};
+
+use crate::message::Digest;
+
+/// Bytes read at once to calculate the digest of a spooled file
+const READ_BUFFER_LEN: usize = 64 * 1024;
+
+/// Where the data of a transmission is written while it arrives
+#[derive(Debug)]
+pub enum DataSink {
+    /** Kept in memory until the transmission is finished */
+    Memory(Vec<u8>),
+    /** Written to a temporary file, which is moved to its final name once the transmission is finished */
+    File(SpoolFile),
+}
+
+impl DataSink {
+    /// Chunks are written at their offset, gaps are filled with zeros until they arrive
+    pub fn write_at(&mut self, offset: u64, chunk: &[u8]) -> io::Result<()> {
+        match self {
+            DataSink::Memory(data) => {
+                let end = offset as usize + chunk.len();
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[offset as usize..end].copy_from_slice(chunk);
+                Ok(())
+            }
+            DataSink::File(file) => file.write_at(offset, chunk),
+        }
+    }
+
+    /// Size of the data, up to the end of the last chunk
+    pub fn len(&self) -> u64 {
+        match self {
+            DataSink::Memory(data) => data.len() as u64,
+            DataSink::File(file) => file.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// SHA-256 of the data
+    pub fn digest(&mut self) -> io::Result<Digest> {
+        let mut hasher = Sha256::new();
+        self.hash_range(&mut hasher, 0..self.len())?;
+        Ok(hasher.finalize().into())
+    }
+
+    fn hash_range(&mut self, hasher: &mut Sha256, range: Range<u64>) -> io::Result<()> {
+        match self {
+            DataSink::Memory(data) => {
+                hasher.update(&data[range.start as usize..range.end as usize]);
+                Ok(())
+            }
+            DataSink::File(file) => file.hash_range(hasher, range),
+        }
+    }
+
+    /// Read all of the data, e.g. to store it elsewhere
+    pub fn read_all(&self) -> io::Result<Vec<u8>> {
+        match self {
+            DataSink::Memory(data) => Ok(data.clone()),
+            DataSink::File(file) => fs::read(&file.path),
+        }
+    }
+}
+
+///
+/// Temporary file that receives the data of one transmission.
+///
+/// Operators can watch it grow, but it only gets its final name in `persist`,
+/// so a file under the final name is always complete.
+///
+#[derive(Debug)]
+pub struct SpoolFile {
+    file: File,
+    path: PathBuf,
+    len: u64,
+}
+
+impl SpoolFile {
+    /// Create the file, fails if it exists already
+    pub fn create(path: PathBuf) -> io::Result<SpoolFile> {
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        Ok(SpoolFile { file, path, len: 0 })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write_at(&mut self, offset: u64, chunk: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(chunk)?;
+        self.len = self.len.max(offset + chunk.len() as u64);
+        Ok(())
+    }
+
+    fn hash_range(&mut self, hasher: &mut Sha256, range: Range<u64>) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(range.start))?;
+        let mut reader = (&self.file).take(range.end - range.start);
+        let mut buffer = vec![0_u8; READ_BUFFER_LEN];
+        loop {
+            let bytes_read = reader.read(&mut buffer)?;
+            if bytes_read == 0 {
+                return Ok(());
+            }
+            hasher.update(&buffer[..bytes_read]);
+        }
+    }
+
+    ///
+    /// Flush the data to disk and rename it to its final name, fails with `AlreadyExists` if the target exists.
+    ///
+    /// The target is reserved by an empty file first, which the rename replaces with the complete file at once.
+    /// Afterwards there is no temporary file left to `discard`.
+    ///
+    pub fn persist(&self, target: &Path) -> io::Result<()> {
+        self.file.sync_all()?;
+        OpenOptions::new().write(true).create_new(true).open(target)?;
+        if let Err(e) = fs::rename(&self.path, target) {
+            let _ = fs::remove_file(target);
+            return Err(e);
+        }
+        if let Some(directory) = target.parent() {
+            // the new directory entry is only durable once the directory itself is synced
+            File::open(directory)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Remove the temporary file
+    pub fn discard(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+///
+/// SHA-256 of the data up to its first gap, extended while the chunks arrive.
+///
+/// Verifying a finished transmission then needs no second pass over up to 1 GiB of data.
+///
+#[derive(Clone, Default)]
+pub struct PrefixDigest {
+    hasher: Sha256,
+    len: u64,
+}
+
+impl fmt::Debug for PrefixDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrefixDigest").field("len", &self.len).finish()
+    }
+}
+
+impl PrefixDigest {
+    /// Bytes at the start of the data that were hashed
+    pub fn hashed_len(&self) -> u64 {
+        self.len
+    }
+
+    /// Hash the data after the prefix up to `contiguous_len`, which must have been received without gaps
+    pub fn extend(&mut self, data: &mut DataSink, contiguous_len: u64) -> io::Result<()> {
+        if contiguous_len <= self.len {
+            return Ok(());
+        }
+        if let Err(e) = data.hash_range(&mut self.hasher, self.len..contiguous_len) {
+            // the hasher may have seen a part of the range
+            *self = PrefixDigest::default();
+            return Err(e);
+        }
+        self.len = contiguous_len;
+        Ok(())
+    }
+
+    pub fn digest(&self) -> Digest {
+        self.hasher.clone().finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod sink_tests {
+    use std::env;
+    use std::process;
+
+    use crate::message::file_digest;
+
+    use super::*;
+
+    fn test_directory(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("dns-sink-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut sink = DataSink::Memory(Vec::new());
+        sink.write_at(3, &[4, 5]).unwrap();
+        sink.write_at(0, &[1, 2, 3]).unwrap();
+        assert_eq!(5, sink.len());
+        assert_eq!(vec![1, 2, 3, 4, 5], sink.read_all().unwrap());
+        assert_eq!(file_digest(&[1, 2, 3, 4, 5]), sink.digest().unwrap());
+    }
+
+    #[test]
+    fn test_spool_file() {
+        let directory = test_directory("spool");
+        let spool_path = directory.join("1.part");
+        let mut sink = DataSink::File(SpoolFile::create(spool_path.clone()).unwrap());
+        assert!(SpoolFile::create(spool_path.clone()).is_err());
+        sink.write_at(3, &[4, 5]).unwrap();
+        assert_eq!(vec![0, 0, 0, 4, 5], fs::read(&spool_path).unwrap());
+        sink.write_at(0, &[1, 2, 3]).unwrap();
+        assert_eq!(5, sink.len());
+        assert_eq!(file_digest(&[1, 2, 3, 4, 5]), sink.digest().unwrap());
+
+        let target = directory.join("file");
+        fs::write(&target, b"existing").unwrap();
+        let file = match sink {
+            DataSink::File(file) => file,
+            DataSink::Memory(_) => unreachable!(),
+        };
+        // existing files are kept
+        assert_eq!(io::ErrorKind::AlreadyExists, file.persist(&target).unwrap_err().kind());
+        assert_eq!(b"existing", fs::read(&target).unwrap().as_slice());
+        assert!(spool_path.exists());
+
+        fs::remove_file(&target).unwrap();
+        file.persist(&target).unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5], fs::read(&target).unwrap());
+        assert!(!spool_path.exists());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_prefix_digest() {
+        let directory = test_directory("digest");
+        for mut sink in [DataSink::Memory(Vec::new()), DataSink::File(SpoolFile::create(directory.join("1.part")).unwrap())] {
+            let mut digest = PrefixDigest::default();
+            sink.write_at(0, &[1, 2]).unwrap();
+            digest.extend(&mut sink, 2).unwrap();
+            sink.write_at(3, &[4, 5]).unwrap();
+            digest.extend(&mut sink, 2).unwrap();
+            assert_eq!(2, digest.hashed_len());
+            sink.write_at(2, &[3]).unwrap();
+            digest.extend(&mut sink, 5).unwrap();
+            assert_eq!(5, digest.hashed_len());
+            assert_eq!(file_digest(&[1, 2, 3, 4, 5]), digest.digest());
+        }
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
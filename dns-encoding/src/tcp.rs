@@ -0,0 +1,72 @@
+use std::io;
+use std::io::{Read, Write};
+
+/// Largest dns message a frame can carry, its length is sent as 16 bit number
+pub const MAX_FRAME_LEN: usize = u16::MAX as usize;
+
+///
+/// Write a dns message prefixed with its length, as required for DNS over TCP (RFC 7766).
+///
+/// Length and message are written at once, so they end up in a single segment if possible.
+///
+pub fn write_frame<W: Write>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+    if message.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "dns message exceeds 65535 bytes"));
+    }
+    let mut frame = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    frame.extend_from_slice(message);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+///
+/// Read the next length prefixed dns message into buffer.
+///
+/// Returns `Ok(false)` if the connection was closed before the next frame.
+///
+pub fn read_frame<R: Read>(reader: &mut R, buffer: &mut Vec<u8>) -> io::Result<bool> {
+    let mut len = [0_u8; 2];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    buffer.resize(u16::from_be_bytes(len) as usize, 0);
+    reader.read_exact(buffer)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tcp_tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"query").unwrap();
+        write_frame(&mut stream, &[]).unwrap();
+        write_frame(&mut stream, &[7; MAX_FRAME_LEN]).unwrap();
+        assert_eq!(&[0, 5], &stream[..2]);
+        assert!(write_frame(&mut stream, &[7; MAX_FRAME_LEN + 1]).is_err());
+
+        let mut reader = Cursor::new(stream);
+        let mut buffer = Vec::new();
+        assert!(read_frame(&mut reader, &mut buffer).unwrap());
+        assert_eq!(b"query", buffer.as_slice());
+        assert!(read_frame(&mut reader, &mut buffer).unwrap());
+        assert!(buffer.is_empty());
+        assert!(read_frame(&mut reader, &mut buffer).unwrap());
+        assert_eq!(vec![7; MAX_FRAME_LEN], buffer);
+        assert!(!read_frame(&mut reader, &mut buffer).unwrap());
+    }
+
+    #[test]
+    fn test_incomplete_frame() {
+        let mut reader = Cursor::new(vec![0, 5, 1, 2]);
+        let mut buffer = Vec::new();
+        assert!(read_frame(&mut reader, &mut buffer).is_err());
+    }
+}
@@ -1,12 +1,16 @@
 #[cfg(test)]
 mod message_tests {
+    use trust_dns_proto::op::Query;
     use trust_dns_proto::rr::domain::Label;
-    use trust_dns_proto::rr::Name;
+    use trust_dns_proto::rr::{Name, RecordType};
     use trust_dns_proto::serialize::binary::{BinEncoder, BinEncodable, BinDecoder, BinDecodable};
 
-    use crate::decode::MessageDecoder;
-    use crate::encode::MessageEncoder;
-    use crate::message::Message;
+    use crate::capabilities::Capabilities;
+    use crate::codec::CodecKind;
+    use crate::decode::{MessageDecoder, MessageDecoderError};
+    use crate::edns;
+    use crate::encode::{MessageEncoder, MessageEncoderError};
+    use crate::message::{Id, Message, ResponseType, SessionId};
 
     fn messages_to_test() -> Vec<Message> {
         vec![
@@ -14,14 +18,21 @@ mod message_tests {
                 host: "database".to_string(),
                 file_name: "secrets.txt".to_string(),
                 rnd_nr: 1234,
+                version: 0,
+                capabilities: Capabilities::V0,
             },
-            Message::Data {
-                id: 2,
-                data: vec![1, 2, 3, 4, 5],
-            },
-            Message::Finish {
-                rnd_nr: 1234
-            }
+            Message::initial(
+                "database".to_string(),
+                "/var/lib/postgresql/data/a_file_name_that_needs_more_than_one_label.conf".to_string(),
+                1234,
+                Capabilities::CODEC_HEX.union(Capabilities::ENCRYPTION),
+            ),
+            Message::data(1234, 2, 0, vec![1, 2, 3, 4, 5]),
+            Message::data(1234, 3, 5, vec![]),
+            Message::data(1234, 4, u64::MAX, vec![6]),
+            Message::Data { rnd_nr: 1234, id: 5, offset: None, data: vec![7], checksum: crc32fast::hash(&[7]) },
+            Message::finish(1234, b"password=password123"),
+            Message::finish(u64::MAX, b"password=password123"),
         ]
     }
 
@@ -29,7 +40,7 @@ mod message_tests {
         let mut buffer: Vec<u8> = Vec::new();
         let mut bin_encoder = BinEncoder::new(&mut buffer);
         m.emit(&mut bin_encoder).unwrap();
-        let mut bin_decoder = BinDecoder::new(&mut buffer);
+        let mut bin_decoder = BinDecoder::new(&buffer);
         trust_dns_proto::op::Message::read(&mut bin_decoder).unwrap()
     }
 
@@ -37,17 +48,180 @@ mod message_tests {
     fn test_symmetric() {
         let label = Label::from_utf8("magic").unwrap();
         let subdomain = Name::from_utf8("extract.de.").unwrap();
-        let encoder = MessageEncoder::new(label.clone(), subdomain.clone());
+        let decoder = MessageDecoder::new(label.clone(), subdomain.clone());
+
+        for codec in &[CodecKind::Base32, CodecKind::Hex, CodecKind::Base36, CodecKind::Raw] {
+            let encoder = MessageEncoder::new(label.clone(), subdomain.clone(), *codec, ResponseType::Address);
+            for message in messages_to_test() {
+                // println!("message = {:?}", message);
+                let dns_message = encoder.encode(message.clone()).unwrap();
+                let dns_message = write_read(dns_message);
+                // println!("dns_message =  ${:?}", dns_message);
+                let message2 = decoder.decode(&dns_message).unwrap();
+                // println!("message2 = {:?}", message2);
+                assert_eq!(message, message2);
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_payload_per_query() {
+        let label = Label::from_utf8("8k1").unwrap();
+        let subdomain = Name::from_utf8("extract.de.").unwrap();
+        let decoder = MessageDecoder::new(label.clone(), subdomain.clone());
+
+        for codec in &[CodecKind::Base32, CodecKind::Hex, CodecKind::Base36, CodecKind::Raw] {
+            let encoder = MessageEncoder::new(label.clone(), subdomain.clone(), *codec, ResponseType::Address);
+            let max_payload = encoder.max_payload_per_query();
+            assert!(max_payload > 85, "codec {:?} only fits {} bytes", codec, max_payload);
+
+            let message = Message::data(SessionId::MAX, Id::MAX, u64::MAX, vec![0xff; max_payload]);
+            let dns_message = write_read(encoder.encode(message.clone()).unwrap());
+            assert_eq!(message, decoder.decode(&dns_message).unwrap());
+
+            // one more byte exceeds the maximum name length
+            let message = Message::data(SessionId::MAX, Id::MAX, u64::MAX, vec![0xff; max_payload + 1]);
+            match encoder.encode(message) {
+                Err(MessageEncoderError::NameTooLong { len }) => assert!(len > 255),
+                other => panic!("Expected NameTooLong, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_rewritten_transaction_id() {
+        let label = Label::from_utf8("magic").unwrap();
+        let subdomain = Name::from_utf8("extract.de.").unwrap();
+        let encoder = MessageEncoder::new(label.clone(), subdomain.clone(), CodecKind::Base32, ResponseType::Txt);
         let decoder = MessageDecoder::new(label, subdomain);
 
         for message in messages_to_test() {
-            // println!("message = {:?}", message);
-            let dns_message = encoder.encode(message.clone());
+            // a recursive resolver picks its own transaction id
+            let mut dns_message = encoder.encode(message.clone()).unwrap();
+            dns_message.set_id(dns_message.id().wrapping_add(4711));
             let dns_message = write_read(dns_message);
-            // println!("dns_message =  ${:?}", dns_message);
-            let message2 = decoder.decode(&dns_message).unwrap();
-            // println!("message2 = {:?}", message2);
-            assert_eq!(message, message2);
+            assert_eq!(message, decoder.decode(&dns_message).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_edns() {
+        let label = Label::from_utf8("magic").unwrap();
+        let subdomain = Name::from_utf8("extract.de.").unwrap();
+        let mut encoder = MessageEncoder::new(label.clone(), subdomain.clone(), CodecKind::Base32, ResponseType::Txt);
+        let decoder = MessageDecoder::new(label, subdomain);
+        let message = Message::data(1234, 2, 0, vec![1, 2, 3]);
+
+        let dns_message = write_read(encoder.encode(message.clone()).unwrap());
+        let edns = dns_message.edns().expect("Query without OPT record");
+        assert_eq!(edns::DEFAULT_PAYLOAD_SIZE, edns.max_payload());
+        assert_eq!(edns::VERSION, edns.version());
+        assert_eq!(message, decoder.decode(&dns_message).unwrap());
+
+        encoder.set_edns_payload_size(None);
+        let dns_message = write_read(encoder.encode(message.clone()).unwrap());
+        assert!(dns_message.edns().is_none());
+        assert!(dns_message.additionals().is_empty());
+        assert_eq!(message, decoder.decode(&dns_message).unwrap());
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let label = Label::from_utf8("magic").unwrap();
+        let subdomain = Name::from_utf8("extract.de.").unwrap();
+        let encoder = MessageEncoder::new(label.clone(), subdomain.clone(), CodecKind::Base32, ResponseType::Address);
+        let decoder = MessageDecoder::new(label, subdomain);
+        let message = Message::Announcement {
+            host: "database".to_string(),
+            file_name: "secrets.txt".to_string(),
+            rnd_nr: 1234,
+            version: crate::capabilities::PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::supported(),
+        };
+        match decoder.decode(&write_read(encoder.encode(message).unwrap())) {
+            Err(MessageDecoderError::UnsupportedVersion { version: 3 }) => {}
+            other => panic!("Expected UnsupportedVersion, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_used_capabilities() {
+        let label = Label::from_utf8("magic").unwrap();
+        let subdomain = Name::from_utf8("extract.de.").unwrap();
+        let encoder = MessageEncoder::new(label.clone(), subdomain.clone(), CodecKind::Hex, ResponseType::Txt);
+        let decoder = MessageDecoder::new(label, subdomain);
+        let dns_message = write_read(encoder.encode(Message::data(1234, 2, 0, vec![1, 2, 3])).unwrap());
+        let (_, capabilities) = decoder.decode_with_capabilities(&dns_message).unwrap();
+        assert_eq!(Capabilities::CODEC_BASE32.union(Capabilities::CODEC_HEX).union(Capabilities::RESPONSE_TXT), capabilities);
+
+        let dns_message = write_read(encoder.encode(Message::finish(1234, &[1, 2, 3])).unwrap());
+        let (_, capabilities) = decoder.decode_with_capabilities(&dns_message).unwrap();
+        assert_eq!(Capabilities::CODEC_BASE32.union(Capabilities::RESPONSE_TXT), capabilities);
+    }
+
+    #[test]
+    fn test_host_label_too_long() {
+        let label = Label::from_utf8("magic").unwrap();
+        let subdomain = Name::from_utf8("extract.de.").unwrap();
+        let encoder = MessageEncoder::new(label, subdomain, CodecKind::Base32, ResponseType::Address);
+        let message = Message::initial("h".repeat(64), "passwords.txt".to_string(), 1234, Capabilities::supported());
+        match encoder.encode(message) {
+            Err(MessageEncoderError::InvalidLabel(_)) => {}
+            other => panic!("Expected InvalidLabel, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_chunk_label() {
+        let label = Label::from_utf8("magic").unwrap();
+        let subdomain = Name::from_utf8("extract.de.").unwrap();
+        let decoder = MessageDecoder::new(label, subdomain);
+        for chunk_label in &["deadbeef-xyz", "deadbeef-", "deadbeef0-1", "xyz-1"] {
+            let name = Name::from_utf8(format!("magic.db-4d2-2.{}.ae.extract.de.", chunk_label)).unwrap();
+            let mut dns_message = trust_dns_proto::op::Message::new();
+            dns_message.add_query(Query::query(name, RecordType::A));
+            match decoder.decode(&write_read(dns_message)) {
+                Err(MessageDecoderError::InvalidOffset) | Err(MessageDecoderError::InvalidChecksum) => {}
+                other => panic!("Expected an invalid chunk label for {}, got {:?}", chunk_label, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_header_label() {
+        let label = Label::from_utf8("magic").unwrap();
+        let subdomain = Name::from_utf8("extract.de.").unwrap();
+        let encoder = MessageEncoder::new(label.clone(), subdomain.clone(), CodecKind::Base32, ResponseType::Address);
+        let decoder = MessageDecoder::new(label, subdomain);
+
+        // type, session and sequence are carried in the label after the magic nr
+        let dns_message = encoder.encode(Message::data(0x4d2, 0x2a, 0, vec![1])).unwrap();
+        assert_eq!("db-4d2-2a", dns_message.queries()[0].name()[1].to_ascii());
+        assert_eq!(Message::data(0x4d2, 0x2a, 0, vec![1]), decoder.decode(&write_read(dns_message)).unwrap());
+
+        for header in &["db-4d2", "db-4d2-2-3", "db-xyz-2", "dq-4d2-2", "4d2-2"] {
+            let name = Name::from_utf8(format!("magic.{}.a0c7d2b2-0.ae.extract.de.", header)).unwrap();
+            let mut dns_message = trust_dns_proto::op::Message::new();
+            dns_message.add_query(Query::query(name, RecordType::A));
+            match decoder.decode(&write_read(dns_message)) {
+                Err(MessageDecoderError::InvalidHeader) => {}
+                other => panic!("Expected InvalidHeader for {}, got {:?}", header, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_file_name_not_utf8() {
+        let label = Label::from_utf8("magic").unwrap();
+        let subdomain = Name::from_utf8("extract.de.").unwrap();
+        let decoder = MessageDecoder::new(label.clone(), subdomain.clone());
+        let file_name = String::from_utf8(CodecKind::Base32.codec().encode(&[0xff, 0xfe])).unwrap();
+        let name = Name::from_utf8(format!("magic.a-4d2-1.db.1f.{}.extract.de.", file_name)).unwrap();
+        let mut dns_message = trust_dns_proto::op::Message::new();
+        dns_message.add_query(Query::query(name, RecordType::A));
+        match decoder.decode(&write_read(dns_message)) {
+            Err(MessageDecoderError::InvalidFileName) => {}
+            other => panic!("Expected InvalidFileName, got {:?}", other),
         }
     }
 }
@@ -55,19 +229,36 @@ mod message_tests {
 
 #[cfg(test)]
 mod message_response_tests {
+    use std::net::Ipv4Addr;
     use std::str::FromStr;
 
-    use trust_dns_proto::rr::{Name, RData, Record};
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+    use trust_dns_proto::rr::rdata::TXT;
+
+    use crate::capabilities::Capabilities;
+    use crate::message::{DataResponse, ErrorCode, FinishResponse, Id, MessageResponse, ServerParameters};
 
-    use crate::message::{DataResponse, FinishResponse, MessageResponse};
+    const PARAMETERS: ServerParameters = ServerParameters { version: 2, max_window_size: 32, max_file_size: 1 << 30 };
 
     fn messages_to_test() -> Vec<MessageResponse> {
         vec![
-            MessageResponse::Announcement { rnd_nr: 1234, next_id: 42 },
+            MessageResponse::Announcement { rnd_nr: 1234, next_id: 42, capabilities: Capabilities::supported(), parameters: None },
+            MessageResponse::Announcement { rnd_nr: 1234, next_id: 42, capabilities: Capabilities::supported(), parameters: Some(PARAMETERS) },
             MessageResponse::Data { response: DataResponse::Resend },
             MessageResponse::Data { response: DataResponse::Acknowledge { next_id: 43 } },
-            MessageResponse::Finish { response: FinishResponse::Resend },
-            MessageResponse::Finish { response: FinishResponse::Acknowledge { rnd_nr: 1234 } }
+            MessageResponse::Data { response: DataResponse::SelectiveAcknowledge { next_id: 44, received: 0b1011 } },
+            MessageResponse::Data { response: DataResponse::Acknowledge { next_id: u16::MAX as Id } },
+            MessageResponse::Data { response: DataResponse::Acknowledge { next_id: u16::MAX as Id + 1 } },
+            MessageResponse::Data { response: DataResponse::Acknowledge { next_id: Id::MAX } },
+            MessageResponse::Data { response: DataResponse::SelectiveAcknowledge { next_id: Id::MAX, received: u32::MAX } },
+            MessageResponse::Finish { response: FinishResponse::Resend { next_id: 5, missing: vec![] } },
+            MessageResponse::Finish { response: FinishResponse::Resend { next_id: Id::MAX, missing: vec![0..3, 1 << 29..1 << 30] } },
+            MessageResponse::Finish { response: FinishResponse::Acknowledge { rnd_nr: 1234 } },
+            MessageResponse::Finish { response: FinishResponse::Failed { rnd_nr: 1234 } },
+            MessageResponse::Announcement { rnd_nr: u64::MAX, next_id: 42, capabilities: Capabilities::supported(), parameters: None },
+            MessageResponse::Error { code: ErrorCode::UnknownId },
+            MessageResponse::Error { code: ErrorCode::Unknown(200) },
+            MessageResponse::Finish { response: FinishResponse::Acknowledge { rnd_nr: u64::MAX } },
         ]
     }
 
@@ -82,10 +273,103 @@ mod message_response_tests {
     #[test]
     fn test_symmetric() {
         for message in messages_to_test() {
-            let r_data = message.clone().encode();
+            let r_data = message.clone().encode().unwrap();
+            let dns_message = create_dns_message(r_data);
+            let message2 = MessageResponse::decode(&dns_message);
+            assert_eq!(message, message2.unwrap());
+        }
+    }
+
+    #[test]
+    fn test_symmetric_txt() {
+        for message in messages_to_test() {
+            let r_data = message.clone().encode_for(RecordType::TXT).unwrap();
+            assert_eq!(RecordType::TXT, r_data.to_record_type());
             let dns_message = create_dns_message(r_data);
             let message2 = MessageResponse::decode(&dns_message);
             assert_eq!(message, message2.unwrap());
         }
     }
+
+    #[test]
+    fn test_v0_announcement_response() {
+        // servers of version 0 send neither a capabilities label nor field
+        let expected = MessageResponse::Announcement { rnd_nr: 1234, next_id: 42, capabilities: Capabilities::V0, parameters: None };
+        let cname = RData::CNAME(Name::from_str("a.1234.42").unwrap());
+        assert_eq!(expected, MessageResponse::decode(&create_dns_message(cname)).unwrap());
+        let txt = RData::TXT(TXT::new(vec!["t=a".to_string(), "r=1234".to_string(), "n=42".to_string()]));
+        assert_eq!(expected, MessageResponse::decode(&create_dns_message(txt)).unwrap());
+    }
+
+    #[test]
+    fn test_txt_ignores_unknown_fields() {
+        let txt = TXT::new(vec!["t=d".to_string(), "x=future".to_string(), "s=a".to_string(), "n=43".to_string()]);
+        let dns_message = create_dns_message(RData::TXT(txt));
+        let expected = MessageResponse::Data { response: DataResponse::Acknowledge { next_id: 43 } };
+        assert_eq!(expected, MessageResponse::decode(&dns_message).unwrap());
+    }
+
+    #[test]
+    fn test_txt_fields() {
+        let txt = |response: MessageResponse| match response.encode_txt() {
+            RData::TXT(txt) => txt.iter().map(|s| String::from_utf8(s.to_vec()).unwrap()).collect::<Vec<_>>(),
+            _ => unreachable!(),
+        };
+        let announcement = MessageResponse::Announcement { rnd_nr: 1234, next_id: 42, capabilities: Capabilities::V0, parameters: Some(PARAMETERS) };
+        assert_eq!(vec!["t=a", "r=1234", "n=42", "c=31", "v=2", "w=32", "m=1073741824"], txt(announcement));
+        // chunks 45, 46, 47 and 49 arrived
+        let acknowledgement = MessageResponse::Data { response: DataResponse::SelectiveAcknowledge { next_id: 44, received: 0b10111 } };
+        assert_eq!(vec!["t=d", "s=w", "n=44", "k=0..3,4..5"], txt(acknowledgement));
+        let acknowledgement = MessageResponse::Data { response: DataResponse::SelectiveAcknowledge { next_id: 44, received: 0 } };
+        assert_eq!(vec!["t=d", "s=w", "n=44", "k="], txt(acknowledgement));
+        assert_eq!(vec!["t=e", "e=3"], txt(MessageResponse::Error { code: ErrorCode::SessionInUse }));
+
+        // ranges beyond the window can not be acknowledged
+        let txt = TXT::new(vec!["t=d".to_string(), "s=w".to_string(), "n=44".to_string(), "k=30..33".to_string()]);
+        assert!(MessageResponse::decode(&create_dns_message(RData::TXT(txt))).is_err());
+    }
+
+    #[test]
+    fn test_skips_unrelated_answers() {
+        let expected = MessageResponse::Announcement { rnd_nr: 1234, next_id: 42, capabilities: Capabilities::CODEC_HEX, parameters: None };
+        let mut dns_message = create_dns_message(RData::A(Ipv4Addr::new(127, 0, 0, 1)));
+        let name = Name::from_str("test.de").unwrap();
+        dns_message.add_answer(Record::from_rdata(name, 120, expected.clone().encode_txt()));
+        assert_eq!(expected, MessageResponse::decode(&dns_message).unwrap());
+    }
+
+    #[test]
+    fn test_resend_fits() {
+        let wide_gaps: Vec<_> = (0..20).map(|k| u64::MAX - 2 * k - 1..u64::MAX - 2 * k).rev().collect();
+        let response = FinishResponse::resend(Id::MAX, wide_gaps.clone());
+        let listed = match &response {
+            FinishResponse::Resend { missing, .. } => missing.len(),
+            _ => panic!("Expected a resend"),
+        };
+        assert!(listed > 0 && listed < wide_gaps.len());
+        assert_eq!(FinishResponse::Resend { next_id: Id::MAX, missing: wide_gaps[..listed].to_vec() }, response);
+
+        let message = MessageResponse::Finish { response };
+        match message.clone().encode().unwrap() {
+            // every label is prefixed by its length, plus the root
+            RData::CNAME(name) => assert!(name.iter().map(|l| 1 + l.len()).sum::<usize>() < 255),
+            other => panic!("Expected a CNAME, got {:?}", other),
+        }
+        match message.clone().encode_for(RecordType::TXT).unwrap() {
+            RData::TXT(txt) => assert!(txt.iter().all(|string| string.len() <= 255)),
+            other => panic!("Expected a TXT record, got {:?}", other),
+        }
+        for record_type in [RecordType::A, RecordType::TXT] {
+            let dns_message = create_dns_message(message.clone().encode_for(record_type).unwrap());
+            assert_eq!(message, MessageResponse::decode(&dns_message).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_short_cnames() {
+        for cname in &["a", "a.1234", "d", "d.x", "f", "f.a", "f.x", "f.r", "f.r.5.3", "x.1"] {
+            let r_data = RData::CNAME(Name::from_str(cname).unwrap());
+            assert!(MessageResponse::decode(&create_dns_message(r_data)).is_err(), "cname {}", cname);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,267 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::tcp;
+
+/// Largest response that is sent over UDP, larger ones are truncated so the client retries over TCP (RFC 1035)
+pub const MAX_UDP_LEN: usize = 512;
+
+///
+/// Carries encoded dns messages between client and server.
+///
+/// A transport is a conversation with one peer at a time: the client talks to its resolver,
+/// the server answers the peer of the message it received last.
+///
+pub trait Transport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()>;
+
+    ///
+    /// Wait up to timeout for the next message and store it in buffer.
+    ///
+    /// Fails with `TimedOut` or `WouldBlock` if nothing arrived, and with `UnexpectedEof` once the peer closed the transport.
+    ///
+    fn receive(&mut self, buffer: &mut Vec<u8>, timeout: Duration) -> io::Result<()>;
+
+    /// Largest message the transport can carry
+    fn max_message_len(&self) -> usize {
+        tcp::MAX_FRAME_LEN
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        (**self).send(message)
+    }
+
+    fn receive(&mut self, buffer: &mut Vec<u8>, timeout: Duration) -> io::Result<()> {
+        (**self).receive(buffer, timeout)
+    }
+
+    fn max_message_len(&self) -> usize {
+        (**self).max_message_len()
+    }
+}
+
+/// Errors after which the peer may still answer, e.g. a lost datagram or an unreachable resolver
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe | io::ErrorKind::NotConnected | io::ErrorKind::UnexpectedEof)
+}
+
+///
+/// Datagrams, a connected socket talks to its resolver, a bound one answers the sender of the last datagram.
+///
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peer: Option<SocketAddr>,
+}
+
+impl UdpTransport {
+    pub fn connect<A: ToSocketAddrs>(local: A, resolver: SocketAddr) -> io::Result<UdpTransport> {
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(resolver)?;
+        Ok(UdpTransport { socket, peer: Some(resolver) })
+    }
+
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<UdpTransport> {
+        Ok(UdpTransport { socket: UdpSocket::bind(address)?, peer: None })
+    }
+
+    /// The resolver, or the sender of the last datagram
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let peer = self.peer.ok_or(io::ErrorKind::NotConnected)?;
+        self.socket.send_to(message, peer)?;
+        Ok(())
+    }
+
+    fn receive(&mut self, buffer: &mut Vec<u8>, timeout: Duration) -> io::Result<()> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        buffer.resize(tcp::MAX_FRAME_LEN, 0);
+        let (bytes_read, source) = self.socket.recv_from(buffer)?;
+        buffer.truncate(bytes_read);
+        self.peer = Some(source);
+        Ok(())
+    }
+
+    fn max_message_len(&self) -> usize {
+        MAX_UDP_LEN
+    }
+}
+
+///
+/// Length prefixed messages over a TCP connection (RFC 7766).
+///
+/// Clients connect on demand and drop the connection after an error or timeout,
+/// since a timeout in the middle of a message leaves the stream in an unknown state.
+/// Like a lost datagram, the messages are sent again after the next timeout.
+///
+pub struct TcpTransport {
+    address: SocketAddr,
+    stream: Option<TcpStream>,
+    connect_timeout: Duration,
+}
+
+impl TcpTransport {
+    pub fn connect(address: SocketAddr) -> TcpTransport {
+        TcpTransport { address, stream: None, connect_timeout: Duration::from_secs(1) }
+    }
+
+    /// Transport for a connection accepted by the server
+    pub fn from_stream(stream: TcpStream) -> io::Result<TcpTransport> {
+        let address = stream.peer_addr()?;
+        Ok(TcpTransport { address, stream: Some(stream), connect_timeout: Duration::from_secs(1) })
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.stream = Some(TcpStream::connect_timeout(&self.address, self.connect_timeout)?);
+        }
+        let result = match &mut self.stream {
+            Some(stream) => tcp::write_frame(stream, message),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        };
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn receive(&mut self, buffer: &mut Vec<u8>, timeout: Duration) -> io::Result<()> {
+        self.connect_timeout = timeout;
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => {
+                // nothing was sent, so nothing can arrive
+                thread::sleep(timeout);
+                return Err(io::ErrorKind::NotConnected.into());
+            }
+        };
+        stream.set_read_timeout(Some(timeout))?;
+        let result = match tcp::read_frame(stream, buffer) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+}
+
+///
+/// In memory transport, e.g. to run client and server in one process.
+///
+/// Every message is delivered, unless the peer was dropped.
+///
+pub struct ChannelTransport {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl ChannelTransport {
+    /// Two transports connected with each other
+    pub fn pair() -> (ChannelTransport, ChannelTransport) {
+        let (sender_a, receiver_b) = channel();
+        let (sender_b, receiver_a) = channel();
+        (ChannelTransport { sender: sender_a, receiver: receiver_a }, ChannelTransport { sender: sender_b, receiver: receiver_b })
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.sender.send(message.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn receive(&mut self, buffer: &mut Vec<u8>, timeout: Duration) -> io::Result<()> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(message) => {
+                *buffer = message;
+                Ok(())
+            }
+            Err(RecvTimeoutError::Timeout) => Err(io::ErrorKind::TimedOut.into()),
+            Err(RecvTimeoutError::Disconnected) => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_channel() {
+        let (mut client, mut server) = ChannelTransport::pair();
+        let mut buffer = Vec::new();
+        client.send(b"query").unwrap();
+        server.receive(&mut buffer, Duration::from_millis(10)).unwrap();
+        assert_eq!(b"query", buffer.as_slice());
+
+        let error = client.receive(&mut buffer, Duration::from_millis(10)).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+        drop(server);
+        let error = client.receive(&mut buffer, Duration::from_millis(10)).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, error.kind());
+    }
+
+    #[test]
+    fn test_udp() {
+        let mut server = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let address = server.socket.local_addr().unwrap();
+        let mut client = UdpTransport::connect("127.0.0.1:0", address).unwrap();
+        let mut buffer = Vec::new();
+
+        // the server can not answer before it received something
+        assert!(server.send(b"response").is_err());
+        client.send(b"query").unwrap();
+        server.receive(&mut buffer, Duration::from_secs(1)).unwrap();
+        assert_eq!(b"query", buffer.as_slice());
+        server.send(b"response").unwrap();
+        client.receive(&mut buffer, Duration::from_secs(1)).unwrap();
+        assert_eq!(b"response", buffer.as_slice());
+    }
+
+    #[test]
+    fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpTransport::connect(listener.local_addr().unwrap());
+        let mut buffer = Vec::new();
+
+        // the connection is opened by the first message
+        let error = client.receive(&mut buffer, Duration::from_millis(10)).unwrap_err();
+        assert!(is_transient(&error));
+        client.send(b"query").unwrap();
+        let mut server = TcpTransport::from_stream(listener.accept().unwrap().0).unwrap();
+        server.receive(&mut buffer, Duration::from_secs(1)).unwrap();
+        assert_eq!(b"query", buffer.as_slice());
+        server.send(b"response").unwrap();
+        client.receive(&mut buffer, Duration::from_secs(1)).unwrap();
+        assert_eq!(b"response", buffer.as_slice());
+
+        drop(server);
+        let error = client.receive(&mut buffer, Duration::from_secs(1)).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, error.kind());
+    }
+}
@@ -0,0 +1,234 @@
+use std::net::IpAddr;
+
+use trust_dns_proto::op::Query;
+use trust_dns_proto::rr::rdata::SOA;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+/// TTL of the zone records
+pub const DEFAULT_TTL: u32 = 3600;
+
+/// How long resolvers may cache that a name does not exist (RFC 2308)
+pub const NEGATIVE_TTL: u32 = 60;
+
+///
+/// The delegated sub domain, served like any other authoritative zone.
+///
+/// Resolvers ask for the SOA and NS records of the zone, and for names that are no protocol messages,
+/// e.g. while they validate the delegation. Without answers they would consider the server broken.
+///
+/// Every name below the origin may lead to protocol messages, e.g. a resolver that minimises the query name
+/// (RFC 7816) asks for `<last payload label>.<origin>` before the full name. So the zone answers no name
+/// with NXDOMAIN, which tells the resolver that nothing exists below it (RFC 8020). The only NXDOMAIN answers
+/// are those of the driver to rejected messages, and their SOA has a TTL of 0, so resolvers do not cache them.
+///
+#[derive(Debug, Clone)]
+pub struct Zone {
+    origin: Name,
+    name_servers: Vec<Name>,
+    hostmaster: Name,
+    serial: u32,
+    /** Addresses of names in the zone, e.g. of the name servers */
+    addresses: Vec<(Name, IpAddr)>,
+    ttl: u32,
+}
+
+///
+/// The answer of the zone to a query.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lookup {
+    /** Empty if the name has no records of the queried type */
+    pub answers: Vec<Record>,
+    /** The SOA record for negative answers, the NS records otherwise */
+    pub authority: Vec<Record>,
+    /** Addresses of the name servers in the answer */
+    pub additionals: Vec<Record>,
+}
+
+impl Zone {
+    ///
+    /// Zone for origin, the primary name server comes first.
+    ///
+    /// The hostmaster is the mailbox of the responsible person, with the `@` replaced by a dot.
+    ///
+    pub fn new(origin: Name, name_servers: Vec<Name>, hostmaster: Name) -> Zone {
+        Zone {
+            origin: fqdn(origin),
+            name_servers: name_servers.into_iter().map(fqdn).collect(),
+            hostmaster: fqdn(hostmaster),
+            serial: 1,
+            addresses: Vec::new(),
+            ttl: DEFAULT_TTL,
+        }
+    }
+
+    pub fn set_serial(&mut self, serial: u32) {
+        self.serial = serial;
+    }
+
+    pub fn set_ttl(&mut self, ttl: u32) {
+        self.ttl = ttl;
+    }
+
+    /// Answer A or AAAA queries for name with address, names outside the zone are ignored
+    pub fn add_address(&mut self, name: Name, address: IpAddr) {
+        let name = fqdn(name);
+        if self.origin.zone_of(&name) {
+            self.addresses.push((name, address));
+        }
+    }
+
+    pub fn origin(&self) -> &Name {
+        &self.origin
+    }
+
+    /// Whether the zone is responsible for name
+    pub fn contains(&self, name: &Name) -> bool {
+        self.origin.zone_of(name)
+    }
+
+    pub fn soa(&self) -> Record {
+        let primary = self.name_servers.first().cloned().unwrap_or_else(|| self.origin.clone());
+        let soa = SOA::new(primary, self.hostmaster.clone(), self.serial, 3600, 600, 86400, NEGATIVE_TTL);
+        Record::from_rdata(self.origin.clone(), self.ttl, RData::SOA(soa))
+    }
+
+    fn name_server_records(&self) -> Vec<Record> {
+        self.name_servers.iter()
+            .map(|name_server| Record::from_rdata(self.origin.clone(), self.ttl, RData::NS(name_server.clone())))
+            .collect()
+    }
+
+    /// All records of a name, empty if it has none
+    fn records(&self, name: &Name) -> Vec<Record> {
+        let mut records = Vec::new();
+        if *name == self.origin {
+            records.push(self.soa());
+            records.extend(self.name_server_records());
+        }
+        for (_, address) in self.addresses.iter().filter(|(address_name, _)| address_name == name) {
+            let rdata = match address {
+                IpAddr::V4(address) => RData::A(*address),
+                IpAddr::V6(address) => RData::AAAA(*address),
+            };
+            records.push(Record::from_rdata(name.clone(), self.ttl, rdata));
+        }
+        records
+    }
+
+    /// Addresses of the name servers among records
+    fn glue(&self, records: &[Record]) -> Vec<Record> {
+        records.iter()
+            .filter_map(|record| match record.rdata() {
+                RData::NS(name_server) => Some(self.records(name_server)),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    ///
+    /// Answer a query for a name in the zone, `None` if the zone is not responsible for it.
+    ///
+    /// Names without records of the queried type, including names without any records, are answered
+    /// with an empty NOERROR response (NODATA). It carries the SOA record, so resolvers know how long to cache it.
+    ///
+    pub fn lookup(&self, query: &Query) -> Option<Lookup> {
+        let name = query.name();
+        if !self.contains(name) {
+            return None;
+        }
+        let answers: Vec<Record> = self.records(name).into_iter()
+            .filter(|record| query.query_type() == RecordType::ANY || record.record_type() == query.query_type())
+            .collect();
+        if answers.is_empty() {
+            return Some(self.no_data());
+        }
+        let authority = if query.query_type() == RecordType::NS { Vec::new() } else { self.name_server_records() };
+        let additionals = self.glue(&answers);
+        Some(Lookup { answers, authority, additionals })
+    }
+
+    /// SOA record for the authority section of negative answers
+    pub fn negative_soa(&self) -> Record {
+        let mut soa = self.soa();
+        // negative answers are cached for the smaller of SOA TTL and minimum (RFC 2308)
+        soa.set_ttl(self.ttl.min(NEGATIVE_TTL));
+        soa
+    }
+
+    fn no_data(&self) -> Lookup {
+        Lookup { answers: Vec::new(), authority: vec![self.negative_soa()], additionals: Vec::new() }
+    }
+}
+
+fn fqdn(mut name: Name) -> Name {
+    name.set_fqdn(true);
+    name
+}
+
+#[cfg(test)]
+mod zone_tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn name(name: &str) -> Name {
+        Name::from_ascii(name).unwrap()
+    }
+
+    fn zone() -> Zone {
+        let mut zone = Zone::new(name("extract.de"), vec![name("ns1.extract.de"), name("ns.example.com")], name("hostmaster.extract.de"));
+        zone.add_address(name("ns1.extract.de"), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        zone.add_address(name("ns.example.com"), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)));
+        zone
+    }
+
+    #[test]
+    fn test_soa() {
+        let lookup = zone().lookup(&Query::query(name("extract.de."), RecordType::SOA)).unwrap();
+        assert_eq!(1, lookup.answers.len());
+        match lookup.answers[0].rdata() {
+            RData::SOA(soa) => {
+                assert_eq!(&name("ns1.extract.de."), soa.mname());
+                assert_eq!(&name("hostmaster.extract.de."), soa.rname());
+            }
+            rdata => panic!("Expected SOA, got {:?}", rdata),
+        }
+    }
+
+    #[test]
+    fn test_ns() {
+        let lookup = zone().lookup(&Query::query(name("EXTRACT.de."), RecordType::NS)).unwrap();
+        assert_eq!(vec![RData::NS(name("ns1.extract.de.")), RData::NS(name("ns.example.com."))],
+                   lookup.answers.iter().map(|r| r.rdata().clone()).collect::<Vec<_>>());
+        // only addresses within the zone are served
+        assert_eq!(vec![RData::A(Ipv4Addr::new(192, 0, 2, 1))],
+                   lookup.additionals.iter().map(|r| r.rdata().clone()).collect::<Vec<_>>());
+
+        let lookup = zone().lookup(&Query::query(name("ns1.extract.de."), RecordType::A)).unwrap();
+        assert_eq!(vec![RData::A(Ipv4Addr::new(192, 0, 2, 1))],
+                   lookup.answers.iter().map(|r| r.rdata().clone()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_negative_answers() {
+        let zone = zone();
+        let lookup = zone.lookup(&Query::query(name("extract.de."), RecordType::MX)).unwrap();
+        assert!(lookup.answers.is_empty());
+        assert_eq!(RecordType::SOA, lookup.authority[0].record_type());
+        assert_eq!(NEGATIVE_TTL, lookup.authority[0].ttl());
+
+        // names without records may be above protocol messages, so they exist
+        for query_name in &["www.extract.de.", "_.extract.de.", "8k1.extract.de.", "ae.db-4d2-1.extract.de."] {
+            for record_type in &[RecordType::A, RecordType::NS, RecordType::TXT] {
+                let lookup = zone.lookup(&Query::query(name(query_name), *record_type)).unwrap();
+                assert!(lookup.answers.is_empty());
+                assert_eq!(vec![zone.negative_soa()], lookup.authority);
+            }
+        }
+
+        assert_eq!(None, zone.lookup(&Query::query(name("example.com."), RecordType::A)));
+        assert_eq!(None, zone.lookup(&Query::query(name("de."), RecordType::NS)));
+    }
+}
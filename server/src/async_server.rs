@@ -0,0 +1,244 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::task;
+use tokio::time::{timeout, Instant};
+
+use dns_encoding::driver::{ServerDriver, Storage};
+use dns_encoding::transport::MAX_UDP_LEN;
+
+/// Datagrams answered at once, further ones are dropped like on a congested link until an answer is sent
+const MAX_PENDING_QUERIES: usize = 256;
+
+///
+/// Serve UDP and TCP from an event loop, instead of one blocking loop per transport.
+///
+/// Every query is answered by its own task on the blocking thread pool, so slow answers,
+/// e.g. a finished file that is written to disk, do not hold up the queries of other clients.
+/// All tasks share the server state, it only keeps the books, chunks are written under the lock of their transmission.
+/// At most `max_connections` TCP connections are served, further ones are closed right away.
+///
+pub fn run<S>(server: Arc<ServerDriver<S>>, udp_socket: std::net::UdpSocket, listener: std::net::TcpListener,
+              tcp_idle_timeout: Duration, max_connections: usize) -> io::Result<()>
+    where S: Storage + Send + Sync + 'static {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async move {
+        // tokio expects sockets that never block the event loop
+        udp_socket.set_nonblocking(true)?;
+        listener.set_nonblocking(true)?;
+        let socket = Arc::new(UdpSocket::from_std(udp_socket)?);
+        let listener = TcpListener::from_std(listener)?;
+
+        tokio::spawn(evict_idle(Arc::clone(&server)));
+        tokio::spawn(accept_connections(Arc::clone(&server), listener, tcp_idle_timeout, max_connections));
+        receive_datagrams(server, socket).await;
+        Ok(())
+    })
+}
+
+/// Answer a query on the blocking thread pool, max_len is the limit of the transport without EDNS
+async fn answer<S>(server: Arc<ServerDriver<S>>, query: Vec<u8>, max_len: usize) -> Option<Vec<u8>>
+    where S: Storage + Send + Sync + 'static {
+    let result = task::spawn_blocking(move || server.answer(&query, max_len)).await;
+    match result {
+        Ok(Ok(response)) => Some(response),
+        Ok(Err(e)) => {
+            warn!("Failed to answer query: {}", e);
+            None
+        }
+        Err(e) => {
+            error!("Answering a query failed, error: {}", e);
+            None
+        }
+    }
+}
+
+async fn receive_datagrams<S>(server: Arc<ServerDriver<S>>, socket: Arc<UdpSocket>)
+    where S: Storage + Send + Sync + 'static {
+    let pending_queries = Arc::new(Semaphore::new(MAX_PENDING_QUERIES));
+    let mut buffer = vec![0_u8; u16::MAX as usize];
+    loop {
+        let (bytes_read, source) = match socket.recv_from(&mut buffer).await {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Failed to receive message, error: {:?}", e);
+                continue;
+            }
+        };
+        debug!("Received from {}: Message with {} bytes", source, bytes_read);
+        let permit = match Arc::clone(&pending_queries).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("Dropping query from {}, already answering {} queries", source, MAX_PENDING_QUERIES);
+                continue;
+            }
+        };
+        let query = buffer[..bytes_read].to_vec();
+        let server = Arc::clone(&server);
+        let socket = Arc::clone(&socket);
+        tokio::spawn(async move {
+            if let Some(response) = answer(server, query, MAX_UDP_LEN).await {
+                if let Err(e) = socket.send_to(&response, source).await {
+                    error!("Failed to send response, error: {}", e);
+                }
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn accept_connections<S>(server: Arc<ServerDriver<S>>, listener: TcpListener, idle_timeout: Duration, max_connections: usize)
+    where S: Storage + Send + Sync + 'static {
+    let connections = Arc::new(Semaphore::new(max_connections));
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Failed to accept connection, error: {}", e);
+                continue;
+            }
+        };
+        let permit = match Arc::clone(&connections).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("Closing connection from {}, already serving {} connections", peer, max_connections);
+                continue;
+            }
+        };
+        debug!("Accepted connection from {}", peer);
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(server, stream, idle_timeout).await {
+                debug!("Closed connection to {}, error: {}", peer, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+///
+/// Answer the length prefixed queries of a connection (RFC 7766) until the client closes it or stays idle.
+///
+/// The idle timeout covers the whole query, so a client can not keep the connection by sending a length only.
+///
+async fn serve_connection<S>(server: Arc<ServerDriver<S>>, mut stream: TcpStream, idle_timeout: Duration) -> io::Result<()>
+    where S: Storage + Send + Sync + 'static {
+    loop {
+        let mut len = [0_u8; 2];
+        match timeout(idle_timeout, stream.read_exact(&mut len)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Ok(()),
+        }
+        let mut query = vec![0_u8; u16::from_be_bytes(len) as usize];
+        match timeout(idle_timeout, stream.read_exact(&mut query)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+        };
+        if let Some(response) = answer(Arc::clone(&server), query, u16::MAX as usize).await {
+            let mut frame = Vec::with_capacity(2 + response.len());
+            frame.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            frame.extend_from_slice(&response);
+            stream.write_all(&frame).await?;
+        }
+    }
+}
+
+/// Evict idle transmissions regularly, the storage may write them to disk
+async fn evict_idle<S>(server: Arc<ServerDriver<S>>)
+    where S: Storage + Send + Sync + 'static {
+    // the first tick of an interval is immediate, there is nothing to evict yet
+    let period = server.eviction_interval();
+    let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let server = Arc::clone(&server);
+        if let Err(e) = task::spawn_blocking(move || server.evict_idle()).await {
+            error!("Evicting idle transmissions failed, error: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod async_server_tests {
+    use std::sync::Mutex;
+    use std::thread;
+
+    use trust_dns_proto::rr::domain::Label;
+    use trust_dns_proto::rr::Name;
+
+    use dns_encoding::capabilities::Capabilities;
+    use dns_encoding::client;
+    use dns_encoding::codec::CodecKind;
+    use dns_encoding::decode::MessageDecoder;
+    use dns_encoding::driver::ClientDriver;
+    use dns_encoding::encode::MessageEncoder;
+    use dns_encoding::message::ResponseType;
+    use dns_encoding::server::{ServerState, TransmissionState};
+    use dns_encoding::transport::{TcpTransport, Transport, UdpTransport};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MemoryStorage {
+        finished: Mutex<Vec<TransmissionState>>,
+    }
+
+    impl Storage for MemoryStorage {
+        fn finished(&self, state: TransmissionState) {
+            self.finished.lock().unwrap().push(state);
+        }
+
+        fn failed(&self, _state: TransmissionState) {}
+
+        fn expired(&self, _state: TransmissionState) {}
+    }
+
+    #[test]
+    fn test_concurrent_clients() {
+        let magic_nr = Label::from_ascii("8k1").unwrap();
+        let sub_domain = Name::from_ascii("extract.de.").unwrap();
+        let decoder = MessageDecoder::new(magic_nr.clone(), sub_domain.clone());
+        let server = Arc::new(ServerDriver::new(decoder, ServerState::new(), MemoryStorage::default()));
+
+        let udp_socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listener = std::net::TcpListener::bind(udp_socket.local_addr().unwrap()).unwrap();
+        let address = udp_socket.local_addr().unwrap();
+        let running = Arc::clone(&server);
+        thread::spawn(move || run(running, udp_socket, listener, Duration::from_secs(1), 64));
+
+        let clients: Vec<_> = (0..100_u8)
+            .map(|i| {
+                let encoder = MessageEncoder::new(magic_nr.clone(), sub_domain.clone(), CodecKind::Base32, ResponseType::Address);
+                thread::spawn(move || {
+                    let data = vec![i; 1000];
+                    let state = client::TransmissionState::new(format!("host{}", i), "passwords.txt".to_string(), data, 50, Capabilities::WINDOW);
+                    let transport: Box<dyn Transport> = if i % 10 == 0 {
+                        Box::new(TcpTransport::connect(address))
+                    } else {
+                        Box::new(UdpTransport::connect("127.0.0.1:0", address).unwrap())
+                    };
+                    ClientDriver::new(encoder, state, CodecKind::Base32).run(transport).unwrap();
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+
+        let finished = server.storage().finished.lock().unwrap();
+        assert_eq!(100, finished.len());
+        for state in finished.iter() {
+            let i: u8 = state.host.trim_start_matches("host").parse().unwrap();
+            assert_eq!(vec![i; 1000], state.data().sink.read_all().unwrap());
+        }
+    }
+}
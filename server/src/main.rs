@@ -1,17 +1,27 @@
-use std::fs::File;
-use std::io::Write;
-use std::io;
-use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, TcpListener, UdpSocket};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use log::{debug, error, info, warn};
 use structopt::StructOpt;
-use trust_dns_proto::rr::{Name, Record};
+use trust_dns_proto::rr::Name;
 use trust_dns_proto::rr::domain::Label;
-use trust_dns_proto::serialize::binary::{BinDecodable, BinDecoder, BinEncodable, BinEncoder};
 
-use dns_encoding::decode::{MessageDecoder};
-use dns_encoding::server::{ServerState, TransmissionState};
+use dns_encoding::capabilities::Capabilities;
+use dns_encoding::codec::CodecKind;
+use dns_encoding::decode::MessageDecoder;
+use dns_encoding::driver::ServerDriver;
+use dns_encoding::server::ServerState;
+use dns_encoding::transport::{TcpTransport, UdpTransport, MAX_UDP_LEN};
+use dns_encoding::zone::Zone;
+
+use crate::storage::{DirectoryStorage, NamingTemplate};
+
+mod async_server;
+mod storage;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "dns-exfiltrating-client", about = "An client to exfiltrate files via dns.")]
@@ -27,8 +37,59 @@ struct ServerOptions {
 
     #[structopt(short, long, default_value = "53")]
     port: u16,
+
+    /// Codecs clients may use for data messages
+    #[structopt(short, long, use_delimiter = true, default_value = "base32,hex,base36,raw")]
+    codecs: Vec<CodecKind>,
+
+    /// Seconds without a message after which a transmission is abandoned
+    #[structopt(long, default_value = "300")]
+    idle_timeout: u64,
+
+    /// Write abandoned transmissions as <file>.partial, with the missing ranges in <file>.partial.missing
+    #[structopt(long)]
+    keep_partial: bool,
+
+    /// Path of received files below the exfiltration directory, with the placeholders {host}, {session} and {name}.
+    /// Existing files are never overwritten, a number is added to the name instead
+    #[structopt(long, default_value = NamingTemplate::DEFAULT)]
+    naming_template: NamingTemplate,
+
+    /// Answer queries concurrently on an event loop, for many simultaneous clients
+    #[structopt(long = "async")]
+    async_mode: bool,
+
+    /// Name servers of the sub domain, as delegated in the parent zone, the first one is the primary [default: ns1.<sub domain>]
+    #[structopt(long = "name-server", number_of_values = 1)]
+    name_servers: Vec<String>,
+
+    /// Public address of this server, served for the name servers inside the sub domain
+    #[structopt(long = "address", number_of_values = 1)]
+    addresses: Vec<IpAddr>,
+
+    /// Mailbox of the person responsible for the zone, with the @ replaced by a dot [default: hostmaster.<sub domain>]
+    #[structopt(long)]
+    hostmaster: Option<String>,
+
+    /// Serial of the SOA record
+    #[structopt(long, default_value = "1")]
+    serial: u32,
+
+    /// Largest UDP response for resolvers that advertise EDNS, 0 ignores EDNS and limits responses to 512 bytes
+    #[structopt(long, default_value = "1232")]
+    edns_payload_size: u16,
+
+    /// Largest file in bytes a client may send, at most 1 GiB [default: 1 GiB]
+    #[structopt(long)]
+    max_file_size: Option<u64>,
 }
 
+/// Connections without a query for this long are closed
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TCP connections served at once, further ones are closed right away
+const MAX_TCP_CONNECTIONS: usize = 64;
+
 fn main() {
     env_logger::init();
 
@@ -37,84 +98,97 @@ fn main() {
     let exfiltration_path = Path::new(&opt.exfiltration_directory);
     assert!(exfiltration_path.exists(), "Exfiltration directory must exist");
     let address = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, opt.port);
-    let socket = UdpSocket::bind(address).expect("Cant bind to socket");
-    let mut buffer = vec![0 as u8; 1024];
-    let mut send_buffer = Vec::with_capacity(1024);
+    let listener = TcpListener::bind(address).expect("Cant bind to tcp socket");
 
     let magic_nr = Label::from_ascii(opt.magic_nr.as_str()).expect("Magic nr must be valid dns label");
     let sub_domain = Name::from_ascii(opt.sub_domain.as_str()).expect("Subdomain must be valid dns name");
-    let message_decoder = MessageDecoder::new(magic_nr, sub_domain);
+    let message_decoder = MessageDecoder::new(magic_nr, sub_domain.clone());
+    let zone = zone(&opt, sub_domain);
+
+    let capabilities = opt.codecs.iter()
+        .fold(Capabilities::RESPONSE_TXT.union(Capabilities::WINDOW), |capabilities, codec| capabilities.union(Capabilities::for_codec(*codec)));
+    let mut storage = DirectoryStorage::new(exfiltration_path.to_path_buf(), opt.naming_template);
+    storage.set_keep_partial(opt.keep_partial);
+    let spool_directory = storage.create_spool_directory().expect("Cant create spool directory");
+
+    let mut server_state = ServerState::with_capabilities(capabilities);
+    server_state.set_idle_timeout(Duration::from_secs(opt.idle_timeout));
+    server_state.set_spool_directory(spool_directory);
+    if let Some(max_file_size) = opt.max_file_size {
+        server_state.set_max_file_size(max_file_size);
+    }
+    let mut server = ServerDriver::new(message_decoder, server_state, storage);
+    server.set_zone(zone);
+    server.set_edns_payload_size(edns_payload_size(opt.edns_payload_size));
+    let server = Arc::new(server);
+
+    if opt.async_mode {
+        let udp_socket = UdpSocket::bind(address).expect("Cant bind to socket");
+        async_server::run(server, udp_socket, listener, TCP_IDLE_TIMEOUT, MAX_TCP_CONNECTIONS).expect("Failed to run async server");
+        return;
+    }
 
-    let mut server_state = ServerState::new();
+    let mut udp_transport = UdpTransport::bind(address).expect("Cant bind to socket");
+    let tcp_server = Arc::clone(&server);
+    thread::spawn(move || accept_connections(listener, tcp_server));
 
-    loop {
-        let (bytes_read, source) = match socket.recv_from(&mut buffer) {
-            Ok(result) => result,
-            Err(e) => {
-                error!("Failed to receive message, error: {:?}", e);
-                continue;
-            }
-        };
-        debug!("Received from {}: Message with {} bytes", source, bytes_read);
+    server.serve(&mut udp_transport);
+}
 
-        let mut bin_decoder = BinDecoder::new(&buffer);
-        let mut dns_message = match trust_dns_proto::op::Message::read(&mut bin_decoder) {
-            Ok(result) => result,
-            Err(e) => {
-                error!("Failed to decode dns message, error: {:?}", e);
-                continue;
-            }
-        };
-        debug!("Received dns message = {:?}", dns_message);
-        let message = match message_decoder.decode(&dns_message) {
-            Ok(result) => result,
-            Err(e) => {
-                error!("Failed to decode information from dns message, error: {:?}", e);
-                continue;
-            }
-        };
-        debug!("Decoded message = {:?}", message);
-
-        match server_state.handle_message(message) {
-            Ok(response) => {
-                debug!("Responding with: {:?}", response);
-                let r_data = response.encode();
-                let name = dns_message.queries().get(0).unwrap().name().clone();
-                dns_message.add_answer(Record::from_rdata(name, 120, r_data));
-                let mut bin_encoder = BinEncoder::new(&mut send_buffer);
-                dns_message.emit(&mut bin_encoder).unwrap();
-                match socket.send_to(&send_buffer, source) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("Failed to send response, error: {:?}", e);
-                        send_buffer.clear();
-                        continue;
-                    }
-                }
-                send_buffer.clear();
-            }
-            Err(e) => {
-                warn!("Server error: {:?}", e);
-                continue;
-            }
+/// The zone of the sub domain, so the server answers like any authoritative name server
+fn zone(opt: &ServerOptions, sub_domain: Name) -> Zone {
+    let in_sub_domain = |label: &str| Name::from_ascii(label).expect("Label must be valid").append_domain(&sub_domain);
+    let name_servers: Vec<Name> = if opt.name_servers.is_empty() {
+        vec![in_sub_domain("ns1")]
+    } else {
+        opt.name_servers.iter().map(|name| Name::from_ascii(name).expect("Name server must be valid dns name")).collect()
+    };
+    let hostmaster = match &opt.hostmaster {
+        Some(hostmaster) => Name::from_ascii(hostmaster).expect("Hostmaster must be valid dns name"),
+        None => in_sub_domain("hostmaster"),
+    };
+    let mut zone = Zone::new(sub_domain, name_servers.clone(), hostmaster);
+    zone.set_serial(opt.serial);
+    for name_server in name_servers {
+        for address in &opt.addresses {
+            zone.add_address(name_server.clone(), *address);
         }
-
-        write_finished_states(&exfiltration_path, &mut server_state.finished_states);
     }
+    zone
 }
 
-fn write_state(exfiltration_path: &Path, state: &TransmissionState) -> io::Result<()> {
-    let target_path = exfiltration_path.join(&state.name);
-    let mut file = File::create(target_path)?;
-    file.write_all(&state.data)
+/// 0 disables EDNS, smaller sizes than the 512 bytes every resolver receives make no sense
+fn edns_payload_size(payload_size: u16) -> Option<u16> {
+    match payload_size {
+        0 => None,
+        payload_size => Some(payload_size.max(MAX_UDP_LEN as u16)),
+    }
 }
 
-fn write_finished_states(exfiltration_path: &Path, finished_states: &mut Vec<TransmissionState>) {
-    for state in finished_states.iter() {
-        match write_state(&exfiltration_path, &state) {
-            Ok(()) => { info!("Successfully received file '{}' from host {}", state.name, state.host) }
-            Err(e) => { error!("Failed to write file '{}' from host {}. Error: {}", state.name, state.host, e) }
+fn accept_connections(listener: TcpListener, server: Arc<ServerDriver<DirectoryStorage>>) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let transport = match stream.and_then(TcpTransport::from_stream) {
+            Ok(transport) => transport,
+            Err(e) => {
+                error!("Failed to accept connection, error: {}", e);
+                continue;
+            }
+        };
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_TCP_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            warn!("Closing connection from {}, already serving {} connections", transport.peer(), MAX_TCP_CONNECTIONS);
+            continue;
         }
+        let server = Arc::clone(&server);
+        let connections = Arc::clone(&connections);
+        thread::spawn(move || {
+            let mut transport = transport;
+            debug!("Accepted connection from {}", transport.peer());
+            if let Err(e) = server.serve_connection(&mut transport, TCP_IDLE_TIMEOUT) {
+                debug!("Closed connection to {}, error: {}", transport.peer(), e);
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
     }
-    finished_states.clear();
 }
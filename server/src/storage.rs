@@ -0,0 +1,489 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::{error, info, warn};
+
+use dns_encoding::driver::Storage;
+use dns_encoding::server::TransmissionState;
+use dns_encoding::sink::DataSink;
+
+/// Longest file or directory name taken from a client, leaves room for collision and partial suffixes
+const MAX_COMPONENT_LEN: usize = 200;
+
+/// Numbered names tried before giving up on a file that already exists
+const MAX_COLLISIONS: usize = 1000;
+
+#[derive(Debug, PartialEq)]
+pub enum TemplateError {
+    /** The template or one of its directories is empty */
+    EmptyComponent,
+    /** Files must be stored below the exfiltration directory */
+    AbsolutePath,
+    /** `.` or `..` would leave the directory of the transmission */
+    RelativeComponent,
+    /** Directories are separated by `/`, a `\` is a separator on some systems only */
+    PathSeparator,
+    /** Only {host}, {session} and {name} are known */
+    UnknownPlaceholder(String),
+    /** A `{` without the matching `}` */
+    UnclosedPlaceholder,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::EmptyComponent => write!(f, "Template contains an empty path component"),
+            TemplateError::AbsolutePath => write!(f, "Template must be a relative path"),
+            TemplateError::RelativeComponent => write!(f, "Template must not contain '.' or '..'"),
+            TemplateError::PathSeparator => write!(f, "Template must separate directories with '/' instead of '\\'"),
+            TemplateError::UnknownPlaceholder(placeholder) => write!(f, "Unknown placeholder {{{}}}, use {{host}}, {{session}} or {{name}}", placeholder),
+            TemplateError::UnclosedPlaceholder => write!(f, "Template contains a '{{' without '}}'"),
+        }
+    }
+}
+
+impl Error for TemplateError {}
+
+///
+/// Where a received file is stored, relative to the exfiltration directory.
+///
+/// `{host}` and `{name}` are replaced by the sanitized host and file name the client announced,
+/// `{session}` by the session token as 16 hex digits. Every `/` starts a directory.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamingTemplate {
+    components: Vec<String>,
+}
+
+impl NamingTemplate {
+    pub const DEFAULT: &'static str = "{host}/{session}/{name}";
+
+    /// Path of the file relative to the exfiltration directory
+    pub fn path(&self, state: &TransmissionState) -> PathBuf {
+        let host = sanitize(&state.host);
+        let session = format!("{:016x}", state.session());
+        let name = sanitize(&state.name);
+        self.components.iter()
+            .map(|component| component
+                .replace("{host}", &host)
+                .replace("{session}", &session)
+                .replace("{name}", &name))
+            .collect()
+    }
+}
+
+impl Default for NamingTemplate {
+    fn default() -> Self {
+        NamingTemplate::from_str(NamingTemplate::DEFAULT).expect("Default template must be valid")
+    }
+}
+
+impl FromStr for NamingTemplate {
+    type Err = TemplateError;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        if template.starts_with('/') || template.starts_with('\\') {
+            return Err(TemplateError::AbsolutePath);
+        }
+        let components: Vec<String> = template.split('/').map(str::to_string).collect();
+        for component in &components {
+            if component.is_empty() {
+                return Err(TemplateError::EmptyComponent);
+            }
+            if component == "." || component == ".." {
+                return Err(TemplateError::RelativeComponent);
+            }
+            if component.contains('\\') {
+                return Err(TemplateError::PathSeparator);
+            }
+            check_placeholders(component)?;
+        }
+        Ok(NamingTemplate { components })
+    }
+}
+
+fn check_placeholders(component: &str) -> Result<(), TemplateError> {
+    let mut rest = component;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').ok_or(TemplateError::UnclosedPlaceholder)? + start;
+        let placeholder = &rest[start + 1..end];
+        if !["host", "session", "name"].contains(&placeholder) {
+            return Err(TemplateError::UnknownPlaceholder(placeholder.to_string()));
+        }
+        rest = &rest[end + 1..];
+    }
+    Ok(())
+}
+
+///
+/// Turn a name sent by a client into a single file name.
+///
+/// Path separators are flattened, so `../../etc/cron.d/x` becomes `etc_cron.d_x`.
+/// Anything but ascii letters, digits, `-`, `_` and `.` is replaced by `_`,
+/// leading dots are removed and long names are cut off. Names that end up empty become `unnamed`.
+///
+pub fn sanitize(name: &str) -> String {
+    let flattened = name.split(['/', '\\'])
+        .filter(|component| !component.is_empty() && *component != "." && *component != "..")
+        .collect::<Vec<_>>()
+        .join("_");
+    let mut sanitized: String = flattened.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+        .skip_while(|c| *c == '.')
+        .collect();
+    sanitized.truncate(MAX_COMPONENT_LEN);
+    if sanitized.is_empty() {
+        sanitized.push_str("unnamed");
+    }
+    sanitized
+}
+
+/// The n-th alternative for a file that already exists, e.g. `passwords.2.txt`
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let numbered_name = match file_name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{}.{}{}", &file_name[..dot], n, &file_name[dot..]),
+        _ => format!("{}.{}", file_name, n),
+    };
+    path.with_file_name(numbered_name)
+}
+
+///
+/// Create a file at path, or at the first numbered alternative that does not exist yet.
+///
+/// `create` must fail with `AlreadyExists` if its path is taken. Returns its result and the path it succeeded with.
+///
+fn create_unique<T, F>(path: &Path, mut create: F) -> io::Result<(T, PathBuf)>
+    where F: FnMut(&Path) -> io::Result<T> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    for n in 0..MAX_COLLISIONS {
+        let candidate = if n == 0 { path.to_path_buf() } else { numbered(path, n) };
+        match create(&candidate) {
+            Ok(created) => return Ok((created, candidate)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} and {} numbered alternatives exist", path.display(), MAX_COLLISIONS)))
+}
+
+fn create_new(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+///
+/// Store data at path, fails with `AlreadyExists` if there is a file.
+///
+/// Spooled data is moved to its final name, so the file appears complete or not at all.
+///
+fn store_new(data: &DataSink, path: &Path) -> io::Result<()> {
+    match data {
+        DataSink::Memory(data) => {
+            let mut file = create_new(path)?;
+            file.write_all(data)?;
+            file.sync_all()
+        }
+        DataSink::File(spool) => spool.persist(path),
+    }
+}
+
+/// Store data at path, or at a numbered alternative if path exists
+fn store(data: &DataSink, path: &Path) -> io::Result<PathBuf> {
+    create_unique(path, |candidate| store_new(data, candidate)).map(|(_, path)| path)
+}
+
+/// The file next to a partial file that lists its missing ranges
+fn missing_path(partial_path: &Path) -> PathBuf {
+    let mut missing_name = partial_path.file_name().unwrap_or_default().to_os_string();
+    missing_name.push(".missing");
+    partial_path.with_file_name(missing_name)
+}
+
+/// Remove the spool file of a transmission that is not stored
+fn discard(data: DataSink) {
+    if let DataSink::File(spool) = data {
+        let path = spool.path().to_path_buf();
+        if let Err(e) = spool.discard() {
+            error!("Failed to remove {}. Error: {}", path.display(), e);
+        }
+    }
+}
+
+///
+/// Writes received files below the exfiltration directory, at the path given by the naming template.
+///
+/// Existing files are never overwritten.
+/// Active transmissions are spooled to `<session>.part` files in the spool directory, `.incoming` below the exfiltration directory.
+/// Since sanitized names never start with a dot, the spool directory can not collide with received files.
+///
+pub struct DirectoryStorage {
+    exfiltration_path: PathBuf,
+    template: NamingTemplate,
+    keep_partial: bool,
+}
+
+impl DirectoryStorage {
+    pub const SPOOL_DIRECTORY: &'static str = ".incoming";
+
+    pub fn new(exfiltration_path: PathBuf, template: NamingTemplate) -> DirectoryStorage {
+        DirectoryStorage { exfiltration_path, template, keep_partial: false }
+    }
+
+    /// Write abandoned transmissions as <file>.partial, with the missing ranges in <file>.partial.missing
+    pub fn set_keep_partial(&mut self, keep_partial: bool) {
+        self.keep_partial = keep_partial;
+    }
+
+    ///
+    /// Create the spool directory, it is on the same file system as the stored files, so they can be renamed there.
+    ///
+    /// Spool files left by a previous run are removed, their transmissions are gone with the server state.
+    ///
+    pub fn create_spool_directory(&self) -> io::Result<PathBuf> {
+        let spool_directory = self.exfiltration_path.join(DirectoryStorage::SPOOL_DIRECTORY);
+        fs::create_dir_all(&spool_directory)?;
+        for entry in fs::read_dir(&spool_directory)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "part") {
+                warn!("Removing {} of a transmission from before the restart", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(spool_directory)
+    }
+
+    fn target_path(&self, state: &TransmissionState) -> PathBuf {
+        self.exfiltration_path.join(self.template.path(state))
+    }
+
+    ///
+    /// Store the received parts of a transmission, and the byte ranges that are missing in between.
+    ///
+    /// Both files get the sanitized name of the naming template, a number is added until neither of them exists.
+    ///
+    fn write_partial_state(&self, state: &TransmissionState) -> io::Result<PathBuf> {
+        let target_path = self.target_path(state);
+        let mut partial_name = target_path.file_name().unwrap_or_default().to_os_string();
+        partial_name.push(".partial");
+        let (mut missing, path) = create_unique(&target_path.with_file_name(partial_name), |candidate| {
+            let missing_path = missing_path(candidate);
+            let missing = create_new(&missing_path)?;
+            match store_new(&state.data().sink, candidate) {
+                Ok(()) => Ok(missing),
+                Err(e) => {
+                    let _ = fs::remove_file(&missing_path);
+                    Err(e)
+                }
+            }
+        })?;
+        writeln!(missing, "# missing byte ranges (start-end, end exclusive) of '{}' from host {}", state.name, state.host)?;
+        let data = state.data();
+        writeln!(missing, "# the file size is unknown, anything after byte {} may be missing as well", data.sink.len())?;
+        for gap in data.received.gaps(data.sink.len()) {
+            writeln!(missing, "{}-{}", gap.start, gap.end)?;
+        }
+        Ok(path)
+    }
+}
+
+impl Storage for DirectoryStorage {
+    fn finished(&self, state: TransmissionState) {
+        let stored = store(&state.data().sink, &self.target_path(&state));
+        match stored {
+            Ok(path) => info!("Successfully received file '{}' from host {} as {}", state.name, state.host, path.display()),
+            // the spool file is kept, so the data can still be recovered
+            Err(e) => { error!("Failed to write file '{}' from host {}. Error: {}", state.name, state.host, e) }
+        }
+    }
+
+    fn failed(&self, state: TransmissionState) {
+        error!("Discarding file '{}' from host {}, size or digest did not match", state.name, state.host);
+        discard(state.into_sink());
+    }
+
+    fn expired(&self, state: TransmissionState) {
+        if !self.keep_partial {
+            warn!("Discarding abandoned file '{}' from host {}", state.name, state.host);
+            discard(state.into_sink());
+            return;
+        }
+        match self.write_partial_state(&state) {
+            Ok(path) => warn!("Wrote abandoned file '{}' from host {} as {}", state.name, state.host, path.display()),
+            Err(e) => { error!("Failed to write partial file '{}' from host {}. Error: {}", state.name, state.host, e) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use std::env;
+    use std::process;
+    use std::time::Instant;
+
+    use dns_encoding::capabilities::Capabilities;
+    use dns_encoding::message::{Message, MessageResponse};
+    use dns_encoding::server::{ServerState, FIRST_ID};
+
+    use super::*;
+
+    /// Empty directory for one test
+    fn test_directory(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("dns-storage-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Receive a whole file, as if a client had sent it
+    fn receive(host: &str, name: &str, rnd_nr: u64, data: &[u8]) -> TransmissionState {
+        let mut state = ServerState::new();
+        let response = state.handle_message(Message::initial(host.to_string(), name.to_string(), rnd_nr, Capabilities::V0)).unwrap();
+        assert!(matches!(response, MessageResponse::Announcement { .. }));
+        state.handle_message(Message::data(rnd_nr, FIRST_ID, 0, data.to_vec())).unwrap();
+        state.handle_message(Message::finish(rnd_nr, data)).unwrap();
+        state.finished_states.pop().expect("transmission must be finished")
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!("passwords.txt", sanitize("passwords.txt"));
+        assert_eq!("etc_cron.d_x", sanitize("../../etc/cron.d/x"));
+        assert_eq!("etc_shadow", sanitize("/etc/shadow"));
+        assert_eq!("windows_system32", sanitize("..\\windows\\system32"));
+        assert_eq!("bashrc", sanitize(".bashrc"));
+        assert_eq!("a_b_c", sanitize("a b\nc"));
+        assert_eq!("unnamed", sanitize(".."));
+        assert_eq!("unnamed", sanitize(""));
+        assert_eq!(MAX_COMPONENT_LEN, sanitize(&"x".repeat(1000)).len());
+    }
+
+    #[test]
+    fn test_template() {
+        assert_eq!(Ok(NamingTemplate { components: vec!["{host}".to_string(), "{session}-{name}".to_string()] }),
+                   "{host}/{session}-{name}".parse());
+        assert_eq!(Err(TemplateError::AbsolutePath), "/tmp/{name}".parse::<NamingTemplate>());
+        assert_eq!(Err(TemplateError::RelativeComponent), "{host}/../{name}".parse::<NamingTemplate>());
+        assert_eq!(Err(TemplateError::PathSeparator), "{host}\\{name}".parse::<NamingTemplate>());
+        assert_eq!(Err(TemplateError::PathSeparator), "{host}/..\\{name}".parse::<NamingTemplate>());
+        assert_eq!(Err(TemplateError::EmptyComponent), "{host}//{name}".parse::<NamingTemplate>());
+        assert_eq!(Err(TemplateError::EmptyComponent), "".parse::<NamingTemplate>());
+        assert_eq!(Err(TemplateError::UnknownPlaceholder("date".to_string())), "{date}/{name}".parse::<NamingTemplate>());
+        assert_eq!(Err(TemplateError::UnclosedPlaceholder), "{host/{name}".parse::<NamingTemplate>());
+    }
+
+    #[test]
+    fn test_numbered() {
+        assert_eq!(PathBuf::from("a/passwords.1.txt"), numbered(Path::new("a/passwords.txt"), 1));
+        assert_eq!(PathBuf::from("a/passwords.2"), numbered(Path::new("a/passwords"), 2));
+        assert_eq!(PathBuf::from("a/.bashrc.3"), numbered(Path::new("a/.bashrc"), 3));
+    }
+
+    #[test]
+    fn test_create_unique() {
+        let directory = test_directory("unique");
+        let path = directory.join("host").join("passwords.txt");
+        let (_, first) = create_unique(&path, create_new).unwrap();
+        let (_, second) = create_unique(&path, create_new).unwrap();
+        let (_, third) = create_unique(&path, create_new).unwrap();
+        assert_eq!(path, first);
+        assert_eq!(directory.join("host").join("passwords.1.txt"), second);
+        assert_eq!(directory.join("host").join("passwords.2.txt"), third);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_directory_storage() {
+        let directory = test_directory("directory");
+        let storage = DirectoryStorage::new(directory.clone(), NamingTemplate::default());
+        storage.finished(receive("db", "../../etc/cron.d/x", 0x2a, b"evil"));
+        storage.finished(receive("db", "passwords.txt", 0x2a, b"first"));
+        storage.finished(receive("db", "passwords.txt", 0x2a, b"second"));
+        storage.finished(receive("web", "passwords.txt", 0x2a, b"other host"));
+
+        let session = directory.join("db").join("000000000000002a");
+        assert_eq!(b"evil", fs::read(session.join("etc_cron.d_x")).unwrap().as_slice());
+        assert_eq!(b"first", fs::read(session.join("passwords.txt")).unwrap().as_slice());
+        assert_eq!(b"second", fs::read(session.join("passwords.1.txt")).unwrap().as_slice());
+        assert_eq!(b"other host", fs::read(directory.join("web").join("000000000000002a").join("passwords.txt")).unwrap().as_slice());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_flat_template() {
+        let directory = test_directory("flat");
+        let template = "{host}-{name}".parse().unwrap();
+        let storage = DirectoryStorage::new(directory.clone(), template);
+        storage.finished(receive("db", "/etc/shadow", 1, b"root"));
+        assert_eq!(b"root", fs::read(directory.join("db-etc_shadow")).unwrap().as_slice());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_spooled_transmissions() {
+        let directory = test_directory("spooled");
+        let mut storage = DirectoryStorage::new(directory.clone(), NamingTemplate::default());
+        storage.set_keep_partial(true);
+        // spool files of a previous run are removed, other files are left alone
+        let spool_directory = directory.join(DirectoryStorage::SPOOL_DIRECTORY);
+        fs::create_dir_all(&spool_directory).unwrap();
+        fs::write(spool_directory.join("0000000000000001.part"), b"stale").unwrap();
+        fs::write(spool_directory.join("notes.txt"), b"notes").unwrap();
+        assert_eq!(spool_directory, storage.create_spool_directory().unwrap());
+        assert!(!spool_directory.join("0000000000000001.part").exists());
+        fs::remove_file(spool_directory.join("notes.txt")).unwrap();
+        let mut server_state = ServerState::new();
+        server_state.set_spool_directory(&spool_directory);
+
+        for rnd_nr in 1..=3 {
+            server_state.handle_message(Message::initial("db".to_string(), "passwords.txt".to_string(), rnd_nr, Capabilities::V0)).unwrap();
+            server_state.handle_message(Message::data(rnd_nr, FIRST_ID, 0, b"secret".to_vec())).unwrap();
+        }
+        // the data is on disk while the transmissions are active
+        assert_eq!(b"secret", fs::read(spool_directory.join("0000000000000001.part")).unwrap().as_slice());
+
+        server_state.handle_message(Message::finish(1, b"secret")).unwrap();
+        server_state.handle_message(Message::finish(2, b"other")).unwrap();
+        server_state.evict_idle(Instant::now() + server_state.idle_timeout());
+        storage.finished(server_state.finished_states.pop().unwrap());
+        storage.failed(server_state.failed_states.pop().unwrap());
+        storage.expired(server_state.expired_states.pop().unwrap());
+
+        assert_eq!(b"secret", fs::read(directory.join("db/0000000000000001/passwords.txt")).unwrap().as_slice());
+        assert!(!directory.join("db/0000000000000002").exists());
+        assert_eq!(b"secret", fs::read(directory.join("db/0000000000000003/passwords.txt.partial")).unwrap().as_slice());
+        assert!(directory.join("db/0000000000000003/passwords.txt.partial.missing").exists());
+        assert_eq!(0, fs::read_dir(&spool_directory).unwrap().count());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_partial_files() {
+        let directory = test_directory("partial");
+        let mut storage = DirectoryStorage::new(directory.clone(), "{name}".parse().unwrap());
+        storage.set_keep_partial(true);
+        // a received file with the name of the missing ranges of a partial one
+        fs::write(directory.join("x.partial.missing"), b"received").unwrap();
+        fs::write(directory.join("x.1.partial"), b"received").unwrap();
+
+        let mut server_state = ServerState::new();
+        server_state.handle_message(Message::initial("db".to_string(), "../../x".to_string(), 1, Capabilities::supported())).unwrap();
+        server_state.handle_message(Message::data(1, FIRST_ID, 0, b"part".to_vec())).unwrap();
+        server_state.evict_idle(Instant::now() + server_state.idle_timeout());
+        storage.expired(server_state.expired_states.pop().unwrap());
+
+        // the name is sanitized and existing files are kept
+        assert_eq!(b"received", fs::read(directory.join("x.partial.missing")).unwrap().as_slice());
+        assert_eq!(b"received", fs::read(directory.join("x.1.partial")).unwrap().as_slice());
+        assert!(!directory.join("x.partial").exists());
+        assert_eq!(b"part", fs::read(directory.join("x.2.partial")).unwrap().as_slice());
+        assert!(directory.join("x.2.partial.missing").exists());
+        assert_eq!(4, fs::read_dir(&directory).unwrap().count());
+        fs::remove_dir_all(directory).unwrap();
+    }
+}